@@ -1,56 +1,347 @@
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr, time::Duration};
 
 use reqwest::Client;
 
 use crate::parser::ParseError;
 
-const URL: &str = "https://boundvariable.space/communicate";
+/// 本番のサーバー
+pub const DEFAULT_URL: &str = "https://boundvariable.space/communicate";
 
-#[derive(thiserror::Error, Debug)]
+// thiserror の #[source] は ::core::option を参照するので、core という名前のこの crate では使えない
+// source は手で実装する
+#[derive(Debug)]
 pub enum RequestError {
-    InvalidToken,
+    // 接続できない、タイムアウトした、body を読み切れなかった等
+    Network(reqwest::Error),
+    // サーバーが 2xx 以外を返した (401 なら auth token が違う)
+    Http { status: u16, body: String },
+    // 応答を ICFP の式として読めなかった
+    Decode(ParseError),
 }
 
 impl Display for RequestError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            RequestError::InvalidToken => write!(f, "Invalid token"),
+            RequestError::Network(e) => write!(f, "network error: {}", e),
+            RequestError::Http { status, body } => {
+                write!(f, "server returned status {}: {}", status, body)
+            }
+            RequestError::Decode(e) => write!(f, "cannot decode response: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RequestError::Network(e) => Some(e),
+            RequestError::Http { .. } => None,
+            RequestError::Decode(e) => Some(e),
         }
     }
 }
 
 impl From<reqwest::Error> for RequestError {
-    fn from(_: reqwest::Error) -> RequestError {
-        RequestError::InvalidToken
+    fn from(e: reqwest::Error) -> RequestError {
+        RequestError::Network(e)
     }
 }
 
 impl From<ParseError> for RequestError {
-    fn from(_: ParseError) -> RequestError {
-        RequestError::InvalidToken
+    fn from(e: ParseError) -> RequestError {
+        RequestError::Decode(e)
+    }
+}
+
+// 問題の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Track {
+    Lambdaman,
+    Spaceship,
+    ThreeD,
+    Efficiency,
+}
+
+impl Track {
+    // コマンド中で使う問題の種類の名前
+    pub fn prefix(&self) -> &str {
+        match self {
+            Track::Lambdaman => "lambdaman",
+            Track::Spaceship => "spaceship",
+            Track::ThreeD => "3d",
+            Track::Efficiency => "efficiency",
+        }
+    }
+
+    // "get <track><id>"。problem_id が空なら問題一覧の取得になる
+    pub fn get_command(&self, problem_id: &str) -> String {
+        format!("get {}{}", self.prefix(), problem_id)
+    }
+
+    // get_command("") で取得した問題一覧のページから、"[<track><id>]" の id を出てきた順に取り出す
+    // "[3d-example]" のような数字でないものは問題ではないので除く
+    pub fn parse_index(&self, page: &str) -> Vec<String> {
+        let pattern = format!("[{}", self.prefix());
+        let mut ids = vec![];
+        let mut rest = page;
+        while let Some(pos) = rest.find(&pattern) {
+            rest = &rest[pos + pattern.len()..];
+            let Some(end) = rest.find(']') else {
+                break;
+            };
+            let id = &rest[..end];
+            if !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()) {
+                ids.push(id.to_string());
+            }
+        }
+        ids
+    }
+
+    // "solve <track><id> <body>"
+    // 3d は複数行のプログラムを送るので、改行で区切る
+    pub fn solve_command(&self, problem_id: &str, body: &str) -> String {
+        let separator = match self {
+            Track::ThreeD => "\n ",
+            _ => " ",
+        };
+        format!("solve {}{}{}{}", self.prefix(), problem_id, separator, body)
+    }
+}
+
+// prefix() と同じ名前から Track を作る (コマンドライン引数用)
+impl FromStr for Track {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Track, String> {
+        [
+            Track::Lambdaman,
+            Track::Spaceship,
+            Track::ThreeD,
+            Track::Efficiency,
+        ]
+        .into_iter()
+        .find(|track| track.prefix() == s)
+        .ok_or_else(|| format!("unknown track '{}'", s))
     }
 }
 
+/// 応答が返ってくるまで待つ時間の既定値
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct ICFPCClient {
     auth_token: String,
+    url: String,
+    // 接続を使い回せるように、リクエスト毎に作らずに持っておく
+    client: Client,
+}
+
+fn build_client(timeout: Duration) -> Client {
+    Client::builder()
+        .timeout(timeout)
+        .build()
+        .expect("failed to build HTTP client")
 }
 
 impl ICFPCClient {
-    pub fn new(auth_token: String) -> ICFPCClient {
-        ICFPCClient { auth_token }
+    // url は通常 DEFAULT_URL。テスト用のモックサーバー等に送る時は別の URL を渡す
+    pub fn new(auth_token: String, url: String) -> ICFPCClient {
+        ICFPCClient {
+            auth_token,
+            url,
+            client: build_client(DEFAULT_TIMEOUT),
+        }
     }
 
-    pub async fn post_message(&self, message: String) -> Result<String, RequestError> {
-        let client = Client::new();
+    // timeout を過ぎても応答が無ければ RequestError::Network を返す
+    pub fn with_timeout(self, timeout: Duration) -> ICFPCClient {
+        ICFPCClient {
+            client: build_client(timeout),
+            ..self
+        }
+    }
 
-        let response = client
-            .post(URL)
+    pub async fn post_message(&self, message: String) -> Result<String, RequestError> {
+        let response = self
+            .client
+            .post(&self.url)
             .body(message)
             .header("Authorization", format!("Bearer {}", &self.auth_token))
             .send()
             .await?;
 
+        let status = response.status();
         let text = response.text().await?;
+        if !status.is_success() {
+            return Err(RequestError::Http {
+                status: status.as_u16(),
+                body: text,
+            });
+        }
         Ok(text)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_track_command() {
+        assert_eq!(Track::Lambdaman.get_command(""), "get lambdaman");
+        assert_eq!(Track::Lambdaman.get_command("4"), "get lambdaman4");
+        assert_eq!(Track::Spaceship.get_command("12"), "get spaceship12");
+        assert_eq!(Track::ThreeD.get_command("3"), "get 3d3");
+        assert_eq!(Track::Efficiency.get_command("7"), "get efficiency7");
+
+        assert_eq!(
+            Track::Lambdaman.solve_command("4", "RRDD"),
+            "solve lambdaman4 RRDD"
+        );
+        assert_eq!(
+            Track::Spaceship.solve_command("12", "3698"),
+            "solve spaceship12 3698"
+        );
+        assert_eq!(
+            Track::ThreeD.solve_command("3", ". A .\n. . ."),
+            "solve 3d3\n . A .\n. . ."
+        );
+        assert_eq!(
+            Track::Efficiency.solve_command("7", "1234"),
+            "solve efficiency7 1234"
+        );
+    }
+
+    #[test]
+    fn test_parse_index() {
+        let page = "You can see the execution trace at [3d-example].\n\
+                    * [3d1] Best score: 2982.\n\
+                    * [3d2] Your score: 10. Best score: 1920.\n\
+                    * [3d12] Best score: 1314.\n";
+        assert_eq!(Track::ThreeD.parse_index(page), vec!["1", "2", "12"]);
+        assert!(Track::Lambdaman.parse_index(page).is_empty());
+
+        // 閉じ括弧が無い途中で切れたページ
+        assert_eq!(
+            Track::Spaceship.parse_index("* [spaceship1] Best score: 5.\n* [spaceship2"),
+            vec!["1"]
+        );
+    }
+
+    #[test]
+    fn test_track_from_str() {
+        assert_eq!("lambdaman".parse(), Ok(Track::Lambdaman));
+        assert_eq!("3d".parse(), Ok(Track::ThreeD));
+        assert!("lambda".parse::<Track>().is_err());
+    }
+
+    #[test]
+    fn test_parse_error_is_request_error() {
+        let err = RequestError::from(ParseError::InvalidCharacter(0x7f));
+        assert!(matches!(
+            err,
+            RequestError::Decode(ParseError::InvalidCharacter(0x7f))
+        ));
+    }
+
+    #[test]
+    fn test_unreachable_server() {
+        // 誰も listen していないポートに送る
+        let client = ICFPCClient::new("token".to_string(), "http://127.0.0.1:1".to_string());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let result = runtime.block_on(client.post_message("S'%4}".to_string()));
+        assert!(matches!(result, Err(RequestError::Network(_))));
+    }
+
+    // 1 回だけリクエストを受けて、status と body を返すサーバーを立てる
+    async fn mock_server(status: &'static str, response_body: &'static str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/communicate", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // リクエストを読み切る前に閉じると送信側がエラーになるので、header と body を読む
+            let mut buf = vec![];
+            let mut chunk = [0u8; 4096];
+            let body_start = loop {
+                let n = stream.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+                if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break pos + 4;
+                }
+            };
+            let header = String::from_utf8_lossy(&buf[..body_start]).to_lowercase();
+            let content_length = header
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .map(|v| v.trim().parse::<usize>().unwrap())
+                .unwrap_or(0);
+            while buf.len() < body_start + content_length {
+                let n = stream.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+            }
+
+            let response = format!(
+                "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                status,
+                response_body.len(),
+                response_body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        url
+    }
+
+    #[test]
+    fn test_http_error_status() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let result = runtime.block_on(async {
+            let url = mock_server("401 Unauthorized", "unauthorized").await;
+            let client = ICFPCClient::new("wrong".to_string(), url);
+            client.post_message("S'%4}".to_string()).await
+        });
+        match result {
+            Err(RequestError::Http { status, body }) => {
+                assert_eq!(status, 401);
+                assert_eq!(body, "unauthorized");
+            }
+            result => panic!("expected Http error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let start = std::time::Instant::now();
+        let result = runtime.block_on(async {
+            // 接続は受けるが何も返さないサーバー
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/communicate", listener.local_addr().unwrap());
+            tokio::spawn(async move {
+                let (_stream, _) = listener.accept().await.unwrap();
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            });
+
+            let client =
+                ICFPCClient::new("token".to_string(), url).with_timeout(Duration::from_millis(200));
+            client.post_message("S'%4}".to_string()).await
+        });
+        assert!(start.elapsed() < Duration::from_secs(10));
+        match result {
+            Err(RequestError::Network(e)) => assert!(e.is_timeout(), "{}", e),
+            result => panic!("expected timeout, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_success_status() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let result = runtime.block_on(async {
+            let url = mock_server("200 OK", "SB%,,/}").await;
+            let client = ICFPCClient::new("token".to_string(), url);
+            client.post_message("S'%4}".to_string()).await
+        });
+        assert_eq!(result.unwrap(), "SB%,,/}");
+    }
+}
@@ -0,0 +1,244 @@
+use std::fmt::Display;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum SimError {
+    NoStartCell,
+    InvalidMove(char),
+    UncollectedPellet {
+        y: usize,
+        x: usize,
+        remaining: usize,
+    },
+    UnvisitedPoint {
+        // 渡された点の列の中で、最初に訪れていない点の位置
+        index: usize,
+        x: i64,
+        y: i64,
+        remaining: usize,
+        // 再生した move の数。この tick までに訪れられなかった
+        tick: usize,
+    },
+}
+
+impl Display for SimError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SimError::NoStartCell => write!(f, "cannot find start cell 'L'"),
+            SimError::InvalidMove(ch) => write!(f, "invalid move '{}'", ch),
+            SimError::UncollectedPellet { y, x, remaining } => write!(
+                f,
+                "pellet at (y: {}, x: {}) is not collected ({} remaining)",
+                y, x, remaining
+            ),
+            SimError::UnvisitedPoint {
+                index,
+                x,
+                y,
+                remaining,
+                tick,
+            } => write!(
+                f,
+                "point #{} ({}, {}) is not visited after {} ticks ({} remaining)",
+                index, x, y, tick, remaining
+            ),
+        }
+    }
+}
+
+/// 問題文のグリッドを読む。空行があればそこで終わりとみなす
+pub fn parse_grid(input: &str) -> Vec<Vec<char>> {
+    input
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .map(|line| line.chars().collect())
+        .collect()
+}
+
+/// lambdaman の移動を元のグリッド上で再生し、全てのペレットを食べきれるか確認する
+/// 壁 '#' やグリッド外への移動はその場に留まるだけで、エラーにはしない
+pub fn simulate_lambdaman(grid: &[Vec<char>], moves: &str) -> Result<(), SimError> {
+    let uncollected = uncollected_pellets(grid, moves)?;
+    match uncollected.first() {
+        None => Ok(()),
+        Some(&(y, x)) => Err(SimError::UncollectedPellet {
+            y,
+            x,
+            remaining: uncollected.len(),
+        }),
+    }
+}
+
+/// simulate_lambdaman と同じように移動を再生し、食べ残したペレットの数を返す (0 なら完了)
+pub fn count_remaining_pellets(grid: &[Vec<char>], moves: &str) -> Result<usize, SimError> {
+    Ok(uncollected_pellets(grid, moves)?.len())
+}
+
+// 移動を再生した後に残っているペレットの (y, x) を、上の行から順に返す
+fn uncollected_pellets(grid: &[Vec<char>], moves: &str) -> Result<Vec<(usize, usize)>, SimError> {
+    let mut simulator = LambdamanSimulator::new(grid)?;
+    simulator.apply(moves)?;
+    Ok(simulator.uncollected())
+}
+
+/// lambdaman の移動を少しずつ再生する
+/// 経路全体を String に溜めずに、書き出しながら食べ残しを数えるのに使う
+pub struct LambdamanSimulator<'a> {
+    grid: &'a [Vec<char>],
+    y: usize,
+    x: usize,
+    eaten: Vec<Vec<bool>>,
+    remaining: usize,
+}
+
+impl<'a> LambdamanSimulator<'a> {
+    pub fn new(grid: &'a [Vec<char>]) -> Result<LambdamanSimulator<'a>, SimError> {
+        let (y, x) = grid
+            .iter()
+            .enumerate()
+            .find_map(|(y, row)| row.iter().position(|&c| c == 'L').map(|x| (y, x)))
+            .ok_or(SimError::NoStartCell)?;
+
+        let mut eaten = grid
+            .iter()
+            .map(|row| vec![false; row.len()])
+            .collect::<Vec<_>>();
+        eaten[y][x] = true;
+        let remaining = grid.iter().flatten().filter(|&&c| c == '.').count();
+
+        Ok(LambdamanSimulator {
+            grid,
+            y,
+            x,
+            eaten,
+            remaining,
+        })
+    }
+
+    /// moves を続けて再生する
+    pub fn apply(&mut self, moves: &str) -> Result<(), SimError> {
+        for ch in moves.chars() {
+            let (dy, dx) = match ch {
+                'U' => (-1, 0),
+                'D' => (1, 0),
+                'L' => (0, -1),
+                'R' => (0, 1),
+                _ => return Err(SimError::InvalidMove(ch)),
+            };
+            let ny = self.y as i64 + dy;
+            let nx = self.x as i64 + dx;
+            if ny < 0 || nx < 0 {
+                continue;
+            }
+            let (ny, nx) = (ny as usize, nx as usize);
+            match self.grid.get(ny).and_then(|row| row.get(nx)) {
+                None | Some('#') => continue,
+                Some(&c) => {
+                    self.y = ny;
+                    self.x = nx;
+                    if c == '.' && !self.eaten[ny][nx] {
+                        self.remaining -= 1;
+                    }
+                    self.eaten[ny][nx] = true;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// まだ食べていないペレットの数
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    fn uncollected(&self) -> Vec<(usize, usize)> {
+        let mut uncollected = vec![];
+        for (y, row) in self.grid.iter().enumerate() {
+            for (x, &c) in row.iter().enumerate() {
+                if c == '.' && !self.eaten[y][x] {
+                    uncollected.push((y, x));
+                }
+            }
+        }
+        uncollected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simulate_valid() {
+        // 問題文の例
+        let grid = parse_grid("###.#...\n...L..##\n.#######");
+        let moves = "LLLDURRRUDRRURR";
+        assert_eq!(simulate_lambdaman(&grid, moves), Ok(()));
+    }
+
+    #[test]
+    fn test_simulate_uncollected() {
+        let grid = parse_grid("###.#...\n...L..##\n.#######");
+        let moves = "LLLDURRRUDRR";
+        assert_eq!(
+            simulate_lambdaman(&grid, moves),
+            Err(SimError::UncollectedPellet {
+                y: 0,
+                x: 5,
+                remaining: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_count_remaining_pellets() {
+        let grid = parse_grid("###.#...\n...L..##\n.#######");
+        assert_eq!(count_remaining_pellets(&grid, "LLLDURRRUDRRURR"), Ok(0));
+        assert_eq!(count_remaining_pellets(&grid, "LLLDURRRUDRR"), Ok(3));
+        assert_eq!(count_remaining_pellets(&grid, ""), Ok(10));
+        assert_eq!(
+            count_remaining_pellets(&grid, "LX"),
+            Err(SimError::InvalidMove('X'))
+        );
+    }
+
+    #[test]
+    fn test_simulate_wall_is_noop() {
+        let grid = parse_grid("#L.");
+        // 壁とグリッド外への移動は何もしない
+        assert_eq!(simulate_lambdaman(&grid, "LUDR"), Ok(()));
+        assert_eq!(
+            simulate_lambdaman(&grid, "LUD"),
+            Err(SimError::UncollectedPellet {
+                y: 0,
+                x: 2,
+                remaining: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_simulate_invalid_input() {
+        assert_eq!(
+            simulate_lambdaman(&parse_grid("..."), "R"),
+            Err(SimError::NoStartCell)
+        );
+        assert_eq!(
+            simulate_lambdaman(&parse_grid("L."), "X"),
+            Err(SimError::InvalidMove('X'))
+        );
+    }
+
+    #[test]
+    fn test_simulator_in_pieces() {
+        let grid = parse_grid("###.#...\n...L..##\n.#######");
+        let mut simulator = LambdamanSimulator::new(&grid).unwrap();
+        assert_eq!(simulator.remaining(), 10);
+        for piece in ["LLLD", "URRRUD", "RR"] {
+            simulator.apply(piece).unwrap();
+        }
+        assert_eq!(simulator.remaining(), 3);
+        simulator.apply("URR").unwrap();
+        assert_eq!(simulator.remaining(), 0);
+        assert_eq!(simulator.apply("X"), Err(SimError::InvalidMove('X')));
+    }
+}
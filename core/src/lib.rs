@@ -1,3 +1,8 @@
 pub mod client;
+pub mod grid;
 pub mod parser;
+pub mod search;
+pub mod solver;
+pub mod spaceship;
+pub mod three_d;
 pub mod tsp;
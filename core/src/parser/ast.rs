@@ -1,9 +1,11 @@
 use std::{
-    collections::{HashSet, VecDeque},
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{self, Write},
     ops::{Index, IndexMut},
 };
 
-use num_bigint::BigInt;
+use num_bigint::{BigInt, Sign};
 
 use super::{
     icfpstring::ICFPString,
@@ -24,15 +26,179 @@ pub enum NodeType {
     Lazy(usize),
 }
 
+// 縮約しきった結果として得られる値
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Value {
+    Boolean(bool),
+    Integer(BigInt),
+    String(ICFPString),
+}
+
+impl Value {
+    pub fn from_node_type(node_type: NodeType) -> Result<Value, ParseError> {
+        match node_type {
+            NodeType::Boolean(b) => Ok(Value::Boolean(b)),
+            NodeType::Integer(i) => Ok(Value::Integer(i)),
+            NodeType::String(s) => Ok(Value::String(s)),
+            _ => Err(ParseError::NotAValue),
+        }
+    }
+}
+
+// 送信や表示に使う形: 真偽値は true/false、整数は 10 進、文字列は平文
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::String(s) => write!(f, "{}", s),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Node {
     pub node_id: usize,
     pub node_type: NodeType,
+    // 以下の部分木に縮約できる箇所も変数も無いことが分かっている
+    // 変数を含まないので substitute で書き換わることはなく、次の sweep からは辿らなくてよい
+    normal_form: bool,
+    // Lazy から参照されている (複数の場所で共有されうる) ので、回収してはいけない
+    shared: bool,
+    // ParserState::closed_size の memo
+    closed_size: ClosedSize,
+}
+
+// 部分木が変数も Apply も含まないか (評価が必ず止まるか) と、その時の部分木のノード数の memo
+// 子孫が縮約されると変わるので、memo した時の ParserState::closed_epoch の間だけ使う
+#[derive(Clone, Copy, Debug)]
+enum ClosedSize {
+    Unknown,
+    // 子孫を調べている途中。ここに戻ってきたら循環している
+    Visiting,
+    Known(u32, Option<usize>),
+}
+
+// 子は node_id で持っているので、node_id と node_type が同じなら同じ部分木を指している
+// normal_form などの評価用の印は比較しない
+impl PartialEq for Node {
+    fn eq(&self, other: &Self) -> bool {
+        self.node_id == other.node_id && self.node_type == other.node_type
+    }
 }
 
 impl Node {
     pub fn new(node_id: usize, node_type: NodeType) -> Node {
-        Node { node_id, node_type }
+        Node {
+            node_id,
+            node_type,
+            normal_form: false,
+            shared: false,
+            closed_size: ClosedSize::Unknown,
+        }
+    }
+
+    // node を 1 step だけ縮約した結果を返す
+    // 縮約が起きた場合は root を新しい node_id に移すので、戻り値と元の node を == で比べれば不動点か分かる
+    // 元の node_id は回収されて使い回されるので、親から参照されていない root でしか使えない
+    // Lazy の評価結果のキャッシュは呼び出しを跨いで持ち越さない
+    pub fn evaluate_once(self, node_factory: &mut NodeFactory) -> Result<Node, ParseError> {
+        if node_factory.root_id != self.node_id {
+            return Err(ParseError::NotRootNode(self.node_id));
+        }
+        let mut parser_state = ParserState::new();
+        parser_state.node_factory = std::mem::replace(node_factory, NodeFactory::new());
+
+        let mut updated = false;
+        let result = evaluate_once(&mut parser_state, self.node_id, &mut updated, 0, false);
+        *node_factory = parser_state.node_factory;
+        result?;
+
+        if !updated {
+            return Ok(node_factory[self.node_id].clone());
+        }
+        let node_type = node_factory[self.node_id].node_type.clone();
+        let new_node_id = node_factory.get_node_id();
+        node_factory[new_node_id] = Node::new(new_node_id, node_type);
+        node_factory.root_id = new_node_id;
+        node_factory.discard_node(self.node_id);
+        Ok(node_factory[new_node_id].clone())
+    }
+
+    // node から辿れるノードの数
+    // Lazy で共有・循環していても各ノードは 1 回だけ数える
+    pub fn len(&self, node_factory: &NodeFactory) -> usize {
+        self.reachable_nodes(node_factory).len()
+    }
+
+    // node から辿れるグラフを Graphviz の DOT 形式で出力する
+    // Lazy の参照先への辺は点線で描く
+    pub fn to_dot_string(&self, node_factory: &NodeFactory) -> String {
+        let mut ret = String::from("digraph ast {\n");
+        for node_id in self.reachable_nodes(node_factory) {
+            let node_type = &node_factory[node_id].node_type;
+            let label = match node_type {
+                NodeType::Boolean(b) => format!("Boolean({})", b),
+                NodeType::Integer(i) => format!("Integer({})", i),
+                NodeType::String(s) => format!("String({:?})", format!("{}", s)),
+                NodeType::Unary(opcode, _) => format!("Unary({:?})", opcode),
+                NodeType::Binary(opcode, _, _) => format!("Binary({:?})", opcode),
+                NodeType::If(_, _, _) => "If".to_string(),
+                NodeType::Lambda(v, _) => format!("Lambda({})", v),
+                NodeType::Variable(v) => format!("Variable({})", v),
+                NodeType::Lazy(_) => "Lazy".to_string(),
+            };
+            let label = label.replace('\\', "\\\\").replace('"', "\\\"");
+            ret.push_str(&format!("    n{} [label=\"{}\"];\n", node_id, label));
+
+            match *node_type {
+                NodeType::Lazy(target) => {
+                    ret.push_str(&format!(
+                        "    n{} -> n{} [style=dashed];\n",
+                        node_id, target
+                    ));
+                }
+                _ => {
+                    for child in children(node_type) {
+                        ret.push_str(&format!("    n{} -> n{};\n", node_id, child));
+                    }
+                }
+            }
+        }
+        ret.push_str("}\n");
+        ret
+    }
+
+    // 深さ優先で辿った順に、到達できる node_id を重複なく返す
+    fn reachable_nodes(&self, node_factory: &NodeFactory) -> Vec<usize> {
+        let mut visited = HashSet::new();
+        let mut order = vec![];
+        let mut stack = vec![self.node_id];
+        while let Some(node_id) = stack.pop() {
+            if !visited.insert(node_id) {
+                continue;
+            }
+            order.push(node_id);
+            // 左の子から順に出力したいので逆順に積む
+            stack.extend(children(&node_factory[node_id].node_type).into_iter().rev());
+        }
+        order
+    }
+}
+
+// node_type が直接参照している node_id (Lazy の参照先を含む)
+fn children(node_type: &NodeType) -> Vec<usize> {
+    match *node_type {
+        NodeType::Boolean(_)
+        | NodeType::Integer(_)
+        | NodeType::String(_)
+        | NodeType::Variable(_) => vec![],
+        NodeType::Unary(_, child) | NodeType::Lambda(_, child) | NodeType::Lazy(child) => {
+            vec![child]
+        }
+        NodeType::Binary(_, child1, child2) => vec![child1, child2],
+        NodeType::If(pred, first, second) => vec![pred, first, second],
     }
 }
 
@@ -68,6 +234,12 @@ impl NodeFactory {
         }
     }
 
+    // 確保したノードのうち、回収されていないものの数
+    // 回収した node_id は使い回すだけで node_buffer は縮まないので、node_buffer.len() では減っていくのが見えない
+    pub fn live_node_count(&self) -> usize {
+        self.node_buffer.len() - self.node_id_buffer.len()
+    }
+
     fn get_var_id(&mut self) -> u32 {
         let ret = self.var_id;
         self.var_id += 1;
@@ -126,11 +298,21 @@ impl NodeFactory {
     fn lazy_node(&mut self, lazy_node_id: usize) -> usize {
         let new_node_id = self.get_node_id();
         self.node_buffer[new_node_id] = Node::new(new_node_id, NodeType::Lazy(lazy_node_id));
+        self.node_buffer[lazy_node_id].shared = true;
         new_node_id
     }
 
+    // node_id を Lazy(lazy_node_id) に書き換える
+    fn set_lazy(&mut self, node_id: usize, lazy_node_id: usize) {
+        self.node_buffer[node_id].node_type = NodeType::Lazy(lazy_node_id);
+        self.node_buffer[lazy_node_id].shared = true;
+    }
+
+    // Lazy から参照されているノードは、他の場所からまだ使われているかもしれないので回収しない
     fn discard_node(&mut self, node_id: usize) {
-        self.node_id_buffer.push(node_id);
+        if !self.node_buffer[node_id].shared {
+            self.node_id_buffer.push(node_id);
+        }
     }
 }
 
@@ -148,40 +330,106 @@ impl IndexMut<usize> for NodeFactory {
     }
 }
 
-// node 以下の変数名を unique に変更する
-// 最初に呼ばれるだけのやつなので、lazy は含まれないと思ってよい。
-pub fn alpha_convert(node_id: usize, parser_state: &mut ParserState, visited: &mut HashSet<usize>) {
-    match parser_state.node_factory[node_id].node_type {
-        NodeType::Boolean(_)
-        | NodeType::Integer(_)
-        | NodeType::String(_)
-        | NodeType::Variable(_) => {}
-        NodeType::Unary(_, child) => alpha_convert(child, parser_state, visited),
-        NodeType::Binary(_, child1, child2) => {
-            alpha_convert(child1, parser_state, visited);
-            alpha_convert(child2, parser_state, visited);
+// root 以下を前順に辿り、各ノードで visit を呼ぶ。visit が false を返したノードの子は辿らない
+// 深い木でも native stack を溢れさせないよう、再帰せずに明示的なスタックで辿る
+// Lazy の中身は visited に記録して 1 回しか辿らない
+fn walk(
+    parser_state: &mut ParserState,
+    root: usize,
+    visited: &mut HashSet<usize>,
+    mut visit: impl FnMut(&mut ParserState, usize) -> Result<bool, ParseError>,
+) -> Result<(), ParseError> {
+    enum Work {
+        Enter(usize),
+        // 経路から外す時に、入る前の印に戻す
+        Exit(usize, u32),
+    }
+
+    let generation = parser_state.path_marks.begin();
+    let mut stack = vec![Work::Enter(root)];
+    while let Some(work) = stack.pop() {
+        let node_id = match work {
+            Work::Enter(node_id) => node_id,
+            Work::Exit(node_id, prev) => {
+                parser_state.path_marks.exit(node_id, prev);
+                continue;
+            }
+        };
+        let prev = parser_state.path_marks.enter(node_id, generation)?;
+        stack.push(Work::Exit(node_id, prev));
+        if !visit(parser_state, node_id)? {
+            continue;
         }
-        NodeType::If(pred, first, second) => {
-            alpha_convert(pred, parser_state, visited);
-            alpha_convert(first, parser_state, visited);
-            alpha_convert(second, parser_state, visited);
+
+        let node_type = &parser_state.node_factory[node_id].node_type;
+        if let NodeType::Lazy(lazy_node_id) = *node_type {
+            if !visited.insert(lazy_node_id) {
+                continue;
+            }
         }
-        NodeType::Lambda(var_id, child) => {
+        stack.extend(children(node_type).into_iter().rev().map(Work::Enter));
+    }
+    Ok(())
+}
+
+// node 以下の変数名を unique に変更する
+// 最初に呼ばれるだけのやつなので、lazy は含まれないと思ってよい。
+pub fn alpha_convert(
+    node_id: usize,
+    parser_state: &mut ParserState,
+    visited: &mut HashSet<usize>,
+) -> Result<(), ParseError> {
+    walk(parser_state, node_id, visited, |parser_state, node_id| {
+        if let NodeType::Lambda(var_id, child) = parser_state.node_factory[node_id].node_type {
             let new_var_id = parser_state.node_factory.get_var_id();
             // var_id を new_id に変更するための visited
             let mut local_visited = HashSet::new();
-            replace_var_id(child, var_id, new_var_id, parser_state, &mut local_visited);
+            replace_var_id(child, var_id, new_var_id, parser_state, &mut local_visited)?;
             parser_state.node_factory[node_id].node_type = NodeType::Lambda(new_var_id, child);
+        }
+        Ok(true)
+    })
+}
+
+// 今辿っている経路上のノードの印。経路に node_id が既に含まれていれば、グラフが循環している
+// visited は Lazy の中身を 1 回しか辿らないためのもので、Lazy 以外の辺で戻ってくる循環は防げない
+// 辿り始める度に世代を変えるので、途中で打ち切っても印を消して回らなくてよい
+// 入れ子で辿っても (substitute の中の replace_var_id など) 外側の印とは世代で区別できる
+#[derive(Default)]
+struct PathMarks {
+    // stamp[node_id] が今の世代なら、node_id は経路上にある
+    stamp: Vec<u32>,
+    generation: u32,
+}
 
-            alpha_convert(child, parser_state, visited);
+impl PathMarks {
+    // 新しい世代を始めて、その世代を返す
+    fn begin(&mut self) -> u32 {
+        if self.generation == u32::MAX {
+            self.stamp.fill(0);
+            self.generation = 0;
         }
-        NodeType::Lazy(lazy_node_id) => {
-            if !visited.contains(&lazy_node_id) {
-                visited.insert(lazy_node_id);
-                alpha_convert(lazy_node_id, parser_state, visited);
-            }
+        self.generation += 1;
+        self.generation
+    }
+
+    // node_id を経路に入れ、exit で戻すための元の印を返す
+    fn enter(&mut self, node_id: usize, generation: u32) -> Result<u32, ParseError> {
+        if node_id >= self.stamp.len() {
+            self.stamp
+                .resize((node_id + 1).max(self.stamp.len() * 2), 0);
+        }
+        let prev = std::mem::replace(&mut self.stamp[node_id], generation);
+        if prev == generation {
+            Err(ParseError::CyclicReference(node_id))
+        } else {
+            Ok(prev)
         }
     }
+
+    fn exit(&mut self, node_id: usize, prev: u32) {
+        self.stamp[node_id] = prev;
+    }
 }
 
 fn replace_var_id(
@@ -190,180 +438,402 @@ fn replace_var_id(
     to: u32,
     parser_state: &mut ParserState,
     visited: &mut HashSet<usize>,
-) {
-    match parser_state.node_factory[node_id].node_type {
-        NodeType::Boolean(_) | NodeType::Integer(_) | NodeType::String(_) => {}
-        NodeType::Unary(_, child) => replace_var_id(child, from, to, parser_state, visited),
-        NodeType::Binary(_, child1, child2) => {
-            replace_var_id(child1, from, to, parser_state, visited);
-            replace_var_id(child2, from, to, parser_state, visited);
-        }
-        NodeType::If(pred, first, second) => {
-            replace_var_id(pred, from, to, parser_state, visited);
-            replace_var_id(first, from, to, parser_state, visited);
-            replace_var_id(second, from, to, parser_state, visited);
-        }
-        // Lambda の場合は、束縛変数と同じ名前の変数がある場合は置換しない
-        NodeType::Lambda(var_id, child) => {
-            if var_id != from {
-                replace_var_id(child, from, to, parser_state, visited);
-            }
-        }
-        NodeType::Variable(var_id) => {
-            if var_id == from {
-                parser_state.node_factory[node_id].node_type = NodeType::Variable(to);
-            }
-        }
-        NodeType::Lazy(lazy_node_id) => {
-            if !visited.contains(&lazy_node_id) {
-                visited.insert(lazy_node_id);
-                replace_var_id(lazy_node_id, from, to, parser_state, visited);
+) -> Result<(), ParseError> {
+    walk(parser_state, node_id, visited, |parser_state, node_id| {
+        match parser_state.node_factory[node_id].node_type {
+            // Lambda の場合は、束縛変数と同じ名前の変数がある場合は置換しない
+            NodeType::Lambda(var_id, _) => Ok(var_id != from),
+            NodeType::Variable(var_id) => {
+                if var_id == from {
+                    parser_state.node_factory[node_id].node_type = NodeType::Variable(to);
+                }
+                Ok(false)
             }
+            _ => Ok(true),
         }
-    }
+    })
 }
 
+// token_stream から前置記法で 1 つの式を読んで node を作る
+// トークンは tokenize_iter から 1 つずつ受け取れば良いので、全トークンを集めておく必要はない
+// 深くネストした式でも native stack を溢れさせないよう、被演算子が揃っていない演算子をスタックに積んでおく
 fn construct_node(
     parser_state: &mut ParserState,
-    token_stream: &mut VecDeque<TokenType>,
+    token_stream: &mut impl Iterator<Item = Result<TokenType, ParseError>>,
 ) -> Result<usize, ParseError> {
-    if let Some(token) = token_stream.pop_front() {
-        let node = match token {
+    fn arity(token: &TokenType) -> usize {
+        match token {
+            TokenType::Boolean(_)
+            | TokenType::Integer(_)
+            | TokenType::String(_)
+            | TokenType::Variable(_) => 0,
+            TokenType::Unary(_) | TokenType::Lambda(_) => 1,
+            TokenType::Binary(_) => 2,
+            TokenType::If => 3,
+        }
+    }
+
+    // 被演算子を読んでいる途中の演算子
+    let mut pending: Vec<(TokenType, Vec<usize>)> = vec![];
+    loop {
+        let token = token_stream
+            .next()
+            .transpose()?
+            .ok_or(ParseError::CannotFindNextToken)?;
+        let mut node = match token {
             TokenType::Boolean(b) => parser_state.node_factory.boolean_node(b),
             TokenType::Integer(i) => parser_state.node_factory.integer_node(i),
             TokenType::String(s) => parser_state.node_factory.string_node(s),
-            TokenType::Unary(opcode) => {
-                let operand = construct_node(parser_state, token_stream)?;
-                parser_state.node_factory.unary_node(opcode, operand)
-            }
-            TokenType::Binary(opcode) => {
-                let operand1 = construct_node(parser_state, token_stream)?;
-                let operand2 = construct_node(parser_state, token_stream)?;
-                parser_state
-                    .node_factory
-                    .binary_node(opcode, operand1, operand2)
-            }
-            TokenType::If => {
-                let operand1 = construct_node(parser_state, token_stream)?;
-                let operand2 = construct_node(parser_state, token_stream)?;
-                let operand3 = construct_node(parser_state, token_stream)?;
-                parser_state
-                    .node_factory
-                    .if_node(operand1, operand2, operand3)
-            }
-            TokenType::Lambda(i) => {
-                let operand = construct_node(parser_state, token_stream)?;
-                parser_state.node_factory.lambda_node(i, operand)
-            }
             TokenType::Variable(i) => parser_state.node_factory.variable_node(i),
+            token => {
+                let operands = Vec::with_capacity(arity(&token));
+                pending.push((token, operands));
+                continue;
+            }
         };
-        Ok(node)
-    } else {
-        Err(ParseError::CannotFindNextToken)
+
+        // 被演算子が揃った演算子から順に node にする
+        loop {
+            let Some((token, operands)) = pending.last_mut() else {
+                return Ok(node);
+            };
+            operands.push(node);
+            if operands.len() < arity(token) {
+                break;
+            }
+            let (token, operands) = pending.pop().unwrap();
+            let factory = &mut parser_state.node_factory;
+            node = match (token, operands.as_slice()) {
+                (TokenType::Unary(opcode), &[operand]) => factory.unary_node(opcode, operand),
+                (TokenType::Binary(opcode), &[operand1, operand2]) => {
+                    factory.binary_node(opcode, operand1, operand2)
+                }
+                (TokenType::If, &[operand1, operand2, operand3]) => {
+                    factory.if_node(operand1, operand2, operand3)
+                }
+                (TokenType::Lambda(i), &[operand]) => factory.lambda_node(i, operand),
+                _ => unreachable!("operand count does not match arity"),
+            };
+        }
     }
 }
 
-pub fn print_node(parsre_state: &ParserState) {
-    fn print_node_inner(parsre_state: &ParserState, node_id: usize, depth: usize) {
-        let node = &parsre_state.node_factory[node_id];
+// print_node で出力するノード数の既定値
+// 巨大な AST をそのまま出力すると端末が固まるので、デバッグ出力はこれで打ち切る
+pub const DEFAULT_PRINT_NODE_LIMIT: usize = 300;
+
+pub fn print_node(parser_state: &ParserState) {
+    print_node_with_limit(parser_state, Some(DEFAULT_PRINT_NODE_LIMIT));
+}
+
+// max_nodes 個のノードを出力したら、残りは "..." の 1 行にまとめて打ち切る
+// None を渡すと全て出力する
+pub fn print_node_with_limit(parser_state: &ParserState, max_nodes: Option<usize>) {
+    let stdout = io::stdout();
+    write_node(&mut stdout.lock(), parser_state, max_nodes).unwrap();
+}
+
+struct NodeWriter<'a, W: Write> {
+    out: &'a mut W,
+    parser_state: &'a ParserState,
+    // 出力できる残りのノード数。None なら無制限
+    remaining: Option<usize>,
+    // 打ち切った後は "..." も含めて何も出力しない
+    truncated: bool,
+}
+
+impl<W: Write> NodeWriter<'_, W> {
+    fn write(&mut self, node_id: usize, depth: usize) -> io::Result<()> {
+        if self.truncated {
+            return Ok(());
+        }
         let indent = "    ".repeat(depth);
+        if self.remaining == Some(0) {
+            self.truncated = true;
+            return writeln!(self.out, "{}...", indent);
+        }
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= 1;
+        }
+
+        let node = &self.parser_state.node_factory[node_id];
         match node.node_type.clone() {
             NodeType::Boolean(b) => {
-                println!("{}Boolean({})", indent, b);
+                writeln!(self.out, "{}Boolean({})", indent, b)?;
             }
             NodeType::Integer(i) => {
-                println!("{}Integer({})", indent, i);
+                writeln!(self.out, "{}Integer({})", indent, i)?;
             }
             NodeType::String(s) => {
-                println!("{}String({})", indent, s);
+                writeln!(self.out, "{}String({})", indent, s)?;
             }
             NodeType::Unary(opcode, child) => {
-                println!("{}Unary({:?})", indent, opcode);
-                print_node_inner(parsre_state, child, depth + 1);
+                writeln!(self.out, "{}Unary({:?})", indent, opcode)?;
+                self.write(child, depth + 1)?;
             }
             NodeType::Binary(opcode, child1, child2) => {
-                println!("{}Binary({:?})", indent, opcode);
-                print_node_inner(parsre_state, child1, depth + 1);
-                print_node_inner(parsre_state, child2, depth + 1);
+                writeln!(self.out, "{}Binary({:?})", indent, opcode)?;
+                self.write(child1, depth + 1)?;
+                self.write(child2, depth + 1)?;
             }
             NodeType::If(pred, first, second) => {
-                println!("{}If", indent);
-                print_node_inner(parsre_state, pred, depth + 1);
-                print_node_inner(parsre_state, first, depth + 1);
-                print_node_inner(parsre_state, second, depth + 1);
+                writeln!(self.out, "{}If", indent)?;
+                self.write(pred, depth + 1)?;
+                self.write(first, depth + 1)?;
+                self.write(second, depth + 1)?;
             }
             NodeType::Lambda(var_id, child) => {
-                println!("{}Lambda({})", indent, var_id);
-                print_node_inner(parsre_state, child, depth + 1);
+                writeln!(self.out, "{}Lambda({})", indent, var_id)?;
+                self.write(child, depth + 1)?;
             }
             NodeType::Variable(var_id) => {
-                println!("{}Variable({})", indent, var_id);
+                writeln!(self.out, "{}Variable({})", indent, var_id)?;
             }
             NodeType::Lazy(var_id) => {
-                println!("{}Lazy({})", indent, var_id);
+                writeln!(self.out, "{}Lazy({})", indent, var_id)?;
             }
         }
+        Ok(())
     }
-    print_node_inner(parsre_state, parsre_state.node_factory.root_id, 0);
-    println!();
-    println!("cache: ");
+}
+
+// 上限は root の木と cache 全体で共有する
+fn write_node(
+    out: &mut impl Write,
+    parser_state: &ParserState,
+    max_nodes: Option<usize>,
+) -> io::Result<()> {
+    let mut writer = NodeWriter {
+        out,
+        parser_state,
+        remaining: max_nodes,
+        truncated: false,
+    };
+    writer.write(parser_state.node_factory.root_id, 0)?;
+    writeln!(writer.out)?;
+    writeln!(writer.out, "cache: ")?;
     let mut set = HashSet::new();
-    for n in parsre_state.node_factory.node_buffer.iter() {
+    for n in parser_state.node_factory.node_buffer.iter() {
         if let NodeType::Lazy(lazy_node_id) = n.node_type {
             set.insert(lazy_node_id);
         }
     }
     for &lazy_node_id in set.iter() {
-        println!("key: {}", lazy_node_id);
-        print_node_inner(parsre_state, lazy_node_id, 1);
+        if writer.truncated {
+            break;
+        }
+        writeln!(writer.out, "key: {}", lazy_node_id)?;
+        writer.write(lazy_node_id, 1)?;
+    }
+    writeln!(writer.out, "-----")
+}
+
+// parse の挙動の設定
+#[derive(Clone, Copy, Debug)]
+pub struct ParseConfig {
+    // 縮約の途中経過 (node の木や iteration 数) を標準出力に出す
+    pub debug: bool,
+    // この回数縮約しても終わらなければ EvaluationLimitExceeded を返す
+    pub max_iterations: usize,
+    pub strategy: EvalStrategy,
+}
+
+// 縮約にかかった手間の統計
+// 圧縮方法ごとの評価コストを比べるのに使う
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EvalStats {
+    // 実際に縮約を行った回数。max_iterations の調整に使う
+    pub iterations: usize,
+    // 各 iteration の開始時の、使われているノード数の最大値
+    pub max_node_count: usize,
+    // Apply(Lambda, _) を beta 簡約した回数
+    pub beta_reductions: usize,
+    // evaluate_once で辿った最も深いノードの深さ
+    pub peak_depth: usize,
+}
+
+// parse_with_config の結果
+#[derive(Clone, Debug)]
+pub struct ParseOutput {
+    pub node: Node,
+    pub stats: EvalStats,
+}
+
+impl Default for ParseConfig {
+    fn default() -> ParseConfig {
+        ParseConfig {
+            debug: false,
+            max_iterations: 10_000_000,
+            strategy: EvalStrategy::default(),
+        }
     }
-    println!("-----");
 }
 
 pub fn parse(input: String) -> Result<Node, ParseError> {
-    let mut parser_state = ParserState::new();
-    let token_list = tokenizer::tokenize(input)?;
-    let mut queue = VecDeque::from_iter(token_list);
-    let root_node_id = construct_node(&mut parser_state, &mut queue)?;
+    parse_with_config(input, ParseConfig::default()).map(|output| output.node)
+}
+
+pub fn parse_with_config(input: String, config: ParseConfig) -> Result<ParseOutput, ParseError> {
+    parse_with_config_and_callback(input, config, |_, _| {})
+}
+
+// parse_with_config と同じだが、各 iteration の開始時に (iter, 使われているノード数) を on_iteration にも渡す
+// EvaluationLimitExceeded で終わった時に、どこまで縮約できていたかを知るのに使う
+pub fn parse_with_config_and_callback(
+    input: String,
+    config: ParseConfig,
+    mut on_iteration: impl FnMut(usize, usize),
+) -> Result<ParseOutput, ParseError> {
+    let (node, stats) = parse_inner(input, config, |iter, node_len| {
+        if config.debug && iter % 1000 == 0 {
+            println!("iter: {}, node_len: {}", iter, node_len);
+        }
+        on_iteration(iter, node_len);
+    })?;
+    Ok(ParseOutput { node, stats })
+}
+
+// 縮約の各 iteration の開始時に (iter, node_len) を on_iteration に渡す
+// ログの出し方は呼び出し側に任せる
+pub fn parse_with_callback(
+    input: String,
+    on_iteration: impl FnMut(usize, usize),
+) -> Result<Node, ParseError> {
+    parse_inner(input, ParseConfig::default(), on_iteration).map(|(node, _)| node)
+}
+
+pub fn parse_with_strategy(input: String, strategy: EvalStrategy) -> Result<Node, ParseError> {
+    let config = ParseConfig {
+        strategy,
+        ..ParseConfig::default()
+    };
+    parse_inner(input, config, |_, _| {}).map(|(node, _)| node)
+}
+
+// 入力を受け取る問題用の評価
+// program は入力を受け取る 1 引数の関数 (最も外側の lambda の引数が入力) として、
+// `B$ <program> S<input>` を縮約した結果を返す
+pub fn evaluate_with_input(program: String, input: ICFPString) -> Result<Value, ParseError> {
+    let input = input.to_string()?.into_iter().collect::<String>();
+    evaluate_to_value(format!("B$ {} S{}", program, input), ParseConfig::default())
+}
+
+// program を config で縮約して、結果を値として返す
+// 関数などの値でないものが残った場合はエラー
+pub fn evaluate_to_value(program: String, config: ParseConfig) -> Result<Value, ParseError> {
+    let output = parse_with_config(program, config)?;
+    Value::from_node_type(output.node.node_type)
+}
+
+// プログラムを読んで、縮約する前の状態を作る
+// 変数名は alpha_convert で unique にしておく
+pub fn construct_program(input: &str, strategy: EvalStrategy) -> Result<ParserState, ParseError> {
+    let mut parser_state = ParserState::with_strategy(strategy);
+    let mut token_stream = tokenizer::tokenize_iter(input);
+    let root_node_id = construct_node(&mut parser_state, &mut token_stream)?;
     parser_state.node_factory.root_id = root_node_id;
 
-    let debug = false;
-    {
-        let mut visited = HashSet::new();
-        alpha_convert(
-            parser_state.node_factory.root_id,
-            &mut parser_state,
-            &mut visited,
-        );
-    }
-    if true {
+    let mut visited = HashSet::new();
+    alpha_convert(root_node_id, &mut parser_state, &mut visited)?;
+    Ok(parser_state)
+}
+
+fn parse_inner(
+    input: String,
+    config: ParseConfig,
+    mut on_iteration: impl FnMut(usize, usize),
+) -> Result<(Node, EvalStats), ParseError> {
+    let mut parser_state = construct_program(&input, config.strategy)?;
+
+    let debug = config.debug;
+    if debug {
         print_node(&parser_state);
     }
 
-    for iter in 0..10_000_000 {
-        let period = if debug { 1 } else { 1000 };
-        if iter % period == 0 {
-            println!(
-                "iter: {}, node_len: {}",
-                iter,
-                parser_state.node_factory.node_buffer.len()
-            );
-        }
+    // max_iterations 回縮約した後、もう縮約できないことを確かめる分だけ 1 回多く回す
+    for iter in 0..=config.max_iterations {
+        let node_count = parser_state.node_factory.live_node_count();
+        on_iteration(iter, node_count);
+        parser_state.stats.max_node_count = parser_state.stats.max_node_count.max(node_count);
         let mut updated = false;
         let root_id = parser_state.node_factory.root_id;
 
-        evaluate_once(&mut parser_state, root_id, &mut updated, 0, debug);
+        evaluate_once(&mut parser_state, root_id, &mut updated, 0, debug)?;
         if debug {
             print_node(&parser_state);
         }
 
         if !updated {
-            println!("break because not updated");
-            break;
+            if debug {
+                println!("break because not updated");
+            }
+            let result = parser_state.node_factory[parser_state.node_factory.root_id].clone();
+            parser_state.stats.iterations = iter;
+            return Ok((result, parser_state.stats));
+        }
+    }
+    Err(ParseError::EvaluationLimitExceeded(config.max_iterations))
+}
+
+// root 以下をトークン列に戻して、tokenizer がそのまま読めるプログラム文字列を作る
+// Lazy は中身を展開して出力するので、共有されている部分木は複製される
+// Lazy を辿って同じノードに戻ってくる場合は文字列にできないので CyclicReference を返す
+pub fn to_program_string(parser_state: &ParserState, root: usize) -> Result<String, ParseError> {
+    enum Work {
+        Enter(usize),
+        Exit(usize),
+    }
+
+    fn encode_number(n: u32) -> Result<String, ParseError> {
+        Ok(ICFPString::from_int(BigInt::from(n))
+            .to_string()?
+            .into_iter()
+            .collect())
+    }
+
+    let node_factory = &parser_state.node_factory;
+    let mut tokens = vec![];
+    let mut on_path = HashSet::new();
+    let mut stack = vec![Work::Enter(root)];
+    while let Some(work) = stack.pop() {
+        let node_id = match work {
+            Work::Enter(node_id) => node_id,
+            Work::Exit(node_id) => {
+                on_path.remove(&node_id);
+                continue;
+            }
+        };
+        if !on_path.insert(node_id) {
+            return Err(ParseError::CyclicReference(node_id));
+        }
+        stack.push(Work::Exit(node_id));
+
+        let node_type = &node_factory[node_id].node_type;
+        match node_type {
+            NodeType::Boolean(b) => tokens.push(if *b { "T" } else { "F" }.to_string()),
+            NodeType::Integer(i) => {
+                // 整数リテラルは非負なので、負の数は U- を付けて表す
+                if i.sign() == Sign::Minus {
+                    tokens.push(UnaryOpecode::Negate.to_token_str().to_string());
+                }
+                let digits =
+                    ICFPString::from_int(BigInt::from(i.magnitude().clone())).to_string()?;
+                tokens.push(format!("I{}", digits.into_iter().collect::<String>()));
+            }
+            NodeType::String(s) => tokens.push(format!(
+                "S{}",
+                s.to_string()?.into_iter().collect::<String>()
+            )),
+            NodeType::Unary(opcode, _) => tokens.push(opcode.to_token_str().to_string()),
+            NodeType::Binary(opcode, _, _) => tokens.push(opcode.to_token_str().to_string()),
+            NodeType::If(_, _, _) => tokens.push("?".to_string()),
+            NodeType::Lambda(v, _) => tokens.push(format!("L{}", encode_number(*v)?)),
+            NodeType::Variable(v) => tokens.push(format!("v{}", encode_number(*v)?)),
+            NodeType::Lazy(_) => {}
         }
+        stack.extend(children(node_type).into_iter().rev().map(Work::Enter));
     }
-    let result = parser_state.node_factory[parser_state.node_factory.root_id].clone();
-    Ok(result)
+    Ok(tokens.join(" "))
 }
 
 // apply をするために variable(var_id) を node で置換する
@@ -372,72 +842,264 @@ pub fn substitute(
     var_id: u32,
     node_id: usize,
     parser_state: &mut ParserState,
-) {
+) -> Result<(), ParseError> {
     // Variable(X) を Lazy(X) で置換する
-    fn substitute_inner(
-        node_id: usize,
-        var_id: u32,
-        lazy_node_id: usize,
-        parser_state: &mut ParserState,
-        visited: &mut HashSet<usize>,
-    ) {
-        let nt = parser_state.node_factory[node_id].node_type.clone();
-        match nt {
-            NodeType::Boolean(_) | NodeType::Integer(_) | NodeType::String(_) => {}
-            NodeType::Unary(_, child) => {
-                substitute_inner(child, var_id, lazy_node_id, parser_state, visited)
+    let mut visited = HashSet::new();
+    walk(
+        parser_state,
+        root_node_id,
+        &mut visited,
+        |parser_state, id| {
+            match parser_state.node_factory[id].node_type {
+                // 同名の束縛変数がある場合は置換しない
+                NodeType::Lambda(child_var_id, _) => Ok(var_id != child_var_id),
+                NodeType::Variable(child_var_id) => {
+                    if var_id == child_var_id {
+                        parser_state.node_factory.set_lazy(id, node_id);
+                    }
+                    Ok(false)
+                }
+                _ => Ok(true),
             }
-            NodeType::Binary(_, child1, child2) => {
-                substitute_inner(child1, var_id, lazy_node_id, parser_state, visited);
-                substitute_inner(child2, var_id, lazy_node_id, parser_state, visited);
+        },
+    )
+}
+
+// Lazy の連鎖をたどって、中身のノードを返す
+// 途中の Lazy は全て中身を直接指すように張り替える
+pub fn extract_node(
+    parser_state: &mut ParserState,
+    node_id: usize,
+    _updated: &mut bool,
+) -> Result<usize, ParseError> {
+    // 全ノード数より長い連鎖は必ずどこかで循環している
+    let limit = parser_state.node_factory.node_buffer.len();
+    let mut inner = node_id;
+    let mut step = 0;
+    while let NodeType::Lazy(lazy_node_id) = parser_state.node_factory[inner].node_type {
+        step += 1;
+        if step > limit {
+            return Err(ParseError::CyclicReference(node_id));
+        }
+        inner = lazy_node_id;
+    }
+
+    let mut current = node_id;
+    while let NodeType::Lazy(lazy_node_id) = parser_state.node_factory[current].node_type {
+        parser_state.node_factory.set_lazy(current, inner);
+        current = lazy_node_id;
+    }
+    Ok(inner)
+}
+
+// Apply(Lambda(var_id, child1_inner), child2) を child1_inner[var_id := child2] で置き換える
+fn beta_reduce(
+    parser_state: &mut ParserState,
+    node_id: usize,
+    var_id: u32,
+    child1_inner: usize,
+    child2: usize,
+) -> Result<(), ParseError> {
+    // Apply の第1項が lambda の時、lambda の中身を substitute して更新するだけではなく、
+    // Apply を適用した結果 lazy で上書きする必要がある
+    // この時、既存の node を使いまわしてしまうと、apply した項としない項を区別できなくなってしまうので、
+    // clone する必要がある
+    // - apply
+    //   - child1(lambda)
+    //     - varX
+    //     - child1_inner
+    //   - child2
+    // --------
+    // - cloned_child1_inner
+
+    let cloned_child1_node_id = parser_state.shallow_clone(child1_inner)?;
+    let new_var_id = parser_state.node_factory.get_var_id();
+    let mut local_visited = HashSet::new();
+    replace_var_id(
+        cloned_child1_node_id,
+        var_id,
+        new_var_id,
+        parser_state,
+        &mut local_visited,
+    )?;
+    substitute(cloned_child1_node_id, new_var_id, child2, parser_state)?;
+    parser_state.node_factory[node_id].node_type = parser_state.node_factory[cloned_child1_node_id]
+        .node_type
+        .clone();
+    Ok(())
+}
+
+pub fn evaluate_once(
+    parser_state: &mut ParserState,
+    node_id: usize,
+    updated: &mut bool,
+    depth: usize,
+    debug: bool,
+) -> Result<(), ParseError> {
+    // 巨大なプログラムでも native stack を溢れさせないよう、再帰せずに明示的なスタックで辿る
+    // Visit は子を左から順に調べ、全ての子を調べ終わったら Finish で normal form かを判定する
+    // Visit の bool は、evaluate_node が求めた前提の部分木の縮約を済ませたか
+    enum Work {
+        Visit(usize, usize, bool),
+        // 経路から外す時に、入る前の印に戻す
+        Finish(usize, u32),
+    }
+
+    // 1 つの部分木を根から辿る sweep。前提の部分木を縮約する間は、その sweep を上に積む
+    struct Sweep {
+        root: usize,
+        depth: usize,
+        // 縮約が起きなくなるまで sweep を繰り返すか (Prerequisite::NormalForm)
+        repeat: bool,
+        stack: Vec<Work>,
+        // 根から今見ているノードまでの経路に付ける印の世代。同じノードに戻ってきたら循環している
+        generation: u32,
+        updated: bool,
+        // sweep を始める前から縮約済みだったなら、normal form の印を付けない
+        updated_before: bool,
+        // repeat の時、これまでの sweep で縮約が起きたか
+        changed: bool,
+        // 上に積んだ前提の sweep で縮約が起きたか
+        prerequisite_updated: bool,
+    }
+
+    impl Sweep {
+        fn new(
+            parser_state: &mut ParserState,
+            root: usize,
+            depth: usize,
+            repeat: bool,
+            updated: bool,
+        ) -> Sweep {
+            Sweep {
+                root,
+                depth,
+                repeat,
+                stack: vec![Work::Visit(root, depth, false)],
+                generation: parser_state.path_marks.begin(),
+                updated,
+                updated_before: updated,
+                changed: false,
+                prerequisite_updated: false,
             }
-            NodeType::If(pred, first, second) => {
-                substitute_inner(pred, var_id, lazy_node_id, parser_state, visited);
-                substitute_inner(first, var_id, lazy_node_id, parser_state, visited);
-                substitute_inner(second, var_id, lazy_node_id, parser_state, visited);
+        }
+    }
+
+    // 呼び出しの間にノードが書き換えられているかもしれないので、ClosedSize の memo は捨てる
+    parser_state.closed_epoch += 1;
+    let mut sweeps = vec![Sweep::new(parser_state, node_id, depth, false, *updated)];
+    let mut children = vec![];
+    let mut prerequisites = vec![];
+    while let Some(sweep) = sweeps.last_mut() {
+        let Some(work) = sweep.stack.pop() else {
+            // 縮約が起きずに一周したので、この sweep は終わり
+            let sweep = sweeps.pop().unwrap();
+            if let Some(parent) = sweeps.last_mut() {
+                parent.prerequisite_updated |= sweep.changed;
             }
-            NodeType::Lambda(child_var_id, child) => {
-                // 同名の束縛変数がある場合は置換しない
-                if var_id != child_var_id {
-                    substitute_inner(child, var_id, lazy_node_id, parser_state, visited);
+            continue;
+        };
+        match work {
+            Work::Visit(node_id, depth, prerequisites_done) => {
+                // 前の sweep までに縮約しきった部分木は辿らない
+                if parser_state.skip_normal_form && parser_state.node_factory[node_id].normal_form {
+                    continue;
                 }
-            }
-            NodeType::Variable(child_var_id) => {
-                if var_id == child_var_id {
-                    parser_state.node_factory[node_id].node_type = NodeType::Lazy(lazy_node_id);
+                let prev = parser_state.path_marks.enter(node_id, sweep.generation)?;
+                parser_state.node_visits += 1;
+                parser_state.stats.peak_depth = parser_state.stats.peak_depth.max(depth);
+
+                children.clear();
+                if prerequisites_done {
+                    sweep.updated |= std::mem::take(&mut sweep.prerequisite_updated);
+                }
+                evaluate_node(
+                    parser_state,
+                    node_id,
+                    &mut sweep.updated,
+                    depth,
+                    debug,
+                    &mut children,
+                    (!prerequisites_done).then_some(&mut prerequisites),
+                )?;
+
+                if !prerequisites.is_empty() {
+                    // 前提の部分木を縮約してから、もう一度このノードを調べる
+                    // 左の被演算子から縮約したいので逆順に積む
+                    parser_state.path_marks.exit(node_id, prev);
+                    sweep.stack.push(Work::Visit(node_id, depth, true));
+                    for prerequisite in prerequisites.drain(..).rev() {
+                        let (root, repeat) = match prerequisite {
+                            Prerequisite::Once(root) => (root, false),
+                            Prerequisite::NormalForm(root) => (root, true),
+                        };
+                        let sweep = Sweep::new(parser_state, root, depth + 1, repeat, false);
+                        sweeps.push(sweep);
+                    }
+                    continue;
+                }
+
+                // 1 回縮約したら、残りのノードは次の sweep で調べる
+                if sweep.updated {
+                    parser_state.closed_epoch += 1;
+                    if sweep.repeat {
+                        let (root, depth) = (sweep.root, sweep.depth);
+                        *sweep = Sweep::new(parser_state, root, depth, true, false);
+                        sweep.changed = true;
+                        continue;
+                    }
+                    sweeps.pop();
+                    match sweeps.last_mut() {
+                        Some(parent) => parent.prerequisite_updated = true,
+                        None => {
+                            *updated = true;
+                            return Ok(());
+                        }
+                    }
+                    continue;
                 }
+                sweep.stack.push(Work::Finish(node_id, prev));
+                sweep.stack.extend(
+                    children
+                        .iter()
+                        .rev()
+                        .map(|&child| Work::Visit(child, depth + 1, false)),
+                );
             }
-            NodeType::Lazy(inner_node_id) => {
-                if !visited.contains(&inner_node_id) {
-                    visited.insert(inner_node_id);
-                    substitute_inner(inner_node_id, var_id, lazy_node_id, parser_state, visited);
+            Work::Finish(node_id, prev) => {
+                parser_state.path_marks.exit(node_id, prev);
+                // 何も縮約されなかったなら、子が全て normal form であればこのノードも normal form
+                if !sweep.updated_before && parser_state.is_normal_form_candidate(node_id) {
+                    parser_state.node_factory[node_id].normal_form = true;
                 }
             }
         }
     }
-
-    let mut visited = HashSet::new();
-    substitute_inner(root_node_id, var_id, node_id, parser_state, &mut visited);
+    Ok(())
 }
 
-pub fn extract_node(parser_state: &mut ParserState, node_id: usize, updated: &mut bool) -> usize {
-    match parser_state.node_factory[node_id].node_type.clone() {
-        NodeType::Lazy(lazy_node_id) => {
-            let inner = extract_node(parser_state, lazy_node_id, updated);
-            parser_state.node_factory[node_id].node_type = NodeType::Lazy(inner);
-            inner
-        }
-        _ => node_id,
-    }
+// evaluate_node が、自身を縮約する前に縮約しておきたい部分木
+enum Prerequisite {
+    // 1 回だけ縮約を試す (StrictSmallArgument の Apply の引数)
+    Once(usize),
+    // これ以上縮約できなくなるまで縮約する (Eager の算術演算の被演算子)
+    // 停止することが分かっている部分木にだけ使う
+    NormalForm(usize),
 }
 
-pub fn evaluate_once(
+// node_id 自身を 1 step 縮約してみる
+// 縮約できなかった場合に続けて調べるべき子は、調べる順に children に積む
+// 先に縮約しておきたい部分木があれば prerequisites に積んで何もせずに返る
+// 呼び出し側はそれを縮約してから、prerequisites を None にしてもう一度呼ぶ
+fn evaluate_node(
     parser_state: &mut ParserState,
     node_id: usize,
     updated: &mut bool,
     depth: usize,
     debug: bool,
-) {
+    children: &mut Vec<usize>,
+    mut prerequisites: Option<&mut Vec<Prerequisite>>,
+) -> Result<(), ParseError> {
     if debug {
         println!("depth: {}", depth);
         println!(
@@ -479,7 +1141,7 @@ pub fn evaluate_once(
         | NodeType::String(_)
         | NodeType::Variable(_) => {}
         NodeType::Unary(opcode, child_id) => {
-            let child_id = extract_node(parser_state, child_id, updated);
+            let child_id = extract_node(parser_state, child_id, updated)?;
             let child_type = parser_state.node_factory[child_id].node_type.clone();
 
             match opcode {
@@ -515,14 +1177,25 @@ pub fn evaluate_once(
                 },
             }
             if !*updated {
-                evaluate_once(parser_state, child_id, updated, depth + 1, debug);
+                children.push(child_id);
             }
         }
         NodeType::Binary(opcode, child1, child2) => {
-            let child1 = extract_node(parser_state, child1, updated);
+            if let Some(prerequisites) = &mut prerequisites {
+                for child in [child1, child2] {
+                    if parser_state.should_force_operand(opcode, child) {
+                        prerequisites.push(Prerequisite::NormalForm(child));
+                    }
+                }
+                if !prerequisites.is_empty() {
+                    return Ok(());
+                }
+            }
+
+            let child1 = extract_node(parser_state, child1, updated)?;
             let child_type1 = parser_state.node_factory[child1].node_type.clone();
 
-            let child2 = extract_node(parser_state, child2, updated);
+            let child2 = extract_node(parser_state, child2, updated)?;
             let child_type2 = parser_state.node_factory[child2].node_type.clone();
 
             match opcode {
@@ -539,29 +1212,28 @@ pub fn evaluate_once(
                         NodeType::Integer(i1),
                         NodeType::Binary(BinaryOpecode::Add, child3, child4),
                     ) => {
-                        let child3 = extract_node(parser_state, child3, updated);
+                        let child3 = extract_node(parser_state, child3, updated)?;
                         let child_type3 = parser_state.node_factory[child3].node_type.clone();
-                        let child4 = extract_node(parser_state, child4, updated);
+                        let child4 = extract_node(parser_state, child4, updated)?;
                         let child_type4 = parser_state.node_factory[child4].node_type.clone();
 
                         // Add(fix, Add(var, fix)) => Add(Add(fix, fix), var) みたいにすると、1つ階層が減る
+                        // 子は Lazy 経由で共有されているかもしれないので書き換えず、新しいノードを作る
                         match (child_type3, child_type4) {
                             (NodeType::Integer(i3), _) => {
                                 *updated = true;
-                                parser_state.node_factory[child1].node_type =
-                                    NodeType::Integer(i1 + i3);
-                                parser_state.node_factory[child2].node_type =
-                                    parser_state.node_factory[child4].node_type.clone();
+                                let sum = parser_state.node_factory.integer_node(i1 + i3);
+                                parser_state.node_factory[node_id].node_type =
+                                    NodeType::Binary(BinaryOpecode::Add, sum, child4);
 
                                 // 即値を移したので回収
                                 parser_state.node_factory.discard_node(child3);
                             }
                             (_, NodeType::Integer(i4)) => {
                                 *updated = true;
-                                parser_state.node_factory[child1].node_type =
-                                    NodeType::Integer(i1 + i4);
-                                parser_state.node_factory[child2].node_type =
-                                    parser_state.node_factory[child3].node_type.clone();
+                                let sum = parser_state.node_factory.integer_node(i1 + i4);
+                                parser_state.node_factory[node_id].node_type =
+                                    NodeType::Binary(BinaryOpecode::Add, sum, child3);
 
                                 // 即値を移したので回収
                                 parser_state.node_factory.discard_node(child4);
@@ -573,29 +1245,27 @@ pub fn evaluate_once(
                         NodeType::Binary(BinaryOpecode::Add, child3, child4),
                         NodeType::Integer(i2),
                     ) => {
-                        let child3 = extract_node(parser_state, child3, updated);
+                        let child3 = extract_node(parser_state, child3, updated)?;
                         let child_type3 = parser_state.node_factory[child3].node_type.clone();
-                        let child4 = extract_node(parser_state, child4, updated);
+                        let child4 = extract_node(parser_state, child4, updated)?;
                         let child_type4 = parser_state.node_factory[child4].node_type.clone();
 
                         // Add(fix, Add(var, fix)) => Add(var, Add(fix, fix)) みたいにすると、1つ階層が減る
                         match (child_type3, child_type4) {
                             (NodeType::Integer(i3), _) => {
                                 *updated = true;
-                                parser_state.node_factory[child2].node_type =
-                                    NodeType::Integer(i2 + i3);
-                                parser_state.node_factory[child1].node_type =
-                                    parser_state.node_factory[child4].node_type.clone();
+                                let sum = parser_state.node_factory.integer_node(i2 + i3);
+                                parser_state.node_factory[node_id].node_type =
+                                    NodeType::Binary(BinaryOpecode::Add, child4, sum);
 
                                 // 即値を移したので回収
                                 parser_state.node_factory.discard_node(child3);
                             }
                             (_, NodeType::Integer(i4)) => {
                                 *updated = true;
-                                parser_state.node_factory[child2].node_type =
-                                    NodeType::Integer(i2 + i4);
-                                parser_state.node_factory[child1].node_type =
-                                    parser_state.node_factory[child3].node_type.clone();
+                                let sum = parser_state.node_factory.integer_node(i2 + i4);
+                                parser_state.node_factory[node_id].node_type =
+                                    NodeType::Binary(BinaryOpecode::Add, child3, sum);
 
                                 // 即値を移したので回収
                                 parser_state.node_factory.discard_node(child4);
@@ -654,6 +1324,10 @@ pub fn evaluate_once(
                     _ => {}
                 },
                 BinaryOpecode::Div => match (child_type1, child_type2) {
+                    // BigInt の 0 除算は panic するので、その前にエラーにする
+                    (NodeType::Integer(_), NodeType::Integer(i2)) if i2 == BigInt::ZERO => {
+                        return Err(ParseError::DivisionByZero(node_id));
+                    }
                     (NodeType::Integer(i1), NodeType::Integer(i2)) => {
                         *updated = true;
                         parser_state.node_factory[node_id].node_type = NodeType::Integer(i1 / i2);
@@ -661,20 +1335,23 @@ pub fn evaluate_once(
                     _ => {}
                 },
                 BinaryOpecode::Modulo => match (child_type1, child_type2) {
+                    (NodeType::Integer(_), NodeType::Integer(i2)) if i2 == BigInt::ZERO => {
+                        return Err(ParseError::DivisionByZero(node_id));
+                    }
                     (NodeType::Integer(i1), NodeType::Integer(i2)) => {
                         *updated = true;
                         parser_state.node_factory[node_id].node_type = NodeType::Integer(i1 % i2);
                     }
                     _ => {}
                 },
-                BinaryOpecode::IntegerLarger => match (child_type1, child_type2) {
+                BinaryOpecode::LessThan => match (child_type1, child_type2) {
                     (NodeType::Integer(i1), NodeType::Integer(i2)) => {
                         *updated = true;
                         parser_state.node_factory[node_id].node_type = NodeType::Boolean(i1 < i2);
                     }
                     _ => {}
                 },
-                BinaryOpecode::IntegerSmaller => match (child_type1, child_type2) {
+                BinaryOpecode::GreaterThan => match (child_type1, child_type2) {
                     (NodeType::Integer(i1), NodeType::Integer(i2)) => {
                         *updated = true;
                         parser_state.node_factory[node_id].node_type = NodeType::Boolean(i1 > i2);
@@ -721,66 +1398,43 @@ pub fn evaluate_once(
                 BinaryOpecode::TakeStr => match (child_type1, child_type2) {
                     (NodeType::Integer(i), NodeType::String(s)) => {
                         *updated = true;
-                        // Note: bigint のサイズの take / drop はサポートできない
-                        parser_state.node_factory[node_id].node_type =
-                            NodeType::String(s.take(i.try_into().unwrap()));
+                        parser_state.node_factory[node_id].node_type = NodeType::String(s.take(&i));
                     }
                     _ => {}
                 },
                 BinaryOpecode::DropStr => match (child_type1, child_type2) {
                     (NodeType::Integer(i), NodeType::String(s)) => {
                         *updated = true;
-                        // Note: bigint のサイズの take / drop はサポートできない
-                        parser_state.node_factory[node_id].node_type =
-                            NodeType::String(s.drop(i.try_into().unwrap()));
+                        parser_state.node_factory[node_id].node_type = NodeType::String(s.drop(&i));
                     }
                     _ => {}
                 },
                 BinaryOpecode::Apply => match child_type1 {
                     NodeType::Lambda(var_id, child1_inner) => {
-                        *updated = true;
-                        // Apply の第1項が lambda の時、lambda の中身を substitute して更新するだけではなく、
-                        // Apply を適用した結果 lazy で上書きする必要がある
-                        // この時、既存の node を使いまわしてしまうと、apply した項としない項を区別できなくなってしまうので、
-                        // clone する必要がある
-                        // - apply
-                        //   - child1(lambda)
-                        //     - varX
-                        //     - child1_inner
-                        //   - child2
-                        // --------
-                        // - cloned_child1_inner
-
-                        let cloned_child1_node_id = parser_state.shallow_clone(child1_inner);
-                        let new_var_id = parser_state.node_factory.get_var_id();
-                        let mut local_visited = HashSet::new();
-                        replace_var_id(
-                            cloned_child1_node_id,
-                            var_id,
-                            new_var_id,
-                            parser_state,
-                            &mut local_visited,
-                        );
-                        substitute(cloned_child1_node_id, new_var_id, child2, parser_state);
-                        parser_state.node_factory[node_id].node_type = parser_state.node_factory
-                            [cloned_child1_node_id]
-                            .node_type
-                            .clone();
+                        // 引数が小さく安全な式なら、代入する前に評価しておく
+                        if let Some(prerequisites) = prerequisites {
+                            if !*updated && parser_state.should_reduce_argument(child2) {
+                                prerequisites.push(Prerequisite::Once(child2));
+                                return Ok(());
+                            }
+                        }
+                        if !*updated {
+                            *updated = true;
+                            parser_state.stats.beta_reductions += 1;
+                            beta_reduce(parser_state, node_id, var_id, child1_inner, child2)?;
+                        }
                     }
                     _ => {}
                 },
             }
             if !*updated {
-                evaluate_once(parser_state, child1, updated, depth + 1, debug);
-                if !*updated {
-                    evaluate_once(parser_state, child2, updated, depth + 1, debug);
-                }
+                children.extend([child1, child2]);
             }
         }
         NodeType::If(pred, first, second) => {
-            let pred = extract_node(parser_state, pred, updated);
-            let first = extract_node(parser_state, first, updated);
-            let second = extract_node(parser_state, second, updated);
+            let pred = extract_node(parser_state, pred, updated)?;
+            let first = extract_node(parser_state, first, updated)?;
+            let second = extract_node(parser_state, second, updated)?;
 
             match parser_state.node_factory[pred].node_type {
                 NodeType::Boolean(b) => {
@@ -796,98 +1450,308 @@ pub fn evaluate_once(
                 }
                 _ => {
                     if !*updated {
-                        evaluate_once(parser_state, pred, updated, depth + 1, debug);
-                        if !*updated {
-                            evaluate_once(parser_state, first, updated, depth + 1, debug);
-                            if !*updated {
-                                evaluate_once(parser_state, second, updated, depth + 1, debug);
-                            }
-                        }
+                        children.extend([pred, first, second]);
                     }
                 }
             }
         }
         NodeType::Lambda(_var_id, child) => {
-            let child = extract_node(parser_state, child, updated);
+            let child = extract_node(parser_state, child, updated)?;
             if !*updated {
-                evaluate_once(parser_state, child, updated, depth + 1, debug);
+                children.push(child);
             }
         }
         NodeType::Lazy(lazy_node) => {
-            let lazy_node = extract_node(parser_state, lazy_node, updated);
+            // 同じ中身を指す別の Lazy で値まで縮約済みなら、連鎖を辿らずにその値を使う
+            if let Some(&value_id) = parser_state.lazy_cache.get(&lazy_node) {
+                *updated = true;
+                parser_state.node_factory[node_id].node_type =
+                    parser_state.node_factory[value_id].node_type.clone();
+                return Ok(());
+            }
+            let target = lazy_node;
+            let lazy_node = extract_node(parser_state, lazy_node, updated)?;
 
             // プリミティブ型に縮約された場合は、Lazy ノードを置換する
+            // 中身は他の Lazy からも参照されているので回収しない
             match parser_state.node_factory[lazy_node].node_type {
-                NodeType::Boolean(_)
-                | NodeType::Integer(_)
-                | NodeType::String(_)
-                | NodeType::Variable(_) => {
+                NodeType::Boolean(_) | NodeType::Integer(_) | NodeType::String(_) => {
+                    *updated = true;
+                    parser_state.node_factory[node_id].node_type =
+                        parser_state.node_factory[lazy_node].node_type.clone();
+                    parser_state.lazy_cache.insert(target, lazy_node);
+                    parser_state.lazy_cache.insert(lazy_node, lazy_node);
+                }
+                // 変数は後で substitute されうるのでキャッシュしない
+                NodeType::Variable(_) => {
                     *updated = true;
                     parser_state.node_factory[node_id].node_type =
                         parser_state.node_factory[lazy_node].node_type.clone();
-
-                    // 即値を移したので回収
-                    parser_state.node_factory.discard_node(lazy_node);
                 }
                 _ => {
                     if !*updated {
-                        evaluate_once(parser_state, lazy_node, updated, depth + 1, debug);
+                        children.push(lazy_node);
                     }
                 }
             }
         }
     }
+    Ok(())
+}
+
+// Apply の引数や演算の被演算子をいつ評価するか
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum EvalStrategy {
+    // 引数は評価せずに Lazy として代入する (call-by-need)
+    #[default]
+    Lazy,
+    // 引数が max_size ノード以下で、自由変数も Apply も含まない (= 評価が必ず止まる) 場合は、
+    // 代入する前に値まで評価しておく
+    StrictSmallArgument {
+        max_size: usize,
+    },
+    // 算術演算・比較の被演算子が自由変数も Apply も含まない場合は、演算を適用する前に両方とも値まで評価する
+    // 1 回の evaluate_once で算術式の木をまとめて縮約できる。Apply の引数は Lazy と同じく評価しない
+    Eager,
 }
 
 pub struct ParserState {
     node_factory: NodeFactory,
+    strategy: EvalStrategy,
+    // normal form の部分木を辿らずに済ませるか (比較用に無効化できる)
+    skip_normal_form: bool,
+    // evaluate_once で実際に中身を調べたノード数
+    node_visits: usize,
+    // 値まで縮約された Lazy の中身のノード -> その値を持つノード
+    // 中身は shared なので回収されず、キーが別のノードに使い回されることはない
+    lazy_cache: HashMap<usize, usize>,
+    stats: EvalStats,
+    path_marks: PathMarks,
+    // ノードを書き換える度に変えて、それまでの ClosedSize の memo を使わないようにする
+    closed_epoch: u32,
 }
 
 impl ParserState {
     pub fn new() -> ParserState {
+        ParserState::with_strategy(EvalStrategy::default())
+    }
+
+    pub fn node_factory(&self) -> &NodeFactory {
+        &self.node_factory
+    }
+
+    // evaluate_once で数えた統計 (iterations と max_node_count は parse の loop で数える)
+    pub fn stats(&self) -> EvalStats {
+        self.stats
+    }
+
+    pub fn node_factory_mut(&mut self) -> &mut NodeFactory {
+        &mut self.node_factory
+    }
+
+    pub fn root(&self) -> &Node {
+        &self.node_factory[self.node_factory.root_id]
+    }
+
+    pub fn with_strategy(strategy: EvalStrategy) -> ParserState {
         ParserState {
             node_factory: NodeFactory::new(),
+            strategy,
+            skip_normal_form: true,
+            node_visits: 0,
+            lazy_cache: HashMap::new(),
+            stats: EvalStats::default(),
+            path_marks: PathMarks::default(),
+            closed_epoch: 0,
         }
     }
 
-    // evaluate_once の apply -> lazy -> lambda で複製したい時に使う
-    fn shallow_clone(&mut self, node_id: usize) -> usize {
-        let nt = self.node_factory[node_id].node_type.clone();
-        match nt {
-            NodeType::Boolean(b) => self.node_factory.boolean_node(b),
-            NodeType::Integer(i) => self.node_factory.integer_node(i),
-            NodeType::String(s) => self.node_factory.string_node(s),
-            NodeType::Unary(o, c) => {
-                let child_id = self.shallow_clone(c);
-                self.node_factory.unary_node(o, child_id)
+    // evaluate_once で縮約が起きなかった node_id を normal form とみなせるか
+    // 変数を含まず、子 (Lazy は中身) が全て normal form なら良い
+    fn is_normal_form_candidate(&self, node_id: usize) -> bool {
+        let is_normal = |child: usize| {
+            let mut id = child;
+            for _ in 0..self.node_factory.node_buffer.len() {
+                match self.node_factory[id].node_type {
+                    NodeType::Lazy(inner) => id = inner,
+                    _ => return self.node_factory[id].normal_form,
+                }
             }
-            NodeType::Binary(o, c1, c2) => {
-                let child_node_id1 = self.shallow_clone(c1);
-                let child_node_id2 = self.shallow_clone(c2);
-                self.node_factory
-                    .binary_node(o, child_node_id1, child_node_id2)
+            false
+        };
+        match self.node_factory[node_id].node_type {
+            NodeType::Boolean(_) | NodeType::Integer(_) | NodeType::String(_) => true,
+            NodeType::Variable(_) => false,
+            NodeType::Unary(_, child) | NodeType::Lambda(_, child) | NodeType::Lazy(child) => {
+                is_normal(child)
             }
-            NodeType::If(p, f, s) => {
-                let new_pred = self.shallow_clone(p);
-                let new_first = self.shallow_clone(f);
-                let new_second = self.shallow_clone(s);
-                self.node_factory.if_node(new_pred, new_first, new_second)
+            NodeType::Binary(_, child1, child2) => is_normal(child1) && is_normal(child2),
+            NodeType::If(pred, first, second) => {
+                is_normal(pred) && is_normal(first) && is_normal(second)
             }
-            NodeType::Lambda(v, c) => {
-                let new_v = self.node_factory.get_var_id();
-                let new_child = self.shallow_clone(c);
-                let new_node_id = self.node_factory.lambda_node(v, new_child);
-                let mut visited = HashSet::new();
-                replace_var_id(node_id, v, new_v, self, &mut visited);
-                new_node_id
+        }
+    }
+
+    // Apply の引数 node_id を代入前に評価するべきか
+    fn should_reduce_argument(&mut self, node_id: usize) -> bool {
+        let max_size = match self.strategy {
+            EvalStrategy::Lazy | EvalStrategy::Eager => return false,
+            EvalStrategy::StrictSmallArgument { max_size } => max_size,
+        };
+        self.closed_size(node_id)
+            .is_some_and(|size| size <= max_size && !self.is_value(node_id))
+    }
+
+    // Eager の時、opcode の被演算子 node_id を演算の前に値まで評価するべきか
+    fn should_force_operand(&mut self, opcode: BinaryOpecode, node_id: usize) -> bool {
+        if self.strategy != EvalStrategy::Eager {
+            return false;
+        }
+        let is_arithmetic = matches!(
+            opcode,
+            BinaryOpecode::Add
+                | BinaryOpecode::Sub
+                | BinaryOpecode::Mul
+                | BinaryOpecode::Div
+                | BinaryOpecode::Modulo
+                | BinaryOpecode::LessThan
+                | BinaryOpecode::GreaterThan
+        );
+        is_arithmetic && self.closed_size(node_id).is_some() && !self.is_value(node_id)
+    }
+
+    // 既に値になっているなら評価するものはない
+    fn is_value(&self, node_id: usize) -> bool {
+        matches!(
+            self.node_factory[node_id].node_type,
+            NodeType::Boolean(_)
+                | NodeType::Integer(_)
+                | NodeType::String(_)
+                | NodeType::Lambda(_, _)
+        )
+    }
+
+    // node_id 以下が変数も Apply も含まなければ (評価が必ず止まるなら)、部分木のノード数の上限を返す
+    // 循環している部分木は含むものとして扱う
+    // 各ノードの結果を memo するので、同じ部分木を何度も辿らない
+    fn closed_size(&mut self, node_id: usize) -> Option<usize> {
+        enum Work {
+            Enter(usize),
+            Exit(usize),
+        }
+
+        let epoch = self.closed_epoch;
+        let memo = |node_factory: &NodeFactory, id: usize| match node_factory[id].closed_size {
+            ClosedSize::Unknown => None,
+            ClosedSize::Visiting => Some(None),
+            ClosedSize::Known(e, size) => (e == epoch).then_some(size),
+        };
+
+        let mut stack = vec![Work::Enter(node_id)];
+        while let Some(work) = stack.pop() {
+            match work {
+                Work::Enter(id) => {
+                    if memo(&self.node_factory, id).is_some() {
+                        continue;
+                    }
+                    let node = &mut self.node_factory[id];
+                    match node.node_type {
+                        NodeType::Boolean(_) | NodeType::Integer(_) | NodeType::String(_) => {
+                            node.closed_size = ClosedSize::Known(epoch, Some(1));
+                        }
+                        // 自由変数を含む・停止しないかもしれない式は評価しない
+                        NodeType::Variable(_) | NodeType::Binary(BinaryOpecode::Apply, _, _) => {
+                            node.closed_size = ClosedSize::Known(epoch, None);
+                        }
+                        _ => {
+                            node.closed_size = ClosedSize::Visiting;
+                            stack.push(Work::Exit(id));
+                            stack.extend(children(&node.node_type).into_iter().map(Work::Enter));
+                        }
+                    }
+                }
+                Work::Exit(id) => {
+                    let mut size = Some(1usize);
+                    for child in children(&self.node_factory[id].node_type) {
+                        size = size
+                            .zip(memo(&self.node_factory, child).flatten())
+                            .map(|(size, child_size)| size.saturating_add(child_size));
+                    }
+                    self.node_factory[id].closed_size = ClosedSize::Known(epoch, size);
+                }
             }
-            NodeType::Variable(v) => self.node_factory.variable_node(v),
-            NodeType::Lazy(_n) => {
-                // Lazy は shallow_clone しない
-                // FIXME ほんと？
-                node_id
+        }
+        memo(&self.node_factory, node_id).flatten()
+    }
+
+    // evaluate_once の apply -> lazy -> lambda で複製したい時に使う
+    // 深い木でも native stack を溢れさせないよう、帰りがけ順に明示的なスタックで複製する
+    fn shallow_clone(&mut self, node_id: usize) -> Result<usize, ParseError> {
+        enum Work {
+            Enter(usize),
+            // 子の複製が揃ったら node_id を複製する。Lambda の場合は新しい変数番号も持つ
+            Exit(usize, Option<u32>),
+        }
+
+        // 複製し終えたノード。Exit で子の分を取り出す
+        let mut cloned: Vec<usize> = vec![];
+        let mut stack = vec![Work::Enter(node_id)];
+        while let Some(work) = stack.pop() {
+            match work {
+                Work::Enter(node_id) => {
+                    let nt = self.node_factory[node_id].node_type.clone();
+                    let new_node_id = match nt {
+                        NodeType::Boolean(b) => self.node_factory.boolean_node(b),
+                        NodeType::Integer(i) => self.node_factory.integer_node(i),
+                        NodeType::String(s) => self.node_factory.string_node(s),
+                        NodeType::Variable(v) => self.node_factory.variable_node(v),
+                        NodeType::Lazy(_n) => {
+                            // Lazy は shallow_clone しない
+                            // FIXME ほんと？
+                            node_id
+                        }
+                        NodeType::Lambda(_, _) => {
+                            let new_v = self.node_factory.get_var_id();
+                            stack.push(Work::Exit(node_id, Some(new_v)));
+                            stack.extend(children(&nt).into_iter().rev().map(Work::Enter));
+                            continue;
+                        }
+                        NodeType::Unary(_, _)
+                        | NodeType::Binary(_, _, _)
+                        | NodeType::If(_, _, _) => {
+                            stack.push(Work::Exit(node_id, None));
+                            stack.extend(children(&nt).into_iter().rev().map(Work::Enter));
+                            continue;
+                        }
+                    };
+                    cloned.push(new_node_id);
+                }
+                Work::Exit(node_id, new_v) => {
+                    let nt = self.node_factory[node_id].node_type.clone();
+                    let new_children = cloned.split_off(cloned.len() - children(&nt).len());
+                    let new_node_id = match (nt, new_children.as_slice()) {
+                        (NodeType::Unary(o, _), &[child_id]) => {
+                            self.node_factory.unary_node(o, child_id)
+                        }
+                        (NodeType::Binary(o, _, _), &[child_node_id1, child_node_id2]) => self
+                            .node_factory
+                            .binary_node(o, child_node_id1, child_node_id2),
+                        (NodeType::If(_, _, _), &[new_pred, new_first, new_second]) => {
+                            self.node_factory.if_node(new_pred, new_first, new_second)
+                        }
+                        (NodeType::Lambda(v, _), &[new_child]) => {
+                            let new_node_id = self.node_factory.lambda_node(v, new_child);
+                            let mut visited = HashSet::new();
+                            replace_var_id(node_id, v, new_v.unwrap(), self, &mut visited)?;
+                            new_node_id
+                        }
+                        _ => unreachable!("children count does not match node type"),
+                    };
+                    cloned.push(new_node_id);
+                }
             }
         }
+        Ok(cloned.pop().unwrap())
     }
 }
 
@@ -945,13 +1809,39 @@ mod tests {
     }
 
     #[test]
-    fn test_gt() {
+    fn test_division_by_zero() {
+        for program in ["B/ I\" I!", "B% I\" I!"] {
+            let result = parse(program.to_string());
+            assert!(
+                matches!(result, Err(ParseError::DivisionByZero(_))),
+                "program: {}",
+                program
+            );
+        }
+        // 0 除算は root ではなく、中の部分木で起きる
+        let mut parser_state = ParserState::new();
+        let mut token_stream = tokenizer::tokenize_iter("B+ I\" B/ I\" I!");
+        let root_id = construct_node(&mut parser_state, &mut token_stream).unwrap();
+        let mut updated = false;
+        let result = evaluate_once(&mut parser_state, root_id, &mut updated, 0, false);
+        let Err(ParseError::DivisionByZero(node_id)) = result else {
+            panic!("unexpected result: {:?}", result);
+        };
+        assert_ne!(node_id, root_id);
+        assert!(matches!(
+            parser_state.node_factory[node_id].node_type,
+            NodeType::Binary(BinaryOpecode::Div, _, _)
+        ));
+    }
+
+    #[test]
+    fn test_less_than() {
         test_sequence("B< I$ I#", NodeType::Boolean(false));
         test_sequence("B< I# I$", NodeType::Boolean(true));
     }
 
     #[test]
-    fn test_lt() {
+    fn test_greater_than() {
         test_sequence("B> I$ I#", NodeType::Boolean(true));
         test_sequence("B> I# I$", NodeType::Boolean(false));
     }
@@ -1032,6 +1922,353 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_strict_small_argument() {
+        // rec n acc = if n == 0 then acc else rec (n - 1) (acc * 2 + 1) を n = 50 で呼ぶ
+        // Lazy だと acc が評価されないまま (((0 * 2 + 1) * 2 + 1) ...) と 50 段積み重なり、
+        // 最後にまとめて評価する時に深く潜る。StrictSmallArgument は毎回先に値にするので浅いまま
+        let y_combinator = r#"B$ L" B$ L# B$ v" B$ v# v# L# B$ v" B$ v# v#"#;
+        let rec = r#"L$ L% L& ? B= v% I! v& B$ B$ v$ B- v% I" B+ B* v& I# I""#;
+        let input = format!("B$ B$ {} {} IS I!", y_combinator, rec);
+        let strategy_list = [
+            EvalStrategy::Lazy,
+            EvalStrategy::StrictSmallArgument { max_size: 16 },
+        ];
+        let mut stats_list = vec![];
+        for strategy in strategy_list {
+            let config = ParseConfig {
+                strategy,
+                ..ParseConfig::default()
+            };
+            let output = parse_with_config(input.clone(), config).unwrap();
+            assert_eq!(
+                output.node.node_type,
+                NodeType::Integer(BigInt::from((1u64 << 50) - 1))
+            );
+            stats_list.push(output.stats);
+        }
+
+        let (lazy, strict) = (&stats_list[0], &stats_list[1]);
+        assert!(strict.iterations <= lazy.iterations);
+        assert!(strict.max_node_count < lazy.max_node_count);
+        assert!(
+            strict.peak_depth * 10 <= lazy.peak_depth,
+            "strict: {}, lazy: {}",
+            strict.peak_depth,
+            lazy.peak_depth
+        );
+    }
+
+    #[test]
+    fn test_eager_arithmetic() {
+        // ((1 + 2) * (3 - 4)) + ((5 * 6) / (7 % 8)) のような算術式の木
+        let input = "B+ B* B+ I\" I# B- I$ I% B/ B* I& I' B% I( I)";
+        let expected = NodeType::Integer(BigInt::from(-3 + 30 / 7));
+
+        let mut iterations = vec![];
+        for strategy in [EvalStrategy::Lazy, EvalStrategy::Eager] {
+            let config = ParseConfig {
+                strategy,
+                ..ParseConfig::default()
+            };
+            let output = parse_with_config(input.to_string(), config).unwrap();
+            assert_eq!(output.node.node_type, expected);
+            iterations.push(output.stats.iterations);
+        }
+        assert!(iterations[1] < iterations[0], "{:?}", iterations);
+        assert_eq!(iterations[1], 1);
+    }
+
+    #[test]
+    fn test_eager_deep_chain() {
+        // (((0 + 1) + 1) + ... ) + 1 と 100k 段左に深い算術式
+        // 被演算子を先に評価する時も native stack を溢れさせず、閉じた項かどうかの判定で部分木を毎回辿り直すこともない
+        // 被演算子は全て先に評価されるので、1 iteration で値になる
+        let depth = 100_000;
+        let input = "B+ ".repeat(depth) + "I!" + &" I\"".repeat(depth);
+        let config = ParseConfig {
+            strategy: EvalStrategy::Eager,
+            ..ParseConfig::default()
+        };
+        let output = parse_with_config(input, config).unwrap();
+        assert_eq!(
+            output.node.node_type,
+            NodeType::Integer(BigInt::from(depth))
+        );
+        assert_eq!(output.stats.iterations, 1);
+    }
+
+    #[test]
+    fn test_eager_keeps_apply_lazy() {
+        // Apply を含む被演算子は先に評価しない
+        let input = "B+ B$ L# B* v# v# I$ B- I% I\"";
+        let result = parse_with_strategy(input.to_string(), EvalStrategy::Eager).unwrap();
+        assert_eq!(result.node_type, NodeType::Integer(BigInt::from(9 + 3)));
+    }
+
+    #[test]
+    fn test_strict_small_argument_skip_unsafe() {
+        // Apply を含む引数は評価せずに代入する
+        let input = "B$ L# B+ v# v# B$ L# B* v# v# I$";
+        let result = parse_with_strategy(
+            input.to_string(),
+            EvalStrategy::StrictSmallArgument { max_size: 16 },
+        )
+        .unwrap();
+        assert_eq!(result.node_type, NodeType::Integer(BigInt::from(18)));
+    }
+
+    #[test]
+    fn test_evaluate_with_input() {
+        // 入力の前に "hello " を付ける
+        let program = "L# B. S(%,,/} v#";
+        let input = ICFPString::from_encoded_str("world").unwrap();
+        let result = evaluate_with_input(program.to_string(), input).unwrap();
+        assert_eq!(
+            result,
+            Value::String(ICFPString::from_encoded_str("hello world").unwrap())
+        );
+
+        // 関数のまま残る場合は値にならない
+        let input = ICFPString::from_encoded_str("x").unwrap();
+        assert!(matches!(
+            evaluate_with_input("L# L$ v#".to_string(), input),
+            Err(ParseError::NotAValue)
+        ));
+    }
+
+    #[test]
+    fn test_evaluate_to_value() {
+        assert_eq!(
+            evaluate_to_value("B+ I# B* I$ I%".to_string(), ParseConfig::default()).unwrap(),
+            Value::Integer(BigInt::from(14))
+        );
+        assert_eq!(
+            evaluate_to_value("B< I$ I%".to_string(), ParseConfig::default()).unwrap(),
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            evaluate_to_value("B. SB%,,/ S}Q/2,$_".to_string(), ParseConfig::default()).unwrap(),
+            Value::String(ICFPString::from_encoded_str("Hello World!").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_evaluate_to_value_not_a_value() {
+        assert!(matches!(
+            evaluate_to_value("L# v#".to_string(), ParseConfig::default()),
+            Err(ParseError::NotAValue)
+        ));
+        // 引数が足りない部分適用も関数のまま残る
+        assert!(matches!(
+            evaluate_to_value("B$ L# L$ v# I#".to_string(), ParseConfig::default()),
+            Err(ParseError::NotAValue)
+        ));
+    }
+
+    #[test]
+    fn test_evaluate_to_value_max_iterations() {
+        let config = ParseConfig {
+            max_iterations: 1,
+            ..ParseConfig::default()
+        };
+        assert!(matches!(
+            evaluate_to_value("B+ I# B* I$ I%".to_string(), config),
+            Err(ParseError::EvaluationLimitExceeded(1))
+        ));
+    }
+
+    #[test]
+    fn test_value_display() {
+        assert_eq!(Value::Boolean(false).to_string(), "false");
+        assert_eq!(Value::Integer(BigInt::from(-3)).to_string(), "-3");
+        assert_eq!(
+            Value::String(ICFPString::from_encoded_str("hi").unwrap()).to_string(),
+            "hi"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_value_serde_roundtrip() {
+        let value_list = vec![
+            Value::Boolean(true),
+            Value::Integer(BigInt::from(-1234567890123456789i64) * 1000),
+            Value::String(ICFPString::from_encoded_str("solve lambdaman1 RRDD").unwrap()),
+        ];
+        for value in value_list {
+            let json = serde_json::to_string(&value).unwrap();
+            let restored: Value = serde_json::from_str(&json).unwrap();
+            assert_eq!(value, restored);
+        }
+    }
+
+    #[test]
+    fn test_construct_node_streaming() {
+        // 深さのある大きめのプログラム
+        let mut program = String::new();
+        for i in 0..2000 {
+            program.push_str(&format!(
+                "B+ I{} ",
+                ICFPString::from_int(BigInt::from(i))
+                    .to_string()
+                    .unwrap()
+                    .iter()
+                    .collect::<String>()
+            ));
+        }
+        program.push_str("S'%4}");
+
+        let node_types = |parser_state: &ParserState| {
+            parser_state
+                .node_factory
+                .node_buffer
+                .iter()
+                .map(|node| node.node_type.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let mut eager_state = ParserState::new();
+        let token_list = tokenizer::tokenize(program.clone()).unwrap();
+        let eager_root =
+            construct_node(&mut eager_state, &mut token_list.into_iter().map(Ok)).unwrap();
+
+        let mut streaming_state = ParserState::new();
+        let streaming_root = construct_node(
+            &mut streaming_state,
+            &mut tokenizer::tokenize_iter(&program),
+        )
+        .unwrap();
+
+        assert_eq!(eager_root, streaming_root);
+        assert_eq!(node_types(&eager_state), node_types(&streaming_state));
+        assert_eq!(eager_state.node_factory.node_buffer.len(), 2000 * 2 + 1);
+    }
+
+    #[test]
+    fn test_construct_node_stream_error() {
+        let mut parser_state = ParserState::new();
+        assert_eq!(
+            construct_node(&mut parser_state, &mut tokenizer::tokenize_iter("B+ I\"")),
+            Err(ParseError::CannotFindNextToken)
+        );
+        assert_eq!(
+            construct_node(&mut parser_state, &mut tokenizer::tokenize_iter("B+ I\" X")),
+            Err(ParseError::invalid_token(2, 6, "X"))
+        );
+    }
+
+    #[test]
+    fn test_extract_node_self_cycle() {
+        let mut parser_state = ParserState::new();
+        // 自分自身を指す Lazy
+        let node_id = parser_state.node_factory.lazy_node(0);
+        let mut updated = false;
+        let result = extract_node(&mut parser_state, node_id, &mut updated);
+        assert!(matches!(result, Err(ParseError::CyclicReference(_))));
+    }
+
+    #[test]
+    fn test_extract_node_cycle() {
+        let mut parser_state = ParserState::new();
+        // 0 -> 1 -> 2 -> 1 と循環する Lazy の連鎖
+        let node0 = parser_state.node_factory.boolean_node(true);
+        let node1 = parser_state.node_factory.boolean_node(true);
+        let node2 = parser_state.node_factory.boolean_node(true);
+        parser_state.node_factory.set_lazy(node0, node1);
+        parser_state.node_factory.set_lazy(node1, node2);
+        parser_state.node_factory.set_lazy(node2, node1);
+        let mut updated = false;
+        let result = extract_node(&mut parser_state, node0, &mut updated);
+        assert!(matches!(result, Err(ParseError::CyclicReference(_))));
+
+        let mut updated = false;
+        let result = evaluate_once(&mut parser_state, node0, &mut updated, 0, false);
+        assert!(matches!(result, Err(ParseError::CyclicReference(_))));
+    }
+
+    // v! -> Lazy -> B+ v! v! と、変数から Lazy を経由して親に戻ってくる循環
+    fn build_cycle_through_lazy(parser_state: &mut ParserState) -> (usize, usize) {
+        let factory = &mut parser_state.node_factory;
+        let var = factory.variable_node(0);
+        let other = factory.variable_node(0);
+        let root = factory.binary_node(BinaryOpecode::Add, var, other);
+        let lazy = factory.lazy_node(root);
+        factory.set_lazy(var, lazy);
+        (root, lazy)
+    }
+
+    #[test]
+    fn test_evaluate_once_cycle() {
+        let mut parser_state = ParserState::new();
+        let (root, _) = build_cycle_through_lazy(&mut parser_state);
+        let mut updated = false;
+        let result = evaluate_once(&mut parser_state, root, &mut updated, 0, false);
+        assert_eq!(result, Err(ParseError::CyclicReference(root)));
+    }
+
+    #[test]
+    fn test_alpha_convert_cycle() {
+        let mut parser_state = ParserState::new();
+        // Unary が自分自身を子に持つ、Lazy を経由しない循環
+        let node = parser_state.node_factory.boolean_node(true);
+        parser_state.node_factory[node].node_type = NodeType::Unary(UnaryOpecode::Not, node);
+        let result = alpha_convert(node, &mut parser_state, &mut HashSet::new());
+        assert_eq!(result, Err(ParseError::CyclicReference(node)));
+    }
+
+    #[test]
+    fn test_substitute_cycle() {
+        let mut parser_state = ParserState::new();
+        let node = parser_state.node_factory.boolean_node(true);
+        parser_state.node_factory[node].node_type = NodeType::Lambda(1, node);
+        let arg = parser_state.node_factory.integer_node(BigInt::from(1));
+        let result = substitute(node, 0, arg, &mut parser_state);
+        assert_eq!(result, Err(ParseError::CyclicReference(node)));
+    }
+
+    #[test]
+    fn test_shared_child_is_not_cycle() {
+        let mut parser_state = ParserState::new();
+        // 循環で失敗した後も、印が残って次の走査を邪魔しない
+        let (root, _) = build_cycle_through_lazy(&mut parser_state);
+        let mut updated = false;
+        let result = evaluate_once(&mut parser_state, root, &mut updated, 0, false);
+        assert!(matches!(result, Err(ParseError::CyclicReference(_))));
+
+        // 同じ子を 2 回参照するのは循環ではない
+        let factory = &mut parser_state.node_factory;
+        let one = factory.integer_node(BigInt::from(1));
+        let sum = factory.binary_node(BinaryOpecode::Add, one, one);
+        let body = factory.binary_node(BinaryOpecode::Mul, sum, sum);
+        let lambda = factory.lambda_node(0, body);
+        alpha_convert(lambda, &mut parser_state, &mut HashSet::new()).unwrap();
+
+        let mut updated = false;
+        evaluate_once(&mut parser_state, body, &mut updated, 0, false).unwrap();
+        assert!(updated);
+        assert_eq!(
+            parser_state.node_factory[sum].node_type,
+            NodeType::Integer(BigInt::from(2))
+        );
+    }
+
+    #[test]
+    fn test_extract_node_chain() {
+        let mut parser_state = ParserState::new();
+        let value = parser_state.node_factory.integer_node(BigInt::from(3));
+        let lazy1 = parser_state.node_factory.lazy_node(value);
+        let lazy2 = parser_state.node_factory.lazy_node(lazy1);
+        let mut updated = false;
+        let result = extract_node(&mut parser_state, lazy2, &mut updated).unwrap();
+        assert_eq!(result, value);
+        // 連鎖は中身を直接指すように張り替えられる
+        assert_eq!(
+            parser_state.node_factory[lazy2].node_type,
+            NodeType::Lazy(value)
+        );
+    }
+
     #[test]
     fn test_lambda_apply4() {
         test_sequence(
@@ -1039,4 +2276,385 @@ mod tests {
                     NodeType::Integer(BigInt::from(16)),
                 )
     }
+
+    // 束縛された変数だけを使うランダムなプログラムを作る
+    fn random_program(rng: &mut impl rand::Rng, depth: usize, bound: &mut Vec<u32>) -> String {
+        let leaf = depth == 0 || rng.gen_ratio(1, 4);
+        if leaf {
+            return match rng.gen_range(0..4) {
+                0 => if rng.gen() { "T" } else { "F" }.to_string(),
+                1 => format!("I{}", (b'!' + rng.gen_range(0..94)) as char),
+                2 if !bound.is_empty() => {
+                    let v = bound[rng.gen_range(0..bound.len())];
+                    format!("v{}", (b'!' + v as u8) as char)
+                }
+                _ => "SB%,,/}Q/2,$_".to_string(),
+            };
+        }
+        match rng.gen_range(0..4) {
+            0 => {
+                let opcode = ["U-", "U!", "U#", "U$"][rng.gen_range(0..4)];
+                format!("{} {}", opcode, random_program(rng, depth - 1, bound))
+            }
+            1 => {
+                let opcode = ["B+", "B*", "B<", "B=", "B.", "BT", "B$"][rng.gen_range(0..7)];
+                let child1 = random_program(rng, depth - 1, bound);
+                let child2 = random_program(rng, depth - 1, bound);
+                format!("{} {} {}", opcode, child1, child2)
+            }
+            2 => {
+                let pred = random_program(rng, depth - 1, bound);
+                let first = random_program(rng, depth - 1, bound);
+                let second = random_program(rng, depth - 1, bound);
+                format!("? {} {} {}", pred, first, second)
+            }
+            _ => {
+                let v = rng.gen_range(0..10);
+                bound.push(v);
+                let body = random_program(rng, depth - 1, bound);
+                bound.pop();
+                format!("L{} {}", (b'!' + v as u8) as char, body)
+            }
+        }
+    }
+
+    fn round_trip(program: &str) -> String {
+        let parser_state = construct_program(program, EvalStrategy::default()).unwrap();
+        let root_id = parser_state.node_factory.root_id;
+        to_program_string(&parser_state, root_id).unwrap()
+    }
+
+    #[test]
+    fn test_to_program_string_round_trip() {
+        use rand::SeedableRng;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        for _ in 0..200 {
+            let program = random_program(&mut rng, 6, &mut vec![]);
+            // 1 回目で変数名が alpha_convert 後のものに揃い、以降は変わらない
+            let once = round_trip(&program);
+            assert_eq!(round_trip(&once), once, "program: {}", program);
+        }
+    }
+
+    #[test]
+    fn test_large_integer_round_trip() {
+        let program = format!("I{}", "~".repeat(30));
+        assert_eq!(round_trip(&program), program);
+        assert_eq!(
+            evaluate_to_value(format!("B+ {} I\"", program), ParseConfig::default()).unwrap(),
+            Value::Integer(BigInt::from(94).pow(30))
+        );
+    }
+
+    #[test]
+    fn test_to_program_string_after_evaluation() {
+        // 縮約途中の (Lazy や負の数を含む) 状態を書き出しても、同じ値に評価される
+        let program = "B$ L# B+ U- v# v# B* I$ I%";
+        let mut parser_state = construct_program(program, EvalStrategy::default()).unwrap();
+        let root_id = parser_state.node_factory.root_id;
+        for _ in 0..3 {
+            let mut updated = false;
+            evaluate_once(&mut parser_state, root_id, &mut updated, 0, false).unwrap();
+            let emitted = to_program_string(&parser_state, root_id).unwrap();
+            assert_eq!(
+                evaluate_to_value(emitted, ParseConfig::default()).unwrap(),
+                Value::Integer(BigInt::ZERO)
+            );
+        }
+        assert_eq!(
+            to_program_string(&parser_state, root_id),
+            Ok("B+ U- I- I-".to_string())
+        );
+    }
+
+    #[test]
+    fn test_to_program_string_cycle() {
+        let mut parser_state = ParserState::new();
+        let factory = &mut parser_state.node_factory;
+        let lazy = factory.boolean_node(true);
+        let root = factory.unary_node(UnaryOpecode::Not, lazy);
+        factory.set_lazy(lazy, root);
+        assert_eq!(
+            to_program_string(&parser_state, root),
+            Err(ParseError::CyclicReference(root))
+        );
+    }
+
+    #[test]
+    fn test_evaluate_once_deep_chain() {
+        // B+ v! (B+ v! (... (B+ I" I"))) のように、縮約できる箇所が一番深い所にしか無い 100k 段の鎖
+        // 自由変数を含むので毎回根から辿り直すことになる
+        let depth = 100_000;
+        let mut parser_state = ParserState::new();
+        let factory = &mut parser_state.node_factory;
+        let one1 = factory.integer_node(BigInt::from(1));
+        let one2 = factory.integer_node(BigInt::from(1));
+        let bottom = factory.binary_node(BinaryOpecode::Add, one1, one2);
+        let mut root_id = bottom;
+        for _ in 0..depth {
+            let var = factory.variable_node(0);
+            root_id = factory.binary_node(BinaryOpecode::Add, var, root_id);
+        }
+
+        let mut updated = false;
+        evaluate_once(&mut parser_state, root_id, &mut updated, 0, false).unwrap();
+        assert!(updated);
+        assert_eq!(
+            parser_state.node_factory[bottom].node_type,
+            NodeType::Integer(BigInt::from(2))
+        );
+
+        let mut updated = false;
+        evaluate_once(&mut parser_state, root_id, &mut updated, 0, false).unwrap();
+        assert!(!updated);
+    }
+
+    #[test]
+    fn test_parse_deep_chain() {
+        // B$ L# v# (B$ L# v# (... I!)) のように 100k 段ネストした式
+        // 構文木の構築・alpha 変換・代入が native stack を溢れさせない
+        let depth = 100_000;
+        let input = "B$ L# v# ".repeat(depth) + "I!";
+        let node = parse(input).unwrap();
+        assert_eq!(node.node_type, NodeType::Integer(BigInt::from(0)));
+    }
+
+    #[test]
+    fn test_shallow_clone_deep_chain() {
+        // L! B+ v! (B+ v! (... I!)) を複製しても native stack を溢れさせない
+        let depth = 100_000;
+        let mut parser_state = ParserState::new();
+        let factory = &mut parser_state.node_factory;
+        let mut body = factory.integer_node(BigInt::from(0));
+        for _ in 0..depth {
+            let var = factory.variable_node(0);
+            body = factory.binary_node(BinaryOpecode::Add, var, body);
+        }
+        let root_id = factory.lambda_node(0, body);
+
+        let cloned = parser_state.shallow_clone(root_id).unwrap();
+        assert_ne!(cloned, root_id);
+        assert!(matches!(
+            parser_state.node_factory[cloned].node_type,
+            NodeType::Lambda(0, child) if child != body
+        ));
+    }
+
+    #[test]
+    fn test_node_evaluate_once() {
+        let mut parser_state =
+            construct_program("B+ I# B* I$ I%", EvalStrategy::default()).unwrap();
+        let mut node = parser_state.root().clone();
+        let factory = parser_state.node_factory_mut();
+
+        let mut steps = 0;
+        loop {
+            let new_node = node.clone().evaluate_once(factory).unwrap();
+            if new_node == node {
+                break;
+            }
+            node = new_node;
+            steps += 1;
+        }
+        // B* I$ I% と B+ I# I, の 2 回
+        assert_eq!(steps, 2);
+        assert_eq!(node.node_type, NodeType::Integer(BigInt::from(14)));
+    }
+
+    #[test]
+    fn test_node_evaluate_once_subterm() {
+        let mut parser_state =
+            construct_program("B+ I# B* I$ I%", EvalStrategy::default()).unwrap();
+        let NodeType::Binary(_, _, child) = parser_state.root().node_type else {
+            panic!("root is not a binary node");
+        };
+        let subterm = parser_state.node_factory()[child].clone();
+
+        // 親から参照されている部分木は 1 step 進められない
+        let result = subterm.evaluate_once(parser_state.node_factory_mut());
+        assert_eq!(result, Err(ParseError::NotRootNode(child)));
+
+        // 木は壊れていないので、プログラム全体はそのまま評価できる
+        let factory = parser_state.node_factory_mut();
+        let mut node = factory[factory.root_id].clone();
+        loop {
+            let new_node = node.clone().evaluate_once(factory).unwrap();
+            if new_node == node {
+                break;
+            }
+            node = new_node;
+        }
+        assert_eq!(node.node_type, NodeType::Integer(BigInt::from(14)));
+    }
+
+    #[test]
+    fn test_to_dot_string() {
+        let parser_state = construct_program("B+ I# I$", EvalStrategy::default()).unwrap();
+        let root = parser_state.root();
+        assert_eq!(root.len(parser_state.node_factory()), 3);
+        assert_eq!(
+            root.to_dot_string(parser_state.node_factory()),
+            "digraph ast {\n    n2 [label=\"Binary(Add)\"];\n    n2 -> n0;\n    n2 -> n1;\n    n0 [label=\"Integer(2)\"];\n    n1 [label=\"Integer(3)\"];\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_to_dot_string_lazy() {
+        // Lazy の参照先は点線で、循環していても 1 回ずつしか出力しない
+        let mut parser_state = ParserState::new();
+        let factory = &mut parser_state.node_factory;
+        let lazy = factory.boolean_node(true);
+        let root = factory.unary_node(UnaryOpecode::Not, lazy);
+        factory.set_lazy(lazy, root);
+
+        let root = &parser_state.node_factory[root];
+        assert_eq!(root.len(&parser_state.node_factory), 2);
+        let dot = root.to_dot_string(&parser_state.node_factory);
+        assert!(dot.contains("n0 -> n1 [style=dashed];"));
+        assert_eq!(dot.matches("[label=").count(), 2);
+    }
+
+    #[test]
+    fn test_print_node_truncated() {
+        let mut parser_state = ParserState::new();
+        let program = format!("{}I!", "U- ".repeat(200));
+        let mut token_stream = tokenizer::tokenize_iter(&program);
+        let root_id = construct_node(&mut parser_state, &mut token_stream).unwrap();
+        parser_state.node_factory.root_id = root_id;
+
+        let node_lines = |max_nodes| {
+            let mut buffer = vec![];
+            write_node(&mut buffer, &parser_state, max_nodes).unwrap();
+            let output = String::from_utf8(buffer).unwrap();
+            // 空行・"cache: "・"-----" 以外がノードの行
+            output
+                .lines()
+                .filter(|line| !line.is_empty() && *line != "cache: " && *line != "-----")
+                .map(|line| line.trim().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let truncated = node_lines(Some(50));
+        assert_eq!(truncated.len(), 51);
+        assert_eq!(truncated.last().unwrap(), "...");
+
+        let full = node_lines(None);
+        assert_eq!(full.len(), 201);
+        assert_eq!(full.last().unwrap(), "Integer(0)");
+    }
+
+    #[test]
+    fn test_skip_normal_form() {
+        // 縮約できない (And に文字列を渡している) 大きな部分木
+        fn static_tree(depth: usize) -> String {
+            if depth == 0 {
+                "S!".to_string()
+            } else {
+                format!("B& {} {}", static_tree(depth - 1), static_tree(depth - 1))
+            }
+        }
+        // 1 sweep に 1 つずつ縮約される部分
+        let program = format!("B= {} {}I\"", static_tree(8), "U- ".repeat(30));
+
+        let reduce = |skip_normal_form: bool| {
+            let mut parser_state = ParserState::new();
+            parser_state.skip_normal_form = skip_normal_form;
+            let mut token_stream = tokenizer::tokenize_iter(&program);
+            let root_id = construct_node(&mut parser_state, &mut token_stream).unwrap();
+            parser_state.node_factory.root_id = root_id;
+            loop {
+                let mut updated = false;
+                evaluate_once(&mut parser_state, root_id, &mut updated, 0, false).unwrap();
+                if !updated {
+                    break;
+                }
+            }
+            let node_type = parser_state.node_factory[root_id].node_type.clone();
+            let NodeType::Binary(_, _, suffix) = node_type else {
+                panic!("unexpected result: {:?}", node_type);
+            };
+            (
+                parser_state.node_factory[suffix].node_type.clone(),
+                parser_state.node_visits,
+            )
+        };
+
+        let (with_skip, visits_with_skip) = reduce(true);
+        let (without_skip, visits_without_skip) = reduce(false);
+        assert_eq!(with_skip, NodeType::Integer(BigInt::from(1)));
+        assert_eq!(with_skip, without_skip);
+        assert!(visits_with_skip * 5 < visits_without_skip);
+    }
+
+    #[test]
+    fn test_parse_with_config_max_iterations() {
+        let program = "U- U- U- I\"".to_string();
+        let output = parse_with_config(program.clone(), ParseConfig::default()).unwrap();
+        assert_eq!(output.node.node_type, NodeType::Integer(BigInt::from(-1)));
+        assert_eq!(output.stats.iterations, 3);
+
+        // ちょうど必要な回数なら足りる
+        let config = ParseConfig {
+            max_iterations: 3,
+            ..ParseConfig::default()
+        };
+        assert!(parse_with_config(program.clone(), config).is_ok());
+
+        let config = ParseConfig {
+            max_iterations: 2,
+            ..ParseConfig::default()
+        };
+        let result = parse_with_config(program, config);
+        assert_eq!(result.unwrap_err(), ParseError::EvaluationLimitExceeded(2));
+    }
+
+    #[test]
+    fn test_parse_with_config_stats() {
+        // ((\x. \y. x + y) 1) 2
+        let program = "B$ B$ L# L$ B+ v# v$ I\" I#".to_string();
+        let output = parse_with_config(program, ParseConfig::default()).unwrap();
+        assert_eq!(output.node.node_type, NodeType::Integer(BigInt::from(3)));
+        assert_eq!(output.stats.beta_reductions, 2);
+        assert!(output.stats.iterations >= 2);
+        assert!(output.stats.max_node_count >= 8);
+        assert!(output.stats.peak_depth >= 1);
+    }
+
+    #[test]
+    fn test_evaluation_limit_exceeded() {
+        // 停止しない再帰 (B$ L# B$ v# v# L# B$ v# v#)
+        let program = "B$ L# B$ v# v# L# B$ v# v#".to_string();
+        let config = ParseConfig {
+            max_iterations: 1000,
+            ..ParseConfig::default()
+        };
+        let result = parse_with_config(program, config);
+        assert_eq!(
+            result.unwrap_err(),
+            ParseError::EvaluationLimitExceeded(1000)
+        );
+    }
+
+    #[test]
+    fn test_shared_lazy_argument() {
+        // v# は全て同じ Lazy の中身を指すので、途中の縮約で中身を書き換えてはいけない
+        // I' は 6 なので 6 + (6 + 6 * 6) = 48
+        test_sequence(
+            "B$ L# B+ v# B+ v# B* v# v# I'",
+            NodeType::Integer(BigInt::from(48)),
+        );
+    }
+
+    #[test]
+    fn test_lazy_node_count_bounded() {
+        let program = "B$ B$ L\" B$ L# B$ v\" B$ v# v# L# B$ v\" B$ v# v# L\" L# ? B= v# I! I\" B$ L$ B+ B$ v\" v$ B$ v\" v$ B- v# I\" I%";
+        let mut max_node_len = 0;
+        let result = parse_with_callback(program.to_string(), |_, node_len| {
+            max_node_len = max_node_len.max(node_len);
+        })
+        .unwrap();
+        assert_eq!(result.node_type, NodeType::Integer(BigInt::from(16)));
+        assert!(max_node_len < 1000, "node_len: {}", max_node_len);
+    }
 }
@@ -0,0 +1,158 @@
+// 評価すると与えた値になるプログラムを、なるべく短く書くための部品
+
+use num_bigint::BigInt;
+
+use crate::parser::{icfpstring::ICFPString, ParseError};
+
+// 非負整数を I... のトークンにする
+pub fn int_token(v: impl Into<BigInt>) -> Result<String, ParseError> {
+    let digits = ICFPString::from_int(v.into()).to_string()?;
+    Ok(format!("I{}", digits.into_iter().collect::<String>()))
+}
+
+// 文字列を S... のトークンにする
+pub fn string_token(s: &ICFPString) -> Result<String, ParseError> {
+    Ok(format!(
+        "S{}",
+        s.to_string()?.into_iter().collect::<String>()
+    ))
+}
+
+// repeat n s = if n == 1 then s else s . repeat (n - 1) s を Y コンビネータで書いたもの
+// n >= 1 で使う (空文字列を書かずに済むように、n == 1 で止める)
+pub const REPEAT_FUNCTION: &str = concat!(
+    r#"B$ L" B$ L# B$ v" B$ v# v# L# B$ v" B$ v# v# "#,
+    r#"L$ L% L& ? B= v% I" v& B. v& B$ B$ v$ B- v% I" v&"#
+);
+
+// 繰り返しを探す塊の最大の長さ
+const MAX_CHUNK_LEN: usize = 16;
+
+/// 評価すると s になるプログラムを返す
+/// 先頭から順に、同じ塊が続く部分を REPEAT_FUNCTION での繰り返しに置き換え、それ以外はそのまま S... で書く
+/// 繰り返す部分が無ければ S... をそのまま返す
+/// REPEAT_FUNCTION の分だけ S... より長くなることもある (短い方が欲しければ compress_string)
+pub fn repeat_program(s: &ICFPString) -> Result<String, ParseError> {
+    let chars = s.iter().copied().collect::<Vec<_>>();
+
+    // "B$ B$ v! I<count> S<chunk>" の長さ
+    // 各 piece は B. で連結するので、その分も繰り返しのコストに含める
+    let repeat_cost = |chunk_len: usize, count: usize| {
+        "B. B$ B$ v! I S".len() + ICFPString::from_int(BigInt::from(count)).len() + chunk_len
+    };
+
+    let mut pieces = vec![];
+    let mut literal_start = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        // (削れる文字数, 塊の長さ, 繰り返す回数)
+        let mut best = (0, 0, 0);
+        for m in 1..=MAX_CHUNK_LEN.min(chars.len() - i) {
+            let chunk = &chars[i..i + m];
+            let mut count = 1;
+            while chars[i + m * count..].starts_with(chunk) {
+                count += 1;
+            }
+            let saved = (m * count).saturating_sub(repeat_cost(m, count));
+            if saved > best.0 {
+                best = (saved, m, count);
+            }
+        }
+
+        let (saved, m, count) = best;
+        if saved == 0 {
+            i += 1;
+            continue;
+        }
+        if literal_start < i {
+            pieces.push(string_token(
+                &s.take(&BigInt::from(i)).drop(&BigInt::from(literal_start)),
+            )?);
+        }
+        let chunk = s.drop(&BigInt::from(i)).take(&BigInt::from(m));
+        pieces.push(format!(
+            "B$ B$ v! {} {}",
+            int_token(count)?,
+            string_token(&chunk)?
+        ));
+        i += m * count;
+        literal_start = i;
+    }
+    if literal_start < chars.len() {
+        pieces.push(string_token(&s.drop(&BigInt::from(literal_start)))?);
+    }
+    // 繰り返しを使わないなら S... と同じ
+    if !pieces.iter().any(|piece| piece.starts_with("B$")) {
+        return string_token(s);
+    }
+
+    // p1 . (p2 . (... . pn))
+    let last = pieces.pop().unwrap();
+    let body = pieces
+        .iter()
+        .rev()
+        .fold(last, |acc, piece| format!("B. {} {}", piece, acc));
+    Ok(format!("B$ L! {} {}", body, REPEAT_FUNCTION))
+}
+
+/// repeat_program とそのまま S... で書いたものの短い方を返す
+pub fn compress_string(s: &ICFPString) -> Result<String, ParseError> {
+    let raw = string_token(s)?;
+    let program = repeat_program(s)?;
+
+    Ok(if program.len() < raw.len() {
+        program
+    } else {
+        raw
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ast::{parse, NodeType};
+
+    fn evaluate_string(program: String) -> String {
+        match parse(program.clone()).unwrap().node_type {
+            NodeType::String(s) => format!("{}", s),
+            node_type => panic!("{} is evaluated to {:?}", program, node_type),
+        }
+    }
+
+    #[test]
+    fn test_int_token() {
+        assert_eq!(int_token(0).unwrap(), "I!");
+        assert_eq!(int_token(1337).unwrap(), "I/6");
+        assert_eq!(int_token(94).unwrap(), "I\"!");
+    }
+
+    #[test]
+    fn test_compress_string() {
+        let s = ICFPString::from_encoded_str(&"R".repeat(1000)).unwrap();
+        let program = compress_string(&s).unwrap();
+        assert!(program.len() < 200, "{}", program);
+        assert_eq!(evaluate_string(program), "R".repeat(1000));
+
+        // 繰り返しの塊と、繰り返さない部分が混ざっている
+        let text = format!("Hello, {}world{}!", "LURD".repeat(100), "D".repeat(300));
+        let s = ICFPString::from_encoded_str(&text).unwrap();
+        let program = compress_string(&s).unwrap();
+        assert!(program.len() < text.len() / 2, "{}", program);
+        assert_eq!(evaluate_string(program), text);
+
+        // 短い文字列はそのまま
+        let s = ICFPString::from_encoded_str("hello").unwrap();
+        assert_eq!(compress_string(&s).unwrap(), string_token(&s).unwrap());
+    }
+
+    #[test]
+    fn test_repeat_program_longer_than_raw() {
+        // 繰り返しは使うが、REPEAT_FUNCTION の分だけ S... より長い
+        let text = "R".repeat(89);
+        let s = ICFPString::from_encoded_str(&text).unwrap();
+        let program = repeat_program(&s).unwrap();
+        assert!(program.len() > text.len() + 1, "{}", program);
+        assert_eq!(compress_string(&s).unwrap(), string_token(&s).unwrap());
+        assert_eq!(evaluate_string(program), text);
+    }
+}
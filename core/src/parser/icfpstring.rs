@@ -1,7 +1,7 @@
-use std::{fmt::Display, ops::Index};
+use std::{fmt::Display, ops::Index, rc::Rc};
 
 use macro_util::str_to_char_array;
-use num_bigint::BigInt;
+use num_bigint::{BigInt, Sign};
 
 use super::ParseError;
 
@@ -10,16 +10,44 @@ str_to_char_array!("abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ01234567
 
 const START_CH: char = '!';
 
+// '!' から '~' までの 94 文字
+const ALPHABET_SIZE: usize = 94;
+
+// Display は CHAR_MAP、iter / Index は ARRAY を使うので、両者が一致することをコンパイル時に確かめる
+// CHAR_MAP は全て ASCII なので、バイト単位で比べれば良い
+const _: () = {
+    let bytes = CHAR_MAP.as_bytes();
+    assert!(bytes.len() == ALPHABET_SIZE);
+    assert!(ARRAY.len() == ALPHABET_SIZE);
+    let mut i = 0;
+    while i < ALPHABET_SIZE {
+        assert!(bytes[i] as char == ARRAY[i]);
+        i += 1;
+    }
+};
+
 /// ICFP の中で使われる文字列 ("S..." や "I..." など)の表現
 /// 標準文字列に修正したり、base-94 文字列の数値変換が行いやすいようにする
+/// take / drop を O(1) にするため、バッファを共有して [offset, offset + len) の範囲を参照する
 #[derive(Debug, Clone)]
 pub struct ICFPString {
-    s: Vec<u8>,
+    s: Rc<Vec<u8>>,
+    offset: usize,
+    len: usize,
 }
 
 impl ICFPString {
     pub fn new(s: Vec<u8>) -> ICFPString {
-        ICFPString { s }
+        let len = s.len();
+        ICFPString {
+            s: Rc::new(s),
+            offset: 0,
+            len,
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.s[self.offset..self.offset + self.len]
     }
 
     pub fn from_rawstr(input: &str) -> Result<ICFPString, ParseError> {
@@ -36,22 +64,27 @@ impl ICFPString {
                 .ok_or(ParseError::InvalidCharacter(ch as i64))?;
             s.push(index as u8);
         }
-        Ok(ICFPString { s })
+        Ok(ICFPString::new(s))
     }
 
     pub fn from_str(input: Vec<char>) -> Result<ICFPString, ParseError> {
         let mut s = vec![];
         for ch in input.iter() {
             let index = *ch as i64 - START_CH as i64;
-            if index < 0 || index >= CHAR_MAP.len() as i64 {
+            if index < 0 || index >= ALPHABET_SIZE as i64 {
                 return Err(ParseError::InvalidCharacter(*ch as i64));
             }
             s.push(index as u8);
         }
-        Ok(ICFPString { s })
+        Ok(ICFPString::new(s))
     }
 
+    // 94 進数で表す。input は 0 以上
     pub fn from_int(input: BigInt) -> ICFPString {
+        // 0 も 1 桁の 0 として表す (空だと "I" だけの不正なトークンになる)
+        if input == BigInt::ZERO {
+            return ICFPString::new(vec![0]);
+        }
         let mut s = vec![];
         let mut input = input;
         let base = BigInt::from(94);
@@ -60,12 +93,16 @@ impl ICFPString {
             input /= 94;
         }
         s.reverse();
-        ICFPString { s }
+        ICFPString::new(s)
+    }
+
+    pub fn from_i64(input: i64) -> ICFPString {
+        ICFPString::from_int(BigInt::from(input))
     }
 
     pub fn to_string(&self) -> Result<Vec<char>, ParseError> {
         let mut ret = vec![];
-        for index in self.s.iter() {
+        for index in self.bytes().iter() {
             let new_index = *index as u32 + START_CH as u32;
             let ch = std::char::from_u32(new_index)
                 .ok_or(ParseError::InvalidCharacter(new_index as i64))?;
@@ -76,40 +113,80 @@ impl ICFPString {
 
     pub fn to_int(&self) -> BigInt {
         let mut ret = BigInt::ZERO;
-        for index in self.s.iter() {
+        for index in self.bytes().iter() {
             ret = ret * 94 + *index as i64;
         }
         ret
     }
 
+    // lambda / variable の番号のような小さい値用
+    // 値として使う整数は桁数に制限が無いので to_int を使う
+    pub fn to_i64(&self) -> Result<i64, ParseError> {
+        i64::try_from(self.to_int()).map_err(|_| ParseError::IntegerOverflow)
+    }
+
     pub fn len(&self) -> usize {
-        self.s.len()
+        self.len
     }
 
     pub fn iter(&self) -> impl Iterator<Item = &char> {
-        self.s.iter().map(|&index| &ARRAY[index as usize])
+        self.bytes().iter().map(|&index| &ARRAY[index as usize])
     }
 
+    // 連結したときだけ新しいバッファを確保する
     pub fn concat(&self, other: &ICFPString) -> ICFPString {
-        let mut s = self.s.clone();
-        s.extend(other.s.iter());
-        ICFPString { s }
+        let mut s = Vec::with_capacity(self.len + other.len);
+        s.extend_from_slice(self.bytes());
+        s.extend_from_slice(other.bytes());
+        ICFPString::new(s)
+    }
+
+    // n を [0, len] に収める。負なら 0、長さを超えるなら len
+    fn clamp_count(&self, n: &BigInt) -> usize {
+        if n.sign() == Sign::Minus {
+            return 0;
+        }
+        usize::try_from(n).map_or(self.len, |n| n.min(self.len))
     }
 
-    pub fn take(&self, n: usize) -> ICFPString {
-        let s = self.s.iter().take(n).copied().collect();
-        ICFPString { s }
+    // take / drop はバッファを共有したまま範囲だけ変える
+    pub fn take(&self, n: &BigInt) -> ICFPString {
+        ICFPString {
+            s: Rc::clone(&self.s),
+            offset: self.offset,
+            len: self.clamp_count(n),
+        }
     }
 
-    pub fn drop(&self, n: usize) -> ICFPString {
-        let s = self.s.iter().skip(n).copied().collect();
-        ICFPString { s }
+    pub fn drop(&self, n: &BigInt) -> ICFPString {
+        let n = self.clamp_count(n);
+        ICFPString {
+            s: Rc::clone(&self.s),
+            offset: self.offset + n,
+            len: self.len - n,
+        }
     }
 }
 
 impl PartialEq for ICFPString {
     fn eq(&self, other: &Self) -> bool {
-        self.s == other.s
+        self.bytes() == other.bytes()
+    }
+}
+
+// 人が読める文字列 (Display の結果) として保存する
+#[cfg(feature = "serde")]
+impl serde::Serialize for ICFPString {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for ICFPString {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        ICFPString::from_encoded_str(&s).map_err(serde::de::Error::custom)
     }
 }
 
@@ -117,7 +194,7 @@ impl Index<usize> for ICFPString {
     type Output = char;
 
     fn index(&self, index: usize) -> &Self::Output {
-        let char_index = self.s[index] as usize;
+        let char_index = self.bytes()[index] as usize;
         &ARRAY[char_index]
     }
 }
@@ -126,7 +203,7 @@ impl Display for ICFPString {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let char_map = to_vec_char(CHAR_MAP);
         let mut char_buffer = vec![];
-        for index in self.s.iter() {
+        for index in self.bytes().iter() {
             let ch = char_map.get(*index as usize).ok_or(std::fmt::Error)?;
             char_buffer.push(*ch);
         }
@@ -137,6 +214,13 @@ impl Display for ICFPString {
     }
 }
 
+/// ファイルから読んだ文字列の末尾の改行 ("\n" / "\r\n") を 1 つだけ取り除く
+/// 改行も ICFP の文字なので、そのままエンコードすると末尾に余計な改行が付いてしまう
+pub fn strip_trailing_newline(s: &str) -> &str {
+    let s = s.strip_suffix('\n').unwrap_or(s);
+    s.strip_suffix('\r').unwrap_or(s)
+}
+
 fn to_vec_char(s: &str) -> Vec<char> {
     s.chars().collect::<Vec<_>>()
 }
@@ -168,6 +252,14 @@ mod tests_rawstr {
         let input = vec![' '];
         let output = ICFPString::from_str(input);
         assert!(output.is_err());
+
+        // '~' の次の文字 (DEL) は 94 文字の範囲外
+        let ch = char::from_u32('~' as u32 + 1).unwrap();
+        assert_eq!(
+            ICFPString::from_str(vec!['~', ch]),
+            Err(ParseError::InvalidCharacter(ch as i64))
+        );
+        assert!(ICFPString::from_str(vec!['~']).is_ok());
     }
 
     #[test]
@@ -216,6 +308,63 @@ mod tests_rawstr {
         assert_eq!(output, expected);
     }
 
+    #[test]
+    fn test_from_i64_and_from_int_agree() {
+        assert_eq!(
+            ICFPString::from_i64(1337).to_string().unwrap(),
+            to_vec_char("/6")
+        );
+        assert_eq!(ICFPString::from_i64(i64::MAX).to_i64(), Ok(i64::MAX));
+        assert_eq!(
+            ICFPString::from_i64(i64::MAX),
+            ICFPString::from_int(BigInt::from(i64::MAX))
+        );
+    }
+
+    #[test]
+    fn test_from_int_beyond_u64() {
+        // u64::MAX + 1 = 2^64
+        let input = BigInt::from(u64::MAX) + 1u32;
+        let s = ICFPString::from_int(input.clone());
+        assert_eq!(s.to_int(), input);
+        assert_eq!(s.to_i64(), Err(ParseError::IntegerOverflow));
+
+        // 94^k は 1 の後に 0 ('!') が k 個続く
+        let output = ICFPString::from_int(BigInt::from(94).pow(40))
+            .to_string()
+            .unwrap();
+        let expected = to_vec_char(&format!("\"{}", "!".repeat(40)));
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_to_int_beyond_u64() {
+        let input = to_vec_char(&"~".repeat(30));
+        let s = ICFPString::from_str(input).unwrap();
+        assert_eq!(s.to_int(), BigInt::from(94).pow(30) - 1);
+        assert!(s.to_int() > BigInt::from(u64::MAX));
+        assert_eq!(ICFPString::from_int(s.to_int()), s);
+    }
+
+    #[test]
+    fn test_to_i64_overflow() {
+        let input = to_vec_char("/6");
+        assert_eq!(ICFPString::from_str(input).unwrap().to_i64(), Ok(1337));
+
+        // 94^12 > 2^63 なので 12 桁の最大値は i64 に収まらない
+        let input = to_vec_char("~~~~~~~~~~~~");
+        let s = ICFPString::from_str(input).unwrap();
+        assert_eq!(s.to_i64(), Err(ParseError::IntegerOverflow));
+        assert_eq!(s.to_int(), BigInt::from(94).pow(12) - 1);
+    }
+
+    #[test]
+    fn test_from_zero() {
+        let output = ICFPString::from_int(BigInt::ZERO).to_string().unwrap();
+        assert_eq!(output, to_vec_char("!"));
+        assert_eq!(ICFPString::from_int(BigInt::ZERO).to_int(), BigInt::ZERO);
+    }
+
     #[test]
     fn test_toi64() {
         let input = to_vec_char("/6");
@@ -224,4 +373,78 @@ mod tests_rawstr {
         let expected = BigInt::from(1337);
         assert_eq!(output, expected);
     }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_roundtrip() {
+        let s = ICFPString::from_str(to_vec_char("B%,,/}Q/2,$_")).unwrap();
+        let json = serde_json::to_string(&s).unwrap();
+        assert_eq!(json, "\"Hello World!\"");
+
+        let restored: ICFPString = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, s);
+    }
+
+    #[test]
+    fn test_space_and_newline_roundtrip() {
+        let text = "###.#...\n...L..##\n.#######\nsolve lambdaman1 RRDD";
+        let s = ICFPString::from_encoded_str(text).unwrap();
+        assert_eq!(s.len(), text.chars().count());
+        assert_eq!(format!("{}", s), text);
+
+        // 空白と改行は CHAR_MAP の最後の 2 文字
+        let raw = s.to_string().unwrap();
+        assert_eq!(raw[8], '~');
+        assert_eq!(raw[raw.len() - 5], '}');
+
+        let from_raw = ICFPString::from_rawstr(&raw.iter().collect::<String>()).unwrap();
+        assert_eq!(from_raw, s);
+
+        let from_int = ICFPString::from_int(s.to_int());
+        assert_eq!(from_int, s);
+    }
+
+    #[test]
+    fn test_take_drop_share_buffer() {
+        let text = "abcdefghij".repeat(10000);
+        let n = |v: usize| BigInt::from(v);
+        let mut s = ICFPString::from_encoded_str(&text).unwrap();
+        for _ in 0..1000 {
+            s = s.drop(&n(1));
+        }
+        // 範囲だけ変わり、バッファはコピーされない
+        assert_eq!(s.len(), text.len() - 1000);
+        assert_eq!(format!("{}", s), text[1000..]);
+
+        let head = s.take(&n(3));
+        assert_eq!(format!("{}", head), "abc");
+        assert_eq!(format!("{}", head.drop(&n(1)).take(&n(1))), "b");
+
+        let joined = head.concat(&s.drop(&n(s.len() - 2)));
+        assert_eq!(joined, ICFPString::from_encoded_str("abcij").unwrap());
+    }
+
+    #[test]
+    fn test_take_drop_out_of_range() {
+        let s = ICFPString::from_encoded_str("abc").unwrap();
+        let huge = BigInt::from(94).pow(30);
+        let negative = BigInt::from(-5);
+
+        assert_eq!(s.take(&BigInt::from(100)), s);
+        assert_eq!(s.take(&huge), s);
+        assert_eq!(s.take(&negative).len(), 0);
+
+        assert_eq!(s.drop(&BigInt::from(100)).len(), 0);
+        assert_eq!(s.drop(&huge).len(), 0);
+        assert_eq!(s.drop(&negative), s);
+    }
+
+    #[test]
+    fn test_strip_trailing_newline() {
+        assert_eq!(strip_trailing_newline("RRDD\n"), "RRDD");
+        assert_eq!(strip_trailing_newline("RRDD\r\n"), "RRDD");
+        assert_eq!(strip_trailing_newline("RRDD"), "RRDD");
+        // 改行は 1 つだけ取り除く
+        assert_eq!(strip_trailing_newline("a b\n\n"), "a b\n");
+    }
 }
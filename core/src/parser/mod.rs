@@ -1,24 +1,72 @@
 pub mod ast;
+pub mod compress;
 pub mod icfpstring;
 pub mod tokenizer;
 
 use std::fmt::Display;
 
-#[derive(thiserror::Error, Debug)]
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
 pub enum ParseError {
     InvalidCharacter(i64),
-    InvalidToken,
+    InvalidToken(Box<InvalidToken>),
     CannotFindNextToken,
     CannotConsumeToken,
+    CyclicReference(usize),
+    NotAValue,
+    IntegerOverflow,
+    EvaluationLimitExceeded(usize),
+    DivisionByZero(usize),
+    NotRootNode(usize),
+}
+
+// 解釈できなかったトークンとその位置
+// ParseError はほぼ全ての関数の Result に入るので、Result<_, ParseError> を小さく保つために Box に入れて持つ
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidToken {
+    // 何番目 (0-indexed) のトークンか
+    pub index: usize,
+    // 入力の先頭からのバイト位置
+    pub offset: usize,
+    pub token: String,
+}
+
+impl ParseError {
+    pub fn invalid_token(index: usize, offset: usize, token: &str) -> ParseError {
+        ParseError::InvalidToken(Box::new(InvalidToken {
+            index,
+            offset,
+            token: token.to_string(),
+        }))
+    }
 }
 
 impl Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             ParseError::InvalidCharacter(i64) => write!(f, "Invalid character {}", i64),
-            ParseError::InvalidToken => write!(f, "Invalid token"),
+            ParseError::InvalidToken(invalid) => write!(
+                f,
+                "Invalid token \"{}\" (token {}, byte {})",
+                invalid.token, invalid.index, invalid.offset
+            ),
             ParseError::CannotFindNextToken => write!(f, "cannot find next token"),
             ParseError::CannotConsumeToken => write!(f, "cannot consume all token"),
+            ParseError::CyclicReference(node_id) => {
+                write!(f, "cyclic reference from node {}", node_id)
+            }
+            ParseError::NotAValue => write!(f, "result is not a boolean, integer or string"),
+            ParseError::IntegerOverflow => write!(f, "integer overflow"),
+            ParseError::EvaluationLimitExceeded(max_iterations) => write!(
+                f,
+                "evaluation did not finish within {} iterations",
+                max_iterations
+            ),
+            ParseError::DivisionByZero(node_id) => {
+                write!(f, "division by zero at node {}", node_id)
+            }
+            ParseError::NotRootNode(node_id) => {
+                write!(f, "node {} is not the root of the program", node_id)
+            }
         }
     }
 }
@@ -18,8 +18,8 @@ pub enum BinaryOpecode {
     Mul,
     Div,
     Modulo,
-    IntegerLarger,
-    IntegerSmaller,
+    LessThan,
+    GreaterThan,
     Equal,
     Or,
     And,
@@ -29,6 +29,40 @@ pub enum BinaryOpecode {
     Apply,
 }
 
+impl UnaryOpecode {
+    // tokenize_one の逆変換
+    pub fn to_token_str(self) -> &'static str {
+        match self {
+            UnaryOpecode::Negate => "U-",
+            UnaryOpecode::Not => "U!",
+            UnaryOpecode::StrToInt => "U#",
+            UnaryOpecode::IntToStr => "U$",
+        }
+    }
+}
+
+impl BinaryOpecode {
+    // tokenize_one の逆変換
+    pub fn to_token_str(self) -> &'static str {
+        match self {
+            BinaryOpecode::Add => "B+",
+            BinaryOpecode::Sub => "B-",
+            BinaryOpecode::Mul => "B*",
+            BinaryOpecode::Div => "B/",
+            BinaryOpecode::Modulo => "B%",
+            BinaryOpecode::LessThan => "B<",
+            BinaryOpecode::GreaterThan => "B>",
+            BinaryOpecode::Equal => "B=",
+            BinaryOpecode::Or => "B|",
+            BinaryOpecode::And => "B&",
+            BinaryOpecode::StrConcat => "B.",
+            BinaryOpecode::TakeStr => "BT",
+            BinaryOpecode::DropStr => "BD",
+            BinaryOpecode::Apply => "B$",
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum TokenType {
     Boolean(bool),
@@ -42,67 +76,83 @@ pub enum TokenType {
 }
 
 pub fn tokenize(input: String) -> Result<Vec<TokenType>, ParseError> {
-    let mut ret = vec![];
-    for token_str in input.split_ascii_whitespace() {
-        let chars = token_str.chars().collect::<Vec<_>>();
-        match chars[0] {
-            'T' => ret.push(TokenType::Boolean(true)),
-            'F' => ret.push(TokenType::Boolean(false)),
-            'I' => {
-                let s = ICFPString::from_str(chars[1..].to_vec())?;
-                let num = s.to_int();
-                ret.push(TokenType::Integer(num));
-            }
-            'S' => {
-                let s = ICFPString::from_str(chars[1..].to_vec())?;
-                ret.push(TokenType::String(s));
-            }
-            'U' => match chars[1] {
-                '-' => ret.push(TokenType::Unary(UnaryOpecode::Negate)),
-                '!' => ret.push(TokenType::Unary(UnaryOpecode::Not)),
-                '#' => ret.push(TokenType::Unary(UnaryOpecode::StrToInt)),
-                '$' => ret.push(TokenType::Unary(UnaryOpecode::IntToStr)),
-                _ => return Err(ParseError::InvalidToken),
-            },
-            'B' => match chars[1] {
-                '+' => ret.push(TokenType::Binary(BinaryOpecode::Add)),
-                '-' => ret.push(TokenType::Binary(BinaryOpecode::Sub)),
-                '*' => ret.push(TokenType::Binary(BinaryOpecode::Mul)),
-                '/' => ret.push(TokenType::Binary(BinaryOpecode::Div)),
-                '%' => ret.push(TokenType::Binary(BinaryOpecode::Modulo)),
-                '<' => ret.push(TokenType::Binary(BinaryOpecode::IntegerLarger)),
-                '>' => ret.push(TokenType::Binary(BinaryOpecode::IntegerSmaller)),
-                '=' => ret.push(TokenType::Binary(BinaryOpecode::Equal)),
-                '|' => ret.push(TokenType::Binary(BinaryOpecode::Or)),
-                '&' => ret.push(TokenType::Binary(BinaryOpecode::And)),
-                '.' => ret.push(TokenType::Binary(BinaryOpecode::StrConcat)),
-                'T' => ret.push(TokenType::Binary(BinaryOpecode::TakeStr)),
-                'D' => ret.push(TokenType::Binary(BinaryOpecode::DropStr)),
-                '$' => ret.push(TokenType::Binary(BinaryOpecode::Apply)),
-                _ => return Err(ParseError::InvalidToken),
-            },
-            '?' => ret.push(TokenType::If),
-            'L' => {
-                let s = ICFPString::from_str(chars[1..].to_vec())?;
-                let num = s.to_int();
-                ret.push(TokenType::Lambda(num.try_into().unwrap()));
-            }
-            'v' => {
-                let s = ICFPString::from_str(chars[1..].to_vec())?;
-                let num = s.to_int();
-                ret.push(TokenType::Variable(num.try_into().unwrap()));
-            }
-            _ => return Err(ParseError::InvalidToken),
+    tokenize_iter(&input).collect()
+}
+
+// 空白区切りのトークンを 1 つずつ読む
+// 全トークンを Vec に集めないので、巨大な入力でもメモリを倍使わずに済む
+pub fn tokenize_iter(input: &str) -> impl Iterator<Item = Result<TokenType, ParseError>> + '_ {
+    input
+        .split_ascii_whitespace()
+        .enumerate()
+        .map(move |(index, token_str)| {
+            // token_str は input の部分文字列なので、先頭のアドレスの差がバイト位置になる
+            let offset = token_str.as_ptr() as usize - input.as_ptr() as usize;
+            tokenize_one(index, offset, token_str)
+        })
+}
+
+// index, offset はエラーの時に位置を示すためだけに使う
+fn tokenize_one(index: usize, offset: usize, token_str: &str) -> Result<TokenType, ParseError> {
+    let invalid = || ParseError::invalid_token(index, offset, token_str);
+    let chars = token_str.chars().collect::<Vec<_>>();
+    let token = match chars[0] {
+        'T' => TokenType::Boolean(true),
+        'F' => TokenType::Boolean(false),
+        'I' => {
+            let s = ICFPString::from_str(chars[1..].to_vec())?;
+            let num = s.to_int();
+            TokenType::Integer(num)
         }
-    }
-    Ok(ret)
+        'S' => {
+            let s = ICFPString::from_str(chars[1..].to_vec())?;
+            TokenType::String(s)
+        }
+        'U' => match chars.get(1).ok_or_else(invalid)? {
+            '-' => TokenType::Unary(UnaryOpecode::Negate),
+            '!' => TokenType::Unary(UnaryOpecode::Not),
+            '#' => TokenType::Unary(UnaryOpecode::StrToInt),
+            '$' => TokenType::Unary(UnaryOpecode::IntToStr),
+            _ => return Err(invalid()),
+        },
+        'B' => match chars.get(1).ok_or_else(invalid)? {
+            '+' => TokenType::Binary(BinaryOpecode::Add),
+            '-' => TokenType::Binary(BinaryOpecode::Sub),
+            '*' => TokenType::Binary(BinaryOpecode::Mul),
+            '/' => TokenType::Binary(BinaryOpecode::Div),
+            '%' => TokenType::Binary(BinaryOpecode::Modulo),
+            '<' => TokenType::Binary(BinaryOpecode::LessThan),
+            '>' => TokenType::Binary(BinaryOpecode::GreaterThan),
+            '=' => TokenType::Binary(BinaryOpecode::Equal),
+            '|' => TokenType::Binary(BinaryOpecode::Or),
+            '&' => TokenType::Binary(BinaryOpecode::And),
+            '.' => TokenType::Binary(BinaryOpecode::StrConcat),
+            'T' => TokenType::Binary(BinaryOpecode::TakeStr),
+            'D' => TokenType::Binary(BinaryOpecode::DropStr),
+            '$' => TokenType::Binary(BinaryOpecode::Apply),
+            _ => return Err(invalid()),
+        },
+        '?' => TokenType::If,
+        'L' => {
+            let s = ICFPString::from_str(chars[1..].to_vec())?;
+            let num = u32::try_from(s.to_i64()?).map_err(|_| ParseError::IntegerOverflow)?;
+            TokenType::Lambda(num)
+        }
+        'v' => {
+            let s = ICFPString::from_str(chars[1..].to_vec())?;
+            let num = u32::try_from(s.to_i64()?).map_err(|_| ParseError::IntegerOverflow)?;
+            TokenType::Variable(num)
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(token)
 }
 
 #[cfg(test)]
 mod tests {
     use num_bigint::BigInt;
 
-    use crate::parser::{icfpstring::ICFPString, tokenizer::TokenType};
+    use crate::parser::{icfpstring::ICFPString, tokenizer::TokenType, ParseError};
 
     use super::{tokenize, BinaryOpecode, UnaryOpecode};
 
@@ -175,13 +225,13 @@ mod tests {
     }
 
     #[test]
-    fn test_example_binary_int_larger() {
-        run_single_token_test("B<", TokenType::Binary(BinaryOpecode::IntegerLarger));
+    fn test_example_binary_less_than() {
+        run_single_token_test("B<", TokenType::Binary(BinaryOpecode::LessThan));
     }
 
     #[test]
-    fn test_example_binary_int_smaller() {
-        run_single_token_test("B>", TokenType::Binary(BinaryOpecode::IntegerSmaller));
+    fn test_example_binary_greater_than() {
+        run_single_token_test("B>", TokenType::Binary(BinaryOpecode::GreaterThan));
     }
 
     #[test]
@@ -241,7 +291,7 @@ mod tests {
         assert_eq!(token_list.len(), 6);
         let expected = vec![
             TokenType::If,
-            TokenType::Binary(BinaryOpecode::IntegerSmaller),
+            TokenType::Binary(BinaryOpecode::GreaterThan),
             TokenType::Integer(BigInt::from(2)),
             TokenType::Integer(BigInt::from(3)),
             TokenType::String(ICFPString::from_str("9%3".chars().collect()).unwrap()),
@@ -251,10 +301,81 @@ mod tests {
         assert_eq!(token_list, expected);
     }
 
+    #[test]
+    fn test_comparison_tokens() {
+        // '<' は i1 < i2、'>' は i1 > i2
+        let token_list = tokenize("B< I# I$ B> I$ I#".to_string()).unwrap();
+        assert_eq!(
+            token_list,
+            vec![
+                TokenType::Binary(BinaryOpecode::LessThan),
+                TokenType::Integer(BigInt::from(2)),
+                TokenType::Integer(BigInt::from(3)),
+                TokenType::Binary(BinaryOpecode::GreaterThan),
+                TokenType::Integer(BigInt::from(3)),
+                TokenType::Integer(BigInt::from(2)),
+            ]
+        );
+    }
+
     #[test]
     fn test_invalid_token() {
-        let input = "X";
-        let result = tokenize(input.to_string());
-        assert!(result.is_err());
+        for (input, index, offset, token) in [
+            ("X", 0, 0, "X"),
+            ("U?", 0, 0, "U?"),
+            ("B@", 0, 0, "B@"),
+            ("U", 0, 0, "U"),
+            ("T X", 1, 2, "X"),
+        ] {
+            assert_eq!(
+                tokenize(input.to_string()),
+                Err(ParseError::invalid_token(index, offset, token)),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_token_position() {
+        let err = tokenize("B+ I\"  B@x  I#".to_string()).unwrap_err();
+        assert_eq!(err, ParseError::invalid_token(2, 7, "B@x"));
+        assert_eq!(err.to_string(), "Invalid token \"B@x\" (token 2, byte 7)");
+    }
+
+    #[test]
+    fn test_invalid_character() {
+        // '!' - '~' 以外の文字は文字列・整数に使えない
+        assert_eq!(
+            tokenize("S\u{7f}".to_string()),
+            Err(ParseError::InvalidCharacter(0x7f))
+        );
+        assert_eq!(
+            tokenize("Ié".to_string()),
+            Err(ParseError::InvalidCharacter('é' as i64))
+        );
+    }
+
+    #[test]
+    fn test_large_integer() {
+        // 94^30 - 1 は i64 に収まらないが、そのまま BigInt になる
+        let token_list = tokenize(format!("I{}", "~".repeat(30))).unwrap();
+        assert_eq!(
+            token_list,
+            vec![TokenType::Integer(BigInt::from(94).pow(30) - 1)]
+        );
+    }
+
+    #[test]
+    fn test_large_variable_id() {
+        assert_eq!(
+            tokenize("L~~~~~~~~~~~~".to_string()),
+            Err(ParseError::IntegerOverflow)
+        );
+        // u32 に収まらない
+        assert_eq!(
+            tokenize("v~~~~~~".to_string()),
+            Err(ParseError::IntegerOverflow)
+        );
     }
 }
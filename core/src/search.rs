@@ -0,0 +1,145 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    hash::Hash,
+};
+
+/// start から goal を満たす状態までの最短経路を A* で探す
+/// neighbors は (次の状態, 移動コスト) を返し、heuristic は goal までのコストの下界を返す
+/// heuristic が常に 0 なら Dijkstra (コストが全て 1 なら BFS) と同じになる
+/// 見つかった場合は (start から goal までの状態列, 合計コスト) を返す
+pub fn astar<State, Neighbors>(
+    start: State,
+    mut neighbors: impl FnMut(&State) -> Neighbors,
+    mut heuristic: impl FnMut(&State) -> i64,
+    mut goal: impl FnMut(&State) -> bool,
+) -> Option<(Vec<State>, i64)>
+where
+    State: Clone + Eq + Hash + Ord,
+    Neighbors: IntoIterator<Item = (State, i64)>,
+{
+    let mut cost_table = HashMap::new();
+    let mut prev_table: HashMap<State, State> = HashMap::new();
+    let mut queue = BinaryHeap::new();
+
+    cost_table.insert(start.clone(), 0);
+    queue.push(Reverse((heuristic(&start), 0, start)));
+
+    while let Some(Reverse((_, cost, state))) = queue.pop() {
+        if cost_table.get(&state).is_some_and(|&c| c < cost) {
+            continue;
+        }
+        if goal(&state) {
+            let mut path = vec![state.clone()];
+            let mut current = state;
+            while let Some(prev) = prev_table.get(&current) {
+                path.push(prev.clone());
+                current = prev.clone();
+            }
+            path.reverse();
+            return Some((path, cost));
+        }
+
+        for (next, step_cost) in neighbors(&state) {
+            let next_cost = cost + step_cost;
+            if cost_table.get(&next).is_some_and(|&c| c <= next_cost) {
+                continue;
+            }
+            cost_table.insert(next.clone(), next_cost);
+            prev_table.insert(next.clone(), state.clone());
+            queue.push(Reverse((next_cost + heuristic(&next), next_cost, next)));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    fn to_grid(s: &str) -> Vec<Vec<char>> {
+        s.lines().map(|line| line.chars().collect()).collect()
+    }
+
+    fn grid_neighbors(grid: &[Vec<char>], (y, x): (usize, usize)) -> Vec<((usize, usize), i64)> {
+        let mut ret = vec![];
+        for (dy, dx) in [(0, 1), (1, 0), (0, -1), (-1, 0)] {
+            let ny = y as i64 + dy;
+            let nx = x as i64 + dx;
+            if ny < 0 || nx < 0 {
+                continue;
+            }
+            let (ny, nx) = (ny as usize, nx as usize);
+            if grid
+                .get(ny)
+                .and_then(|row| row.get(nx))
+                .is_some_and(|&c| c != '#')
+            {
+                ret.push(((ny, nx), 1));
+            }
+        }
+        ret
+    }
+
+    fn bfs_distance(grid: &[Vec<char>], start: (usize, usize), goal: (usize, usize)) -> i64 {
+        let mut dist = HashMap::new();
+        let mut queue = VecDeque::new();
+        dist.insert(start, 0);
+        queue.push_back(start);
+        while let Some(pos) = queue.pop_front() {
+            for (next, _) in grid_neighbors(grid, pos) {
+                if !dist.contains_key(&next) {
+                    dist.insert(next, dist[&pos] + 1);
+                    queue.push_back(next);
+                }
+            }
+        }
+        dist[&goal]
+    }
+
+    #[test]
+    fn test_astar_manhattan_matches_bfs() {
+        let grid = to_grid("......\n.####.\n.#....\n.#.##.\n...#..");
+        let start = (0, 0);
+        let manhattan = |goal: (usize, usize)| {
+            move |&(y, x): &(usize, usize)| (y.abs_diff(goal.0) + x.abs_diff(goal.1)) as i64
+        };
+
+        for goal in [(4, 5), (2, 2), (3, 2), (0, 5)] {
+            let (path, cost) = astar(
+                start,
+                |&pos| grid_neighbors(&grid, pos),
+                manhattan(goal),
+                |&pos| pos == goal,
+            )
+            .unwrap();
+            assert_eq!(cost, bfs_distance(&grid, start, goal));
+            assert_eq!(path.len() as i64, cost + 1);
+            assert_eq!(path.first(), Some(&start));
+            assert_eq!(path.last(), Some(&goal));
+
+            let (_, zero_cost) = astar(
+                start,
+                |&pos| grid_neighbors(&grid, pos),
+                |_| 0,
+                |&pos| pos == goal,
+            )
+            .unwrap();
+            assert_eq!(zero_cost, cost);
+        }
+    }
+
+    #[test]
+    fn test_astar_unreachable() {
+        let grid = to_grid("..#.");
+        let result = astar(
+            (0, 0),
+            |&pos| grid_neighbors(&grid, pos),
+            |_| 0,
+            |&pos| pos == (0, 3),
+        );
+        assert!(result.is_none());
+    }
+}
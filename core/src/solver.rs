@@ -0,0 +1,126 @@
+use std::{fmt::Display, io::BufRead};
+
+// エラーメッセージに含める入力行の最大文字数
+const SNIPPET_LEN: usize = 40;
+
+#[derive(thiserror::Error, Debug)]
+pub enum SolveError {
+    InputParse { line: usize, message: String },
+}
+
+impl Display for SolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SolveError::InputParse { line, message } => {
+                write!(f, "failed to parse input at line {}: {}", line, message)
+            }
+        }
+    }
+}
+
+impl SolveError {
+    /// line は 1-indexed。content が長い場合は先頭だけをメッセージに含める
+    pub fn input_parse(line: usize, content: &str, cause: impl Display) -> SolveError {
+        let mut snippet = content.chars().take(SNIPPET_LEN).collect::<String>();
+        if content.chars().count() > SNIPPET_LEN {
+            snippet.push_str("...");
+        }
+        SolveError::InputParse {
+            line,
+            message: format!("{} (\"{}\")", cause, snippet),
+        }
+    }
+
+    /// 行そのものが読めなかった場合 (パイプが途中で壊れた等)
+    pub fn input_io(line: usize, cause: std::io::Error) -> SolveError {
+        SolveError::InputParse {
+            line,
+            message: cause.to_string(),
+        }
+    }
+}
+
+/// 空行で区切って 1 つのファイルにまとめた複数の問題を、問題ごとの文字列に分ける
+/// 空行が続いても空の問題は作らない。各問題は改行で終わる
+pub fn split_records(reader: impl BufRead) -> Result<Vec<String>, SolveError> {
+    let mut records = vec![];
+    let mut current = String::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| SolveError::input_io(index + 1, e))?;
+        if line.trim().is_empty() {
+            if !current.is_empty() {
+                records.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+        current.push_str(&line);
+        current.push('\n');
+    }
+    if !current.is_empty() {
+        records.push(current);
+    }
+    Ok(records)
+}
+
+/// 新しく求めた解を、保存済みの解 (baseline) と長さで比べた結果。短い方が良い
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Better,
+    Equal,
+    Worse,
+}
+
+impl Comparison {
+    pub fn of(new_len: usize, baseline_len: usize) -> Comparison {
+        match new_len.cmp(&baseline_len) {
+            std::cmp::Ordering::Less => Comparison::Better,
+            std::cmp::Ordering::Equal => Comparison::Equal,
+            std::cmp::Ordering::Greater => Comparison::Worse,
+        }
+    }
+}
+
+impl Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Comparison::Better => write!(f, "better"),
+            Comparison::Equal => write!(f, "equal"),
+            Comparison::Worse => write!(f, "worse"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_input_parse_message() {
+        let long_line = "x".repeat(100);
+        let err = SolveError::input_parse(3, &long_line, "invalid digit");
+        let SolveError::InputParse { line, message } = &err;
+        assert_eq!(*line, 3);
+        assert!(message.starts_with("invalid digit"));
+        assert!(message.contains(&format!("{}...", "x".repeat(SNIPPET_LEN))));
+        assert!(err.to_string().contains("line 3"));
+    }
+
+    #[test]
+    fn test_comparison() {
+        assert_eq!(Comparison::of(10, 12), Comparison::Better);
+        assert_eq!(Comparison::of(12, 12), Comparison::Equal);
+        assert_eq!(Comparison::of(13, 12), Comparison::Worse);
+        assert_eq!(Comparison::Worse.to_string(), "worse");
+    }
+
+    #[test]
+    fn test_split_records() {
+        let input = "\n#L.\n...\n\n\n1 -1\n2 -5\n  \nL#\n";
+        let records = split_records(std::io::Cursor::new(input)).unwrap();
+        assert_eq!(records, vec!["#L.\n...\n", "1 -1\n2 -5\n", "L#\n"]);
+
+        assert!(split_records(std::io::Cursor::new("\n\n"))
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::HashSet;
+
+use crate::{grid::SimError, solver::SolveError};
+
+/// 問題文の点の一覧 ("x y" の行の並び) を読む。空行があればそこで終わりとみなす
+pub fn parse_points(input: &str) -> Result<Vec<(i64, i64)>, SolveError> {
+    let mut points = vec![];
+    for (index, line) in input.lines().enumerate() {
+        if line.is_empty() {
+            break;
+        }
+        let nums = line
+            .split_whitespace()
+            .map(|s| s.parse::<i64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| SolveError::input_parse(index + 1, line, e))?;
+        if nums.len() != 2 {
+            return Err(SolveError::input_parse(
+                index + 1,
+                line,
+                format!("expected 2 numbers, found {}", nums.len()),
+            ));
+        }
+        points.push((nums[0], nums[1]));
+    }
+    Ok(points)
+}
+
+/// 数字キー (1 - 9) に対応する加速度 (dx, dy)。テンキーの配置と同じで、y は上向き
+fn acceleration(ch: char) -> Option<(i64, i64)> {
+    let digit = ch.to_digit(10).filter(|&d| d >= 1)? as i64 - 1;
+    Some((digit % 3 - 1, digit / 3 - 1))
+}
+
+/// 原点で静止した状態から moves を再生し、points の全ての点を訪れるか確認する
+/// 点を訪れたかどうかは、各 move の後の位置で判定する
+pub fn simulate_spaceship(points: &[(i64, i64)], moves: &str) -> Result<(), SimError> {
+    let (mut x, mut y, mut vx, mut vy) = (0i64, 0i64, 0i64, 0i64);
+    let mut visited = HashSet::new();
+    let mut tick = 0;
+
+    for ch in moves.chars() {
+        let (ax, ay) = acceleration(ch).ok_or(SimError::InvalidMove(ch))?;
+        vx += ax;
+        vy += ay;
+        x += vx;
+        y += vy;
+        visited.insert((x, y));
+        tick += 1;
+    }
+
+    let unvisited = points
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| !visited.contains(p))
+        .collect::<Vec<_>>();
+    match unvisited.first() {
+        None => Ok(()),
+        Some(&(index, &(x, y))) => Err(SimError::UnvisitedPoint {
+            index,
+            x,
+            y,
+            remaining: unvisited.len(),
+            tick,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "236659" で訪れる位置から 1 つ (6, -7) を除いたもの
+    const POINTS: [(i64, i64); 5] = [(0, -1), (1, -3), (3, -5), (9, -9), (13, -10)];
+
+    #[test]
+    fn test_parse_points() {
+        assert_eq!(
+            parse_points("1 -1\n1 -3\n\n2 -5\n").unwrap(),
+            vec![(1, -1), (1, -3)]
+        );
+        assert!(parse_points("1 -1\n1\n").is_err());
+    }
+
+    #[test]
+    fn test_simulate_valid() {
+        assert_eq!(simulate_spaceship(&POINTS, "236659"), Ok(()));
+    }
+
+    #[test]
+    fn test_simulate_unvisited() {
+        assert_eq!(
+            simulate_spaceship(&POINTS, "2366"),
+            Err(SimError::UnvisitedPoint {
+                index: 3,
+                x: 9,
+                y: -9,
+                remaining: 2,
+                tick: 4,
+            })
+        );
+        assert_eq!(
+            simulate_spaceship(&POINTS, "2366").unwrap_err().to_string(),
+            "point #3 (9, -9) is not visited after 4 ticks (2 remaining)"
+        );
+        assert_eq!(
+            simulate_spaceship(&POINTS, "23665X"),
+            Err(SimError::InvalidMove('X'))
+        );
+        assert_eq!(
+            simulate_spaceship(&POINTS, "0"),
+            Err(SimError::InvalidMove('0'))
+        );
+    }
+}
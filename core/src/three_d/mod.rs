@@ -0,0 +1,436 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
+
+use num_bigint::BigInt;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum SimError {
+    InvalidCell { x: usize, y: usize, token: String },
+    WriteConflict { x: i64, y: i64, time: usize },
+    SubmitConflict { time: usize },
+    DivisionByZero { x: i64, y: i64, time: usize },
+    InvalidTimeWarp { x: i64, y: i64, time: usize },
+    TimeWarpConflict { time: usize },
+    NoOutput { time: usize },
+    TickLimitExceeded(usize),
+}
+
+impl Display for SimError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SimError::InvalidCell { x, y, token } => {
+                write!(f, "invalid cell '{}' at (x: {}, y: {})", token, x, y)
+            }
+            SimError::WriteConflict { x, y, time } => write!(
+                f,
+                "multiple values are written to (x: {}, y: {}) at time {}",
+                x, y, time
+            ),
+            SimError::SubmitConflict { time } => {
+                write!(f, "different values are submitted at time {}", time)
+            }
+            SimError::DivisionByZero { x, y, time } => write!(
+                f,
+                "division by zero at (x: {}, y: {}) at time {}",
+                x, y, time
+            ),
+            SimError::InvalidTimeWarp { x, y, time } => write!(
+                f,
+                "invalid time warp at (x: {}, y: {}) at time {}",
+                x, y, time
+            ),
+            SimError::TimeWarpConflict { time } => {
+                write!(f, "conflicting time warps at time {}", time)
+            }
+            SimError::NoOutput { time } => {
+                write!(f, "no operator can be reduced at time {}", time)
+            }
+            SimError::TickLimitExceeded(max_ticks) => {
+                write!(f, "no value is submitted within {} ticks", max_ticks)
+            }
+        }
+    }
+}
+
+/// 盤面の 1 マス。A / B は読み込み時点ではそのまま持っておき、simulate の最初に入力値で置き換える
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cell {
+    Value(BigInt),
+    // < > ^ v + - * / % @ = # S のいずれか
+    Operator(char),
+    InputA,
+    InputB,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimResult {
+    // S に書き込まれた値
+    pub output: BigInt,
+    // 時間を巻き戻した分も含めて、実際に計算した tick の数
+    pub ticks: usize,
+    // 到達した最大の時刻 (スコアの時空間体積の t に相当)
+    pub max_time: usize,
+}
+
+const OPERATORS: &str = "<>^v+-*/%@=#S";
+
+/// 空白区切りの盤面を読む。"." は空きマス。空行があればそこで終わりとみなす
+pub fn parse_grid(input: &str) -> Result<Vec<Vec<Option<Cell>>>, SimError> {
+    let mut grid = vec![];
+    for (y, line) in input.lines().enumerate() {
+        if line.trim().is_empty() {
+            break;
+        }
+        let mut row = vec![];
+        for (x, token) in line.split_whitespace().enumerate() {
+            let cell = match token {
+                "." => None,
+                "A" => Some(Cell::InputA),
+                "B" => Some(Cell::InputB),
+                _ if token.len() == 1 && OPERATORS.contains(token) => {
+                    Some(Cell::Operator(token.chars().next().unwrap()))
+                }
+                _ => match token.parse::<BigInt>() {
+                    Ok(value) => Some(Cell::Value(value)),
+                    Err(_) => {
+                        return Err(SimError::InvalidCell {
+                            x,
+                            y,
+                            token: token.to_string(),
+                        })
+                    }
+                },
+            };
+            row.push(cell);
+        }
+        grid.push(row);
+    }
+    Ok(grid)
+}
+
+// 盤面は無限に広がりうるので、空でないマスだけを座標で持つ
+type Board = HashMap<(i64, i64), Cell>;
+
+// 1 tick の間に起きる書き込み
+#[derive(Default)]
+struct TickEffect {
+    // 入力として読まれて消えるマス
+    erased: HashSet<(i64, i64)>,
+    writes: HashMap<(i64, i64), BigInt>,
+    submitted: Option<BigInt>,
+    warps: Vec<TimeWarp>,
+}
+
+struct TimeWarp {
+    // @ の位置
+    from: (i64, i64),
+    dt: i64,
+    to: (i64, i64),
+    value: BigInt,
+}
+
+/// 3d プログラムを入力 A, B で実行し、S に書き込まれた値を返す
+/// 時刻 1 の盤面から始め、全ての演算子を同時に評価して次の時刻の盤面を作る
+/// `@` が動いた場合は、その tick の他の変化を捨てて過去の盤面に書き込み、その時刻からやり直す
+pub fn simulate(
+    grid: &[Vec<Option<Cell>>],
+    a: BigInt,
+    b: BigInt,
+    max_ticks: usize,
+) -> Result<SimResult, SimError> {
+    let mut board = Board::new();
+    for (y, row) in grid.iter().enumerate() {
+        for (x, cell) in row.iter().enumerate() {
+            let cell = match cell {
+                None => continue,
+                Some(Cell::InputA) => Cell::Value(a.clone()),
+                Some(Cell::InputB) => Cell::Value(b.clone()),
+                Some(cell) => cell.clone(),
+            };
+            board.insert((x as i64, y as i64), cell);
+        }
+    }
+
+    // history[t - 1] が時刻 t の盤面
+    let mut history = vec![board];
+    let mut max_time = 1;
+    for tick in 1..=max_ticks {
+        let time = history.len();
+        let board = history.last().unwrap();
+        let effect = evaluate_tick(board, time)?;
+
+        // 同じ tick で S への書き込みと time warp が起きた場合は S を優先する
+        if let Some(output) = effect.submitted {
+            return Ok(SimResult {
+                output,
+                ticks: tick,
+                max_time: max_time.max(time + 1),
+            });
+        }
+
+        if !effect.warps.is_empty() {
+            let TimeWarp {
+                from: (x, y), dt, ..
+            } = effect.warps[0];
+            if effect.warps.iter().any(|warp| warp.dt != dt) {
+                return Err(SimError::TimeWarpConflict { time });
+            }
+            // 未来や、時刻 1 より前には飛べない
+            let target_time = time as i64 - dt;
+            if dt <= 0 || target_time < 1 {
+                return Err(SimError::InvalidTimeWarp { x, y, time });
+            }
+
+            history.truncate(target_time as usize);
+            let board = history.last_mut().unwrap();
+            let mut written = HashMap::new();
+            for warp in effect.warps {
+                match written.get(&warp.to) {
+                    Some(prev) if *prev != warp.value => {
+                        return Err(SimError::TimeWarpConflict { time });
+                    }
+                    _ => {
+                        written.insert(warp.to, warp.value.clone());
+                        board.insert(warp.to, Cell::Value(warp.value));
+                    }
+                }
+            }
+            continue;
+        }
+
+        if effect.erased.is_empty() && effect.writes.is_empty() {
+            return Err(SimError::NoOutput { time });
+        }
+        let mut next = board.clone();
+        for pos in effect.erased.iter() {
+            next.remove(pos);
+        }
+        // 読まれて消えたマスにも書き込める
+        for (pos, value) in effect.writes {
+            next.insert(pos, Cell::Value(value));
+        }
+        history.push(next);
+        max_time = max_time.max(history.len());
+    }
+    Err(SimError::TickLimitExceeded(max_ticks))
+}
+
+// 時刻 time の盤面の演算子を全て評価して、起きる変化を集める
+fn evaluate_tick(board: &Board, time: usize) -> Result<TickEffect, SimError> {
+    let value_at = |x: i64, y: i64| match board.get(&(x, y)) {
+        Some(Cell::Value(value)) => Some(value),
+        _ => None,
+    };
+
+    let mut effect = TickEffect::default();
+    let write = |effect: &mut TickEffect, (x, y): (i64, i64), value: BigInt| {
+        if let Some(Cell::Operator('S')) = board.get(&(x, y)) {
+            match &effect.submitted {
+                Some(prev) if *prev != value => return Err(SimError::SubmitConflict { time }),
+                _ => effect.submitted = Some(value),
+            }
+            return Ok(());
+        }
+        if effect.writes.insert((x, y), value).is_some() {
+            return Err(SimError::WriteConflict { x, y, time });
+        }
+        Ok(())
+    };
+
+    for (&(x, y), cell) in board.iter() {
+        let Cell::Operator(op) = *cell else {
+            continue;
+        };
+        match op {
+            // 値を 1 マス動かす (読む位置, 書く位置)
+            '<' | '>' | '^' | 'v' => {
+                let (from, to) = match op {
+                    '<' => ((x + 1, y), (x - 1, y)),
+                    '>' => ((x - 1, y), (x + 1, y)),
+                    '^' => ((x, y + 1), (x, y - 1)),
+                    _ => ((x, y - 1), (x, y + 1)),
+                };
+                if let Some(value) = value_at(from.0, from.1) {
+                    effect.erased.insert(from);
+                    write(&mut effect, to, value.clone())?;
+                }
+            }
+            // 左を x、上を y として、結果を右と下に書く
+            '+' | '-' | '*' | '/' | '%' | '=' | '#' => {
+                let (Some(lhs), Some(rhs)) = (value_at(x - 1, y), value_at(x, y - 1)) else {
+                    continue;
+                };
+                let (right, below) = match op {
+                    '+' => (lhs + rhs, lhs + rhs),
+                    '-' => (lhs - rhs, lhs - rhs),
+                    '*' => (lhs * rhs, lhs * rhs),
+                    '/' | '%' => {
+                        if *rhs == BigInt::ZERO {
+                            return Err(SimError::DivisionByZero { x, y, time });
+                        }
+                        // 0 方向への切り捨てと、被除数と同じ符号の余り
+                        let value = if op == '/' { lhs / rhs } else { lhs % rhs };
+                        (value.clone(), value)
+                    }
+                    // 条件を満たす時だけ、上の値を右に、左の値を下に流す
+                    '=' if lhs == rhs => (rhs.clone(), lhs.clone()),
+                    '#' if lhs != rhs => (rhs.clone(), lhs.clone()),
+                    _ => continue,
+                };
+                effect.erased.insert((x - 1, y));
+                effect.erased.insert((x, y - 1));
+                write(&mut effect, (x + 1, y), right)?;
+                write(&mut effect, (x, y + 1), below)?;
+            }
+            // 左が dx、右が dy、下が dt、上が書き込む値
+            '@' => {
+                let (Some(dx), Some(dy), Some(dt), Some(value)) = (
+                    value_at(x - 1, y),
+                    value_at(x + 1, y),
+                    value_at(x, y + 1),
+                    value_at(x, y - 1),
+                ) else {
+                    continue;
+                };
+                let invalid = || SimError::InvalidTimeWarp { x, y, time };
+                let dx = i64::try_from(dx).map_err(|_| invalid())?;
+                let dy = i64::try_from(dy).map_err(|_| invalid())?;
+                let dt = i64::try_from(dt).map_err(|_| invalid())?;
+                effect.warps.push(TimeWarp {
+                    from: (x, y),
+                    dt,
+                    to: (x - dx, y - dy),
+                    value: value.clone(),
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(effect)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(program: &str, a: i64, b: i64) -> Result<SimResult, SimError> {
+        let grid = parse_grid(program).unwrap();
+        simulate(&grid, BigInt::from(a), BigInt::from(b), 1000)
+    }
+
+    #[test]
+    fn test_add() {
+        let result = run(". A .\nB + S", 3, 4).unwrap();
+        assert_eq!(result.output, BigInt::from(7));
+        assert_eq!(result.ticks, 1);
+    }
+
+    #[test]
+    fn test_move_and_divide() {
+        // A を 2 マス右に動かしてから B で割る
+        let program = ". . . . . B .\nA > . > . / S";
+        let result = run(program, -7, 2).unwrap();
+        // 0 方向に切り捨てる
+        assert_eq!(result.output, BigInt::from(-3));
+        assert_eq!(result.ticks, 3);
+
+        let program = ". . . . . B .\nA > . > . % S";
+        assert_eq!(run(program, -7, 2).unwrap().output, BigInt::from(-1));
+        assert_eq!(
+            run(program, -7, 0),
+            Err(SimError::DivisionByZero {
+                x: 5,
+                y: 1,
+                time: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_time_warp_loop() {
+        // X = A が 0 でなければ 1 減らして、時刻 1 の X に書き戻す
+        // 0 になったら = が S に 0 を書く
+        let program = "\
+. . . 0 . .
+. . A # . .
+. 0 = . . .
+. . S v 1 .
+. . . . - .
+. . . . . .
+. . . 2 @ 5
+. . . . 3 .";
+        let result = run(program, 5, 0).unwrap();
+        assert_eq!(result.output, BigInt::from(0));
+        // 1 周 4 tick を 5 回繰り返した後、= の 1 tick で終わる
+        assert_eq!(result.ticks, 4 * 5 + 1);
+        assert_eq!(result.max_time, 4);
+    }
+
+    #[test]
+    fn test_dataset_solutions() {
+        // 提出して正解した解 (1: 階乗, 2: 絶対値, 3: 符号)
+        let cases = [
+            (
+                include_str!("../../../dataset/solution/3d/1.txt"),
+                vec![(5, 120), (1, 1), (10, 3628800)],
+            ),
+            (
+                include_str!("../../../dataset/solution/3d/2.txt"),
+                vec![(3, 3), (-6, 6), (0, 0)],
+            ),
+            (
+                include_str!("../../../dataset/solution/3d/3.txt"),
+                vec![(3, 1), (-6, -1), (0, 0)],
+            ),
+        ];
+        for (program, examples) in cases {
+            for (a, expected) in examples {
+                let result = run(program, a, 0).unwrap();
+                assert_eq!(result.output, BigInt::from(expected), "A = {}", a);
+            }
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        // 2 つの演算子が同じマスに書き込む
+        assert_eq!(
+            run("A > . < B", 1, 2),
+            Err(SimError::WriteConflict {
+                x: 2,
+                y: 0,
+                time: 1
+            })
+        );
+        // 何も動かない
+        assert_eq!(
+            run(". A .\n. + S", 1, 2),
+            Err(SimError::NoOutput { time: 1 })
+        );
+        // 時刻 1 より前に戻ろうとする
+        assert_eq!(
+            run(". A .\n0 @ 0\n. 5 .", 1, 2),
+            Err(SimError::InvalidTimeWarp {
+                x: 1,
+                y: 1,
+                time: 1
+            })
+        );
+        // 時刻 2 に、時刻 1 の盤面と同じ値を書き戻し続けて止まらない
+        let grid = parse_grid("1 > . .\n. 1 @ 0\n. . 1 .").unwrap();
+        assert_eq!(
+            simulate(&grid, BigInt::ZERO, BigInt::ZERO, 100),
+            Err(SimError::TickLimitExceeded(100))
+        );
+        assert_eq!(
+            parse_grid(". x"),
+            Err(SimError::InvalidCell {
+                x: 1,
+                y: 0,
+                token: "x".to_string()
+            })
+        );
+    }
+}
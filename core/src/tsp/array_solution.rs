@@ -1,3 +1,9 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+};
+
 use crate::tsp::solution::Solution;
 
 #[derive(Clone, Debug)]
@@ -35,6 +41,44 @@ impl ArraySolution {
         self.content.copy_from_slice(&other.content);
         self.index_of.copy_from_slice(&other.index_of);
     }
+
+    /// 訪問順に 1 行に 1 つずつ id を書き出す
+    pub fn save(&self, filepath: &PathBuf) -> io::Result<()> {
+        let f = File::create(filepath)?;
+        let mut writer = BufWriter::new(f);
+        for id in self.content.iter() {
+            writeln!(writer, "{}", id)?;
+        }
+        writer.flush()
+    }
+
+    /// save で書き出した解を読み込む。行数が次元になる
+    /// 数として読めない行があるか、0..行数 の順列になっていなければ InvalidData
+    pub fn load(filepath: &PathBuf) -> io::Result<ArraySolution> {
+        let content = fs::read_to_string(filepath)?
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                line.trim()
+                    .parse::<u32>()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut seen = vec![false; content.len()];
+        for &id in content.iter() {
+            match seen.get_mut(id as usize) {
+                Some(seen) if !*seen => *seen = true,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("not a permutation of 0..{}: {}", content.len(), id),
+                    ))
+                }
+            }
+        }
+        Ok(ArraySolution::from_array(content))
+    }
 }
 
 impl Solution for ArraySolution {
@@ -116,6 +160,37 @@ mod tests {
 
     use super::ArraySolution;
 
+    #[test]
+    fn test_save_load() {
+        let mut solution = ArraySolution::new(100);
+        solution.swap(20, 80);
+        solution.swap(5, 60);
+
+        let filepath = std::env::temp_dir().join("array_solution_save_load_test.txt");
+        solution.save(&filepath).unwrap();
+        let loaded = ArraySolution::load(&filepath).unwrap();
+        std::fs::remove_file(&filepath).unwrap();
+
+        assert_eq!(loaded.len(), solution.len());
+        for id in 0..solution.len() as u32 {
+            assert_eq!(loaded.next(id), solution.next(id));
+            assert_eq!(loaded.prev(id), solution.prev(id));
+        }
+    }
+
+    #[test]
+    fn test_load_invalid() {
+        let filepath = std::env::temp_dir().join("array_solution_load_invalid_test.txt");
+        for content in ["0\n1\nx\n", "0\n2\n", "1\n1\n0\n"] {
+            std::fs::write(&filepath, content).unwrap();
+            let err = ArraySolution::load(&filepath).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{:?}", content);
+        }
+        std::fs::remove_file(&filepath).unwrap();
+
+        assert!(ArraySolution::load(&filepath).is_err());
+    }
+
     #[test]
     fn test_solution_swap() {
         let dimension = 100;
@@ -159,4 +234,24 @@ mod tests {
         assert_eq!(solution.prev(81), 82);
         assert_eq!(solution.next(81), 80);
     }
+
+    #[test]
+    fn test_iter_order() {
+        let dimension = 100;
+        let mut solution = ArraySolution::new(dimension);
+        solution.swap(20, 80);
+        solution.swap(90, 10);
+
+        let order = solution.iter_order(42).collect::<Vec<_>>();
+        assert_eq!(order.len(), dimension);
+        assert_eq!(order[0], 42);
+        // 全ての都市をちょうど 1 回ずつ訪れる
+        let mut sorted = order.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..dimension as u32).collect::<Vec<_>>());
+        // 隣り合う要素は next で繋がっている
+        for i in 0..dimension {
+            assert_eq!(solution.next(order[i]), order[(i + 1) % dimension]);
+        }
+    }
 }
@@ -0,0 +1,79 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use crate::tsp::distance::DistanceFunction;
+
+// まだ計算していない組を表す値
+const UNKNOWN: i64 = i64::MIN;
+
+/// 計算した距離を n * n の表に覚えておく DistanceFunction
+/// 距離の計算が重い問題で、同じ組を何度も問い合わせる LKH などを速くする
+/// 表は n * n * 8 byte 使うので、大きな問題では使えない
+pub struct CachedDistance<D: DistanceFunction> {
+    inner: D,
+    n: usize,
+    // 並列に呼ばれても同じ値を書き込むだけなので、Relaxed で十分
+    table: Vec<AtomicI64>,
+}
+
+impl<D: DistanceFunction> CachedDistance<D> {
+    pub fn new(inner: D) -> CachedDistance<D> {
+        let n = inner.dimension() as usize;
+        let table = (0..n * n).map(|_| AtomicI64::new(UNKNOWN)).collect();
+        CachedDistance { inner, n, table }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+}
+
+impl<D: DistanceFunction> DistanceFunction for CachedDistance<D> {
+    fn distance(&self, id1: u32, id2: u32) -> i64 {
+        let cell = &self.table[id1 as usize * self.n + id2 as usize];
+        let cached = cell.load(Ordering::Relaxed);
+        if cached != UNKNOWN {
+            return cached;
+        }
+        let value = self.inner.distance(id1, id2);
+        cell.store(value, Ordering::Relaxed);
+        value
+    }
+
+    fn dimension(&self) -> u32 {
+        self.inner.dimension()
+    }
+
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+
+    fn is_symmetric(&self) -> bool {
+        self.inner.is_symmetric()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tsp::test_util::{CountingDistance, RandomPoints};
+
+    #[test]
+    fn test_same_as_inner() {
+        let point_list = vec![(0, 0), (3, 4), (-5, 12), (7, -1), (0, 0)];
+        let n = point_list.len() as u32;
+        let cached = CachedDistance::new(CountingDistance::new(RandomPoints { point_list }));
+        assert_eq!(cached.dimension(), n);
+        assert_eq!(cached.name(), "counting");
+
+        for _round in 0..3 {
+            for id1 in 0..n {
+                for id2 in 0..n {
+                    let expected = cached.inner().inner.distance(id1, id2);
+                    assert_eq!(cached.distance(id1, id2), expected);
+                }
+            }
+        }
+        // 2 周目以降は表から返すので、内側は各組 1 回しか呼ばれない
+        assert_eq!(cached.inner().count(), (n * n) as usize);
+    }
+}
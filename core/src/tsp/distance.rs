@@ -6,4 +6,11 @@ pub trait DistanceFunction {
     fn dimension(&self) -> u32;
 
     fn name(&self) -> String;
+
+    // distance(id1, id2) == distance(id2, id1) が常に成り立つか
+    // 2-opt / 3-opt の gain 計算はこれを前提にしている
+    // lkh::solve は false の場合、区間の反転まで含めて差分を計算する探索に切り替える
+    fn is_symmetric(&self) -> bool {
+        true
+    }
 }
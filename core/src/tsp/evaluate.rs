@@ -10,3 +10,103 @@ pub fn evaluate(distance: &impl DistanceFunction, solution: &impl Solution) -> i
     }
     sum
 }
+
+// tour の各辺を (from, to, 距離) で、0 から next を辿る順に返す
+// 距離の合計は evaluate と同じになる
+pub fn evaluate_edges(
+    distance: &impl DistanceFunction,
+    solution: &impl Solution,
+) -> Vec<(u32, u32, i64)> {
+    solution
+        .iter_order(0)
+        .map(|id| {
+            let next = solution.next(id);
+            (id, next, distance.distance(id, next))
+        })
+        .collect()
+}
+
+// 距離の長い順に n 本の辺を返す。同じ距離なら tour の順
+// LKH の結果が悪い時に、どこに長い辺が残っているかを調べるのに使う
+pub fn longest_edges(
+    distance: &impl DistanceFunction,
+    solution: &impl Solution,
+    n: usize,
+) -> Vec<(u32, u32, i64)> {
+    let mut edges = evaluate_edges(distance, solution);
+    edges.sort_by_key(|&(_, _, cost)| std::cmp::Reverse(cost));
+    edges.truncate(n);
+    edges
+}
+
+// 2-opt で辺 (a, b), (c, d) を (a, c), (b, d) につなぎ替えた時の長さの変化量
+// b = next(a), d = next(c) として、solution.swap(b, c) の前後の差分になる
+// 全体を計算し直さずに済むように、対称な距離でのみ使う
+// 非対称な距離では asymmetric_two_opt_delta を使う
+pub fn two_opt_delta(distance: &impl DistanceFunction, a: u32, b: u32, c: u32, d: u32) -> i64 {
+    distance.distance(a, c) + distance.distance(b, d)
+        - distance.distance(a, b)
+        - distance.distance(c, d)
+}
+
+// two_opt_delta の非対称な距離版
+// swap(b, c) で b から c までの区間が反転し、区間内の辺の向きも変わるので、その差分も足す
+// 区間の長さに比例する時間がかかる
+pub fn asymmetric_two_opt_delta(
+    distance: &impl DistanceFunction,
+    solution: &impl Solution,
+    a: u32,
+    b: u32,
+    c: u32,
+    d: u32,
+) -> i64 {
+    let mut delta = two_opt_delta(distance, a, b, c, d);
+    let mut id = b;
+    while id != c {
+        let next = solution.next(id);
+        delta += distance.distance(next, id) - distance.distance(id, next);
+        id = next;
+    }
+    delta
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tsp::{array_solution::ArraySolution, test_util::LineDistance};
+
+    #[test]
+    fn test_evaluate_edges() {
+        let distance = LineDistance {
+            point_list: vec![0, 10, 3, 7, 1, 20],
+        };
+        let mut solution = ArraySolution::new(6);
+        solution.swap(1, 3);
+
+        let edges = evaluate_edges(&distance, &solution);
+        assert_eq!(edges.len(), 6);
+        assert_eq!(
+            edges.iter().map(|&(_, _, cost)| cost).sum::<i64>(),
+            evaluate(&distance, &solution)
+        );
+        for &(from, to, cost) in edges.iter() {
+            assert_eq!(solution.next(from), to);
+            assert_eq!(distance.distance(from, to), cost);
+        }
+    }
+
+    #[test]
+    fn test_longest_edges() {
+        let distance = LineDistance {
+            point_list: vec![0, 10, 3, 7, 1, 20],
+        };
+        // 0 -> 1 -> 2 -> 3 -> 4 -> 5 -> 0
+        let solution = ArraySolution::new(6);
+
+        assert_eq!(
+            longest_edges(&distance, &solution, 3),
+            vec![(5, 0, 20), (4, 5, 19), (0, 1, 10)]
+        );
+        assert_eq!(longest_edges(&distance, &solution, 100).len(), 6);
+    }
+}
@@ -1,4 +1,4 @@
-use rand::{rngs::ThreadRng, Rng};
+use rand::Rng;
 
 pub struct IntSet {
     array: Vec<u32>,
@@ -63,7 +63,7 @@ impl IntSet {
         self.index -= 1;
     }
 
-    pub fn random_select(&mut self, rng: &mut ThreadRng) -> u32 {
+    pub fn random_select(&mut self, rng: &mut impl Rng) -> u32 {
         assert!(!self.is_empty());
         let index = rng.gen_range(0..self.index);
         self.array[index]
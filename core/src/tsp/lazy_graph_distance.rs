@@ -0,0 +1,312 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use crate::tsp::distance::DistanceFunction;
+
+// BFS で辿り着けなかった点
+const UNREACHABLE: u32 = u32::MAX;
+
+struct SourceCache {
+    // rows[s] は s からの BFS の結果
+    rows: Vec<Option<Arc<Vec<u32>>>>,
+    cached: usize,
+    // 次に追い出す候補を探し始める始点
+    hand: usize,
+}
+
+/// 重みなしの無向グラフ上の最短路長を、問い合わせがあった時に BFS で求める DistanceFunction
+/// max_cached_sources が点数以上なら、最初に全ての始点から BFS して n * n の表を作る
+/// そうでなければ、始点 max_cached_sources 個分の BFS の結果だけを覚えておき、溢れたら最近使われていないものを捨てる
+/// 無向なので、distance(a, b) は a と b のどちらの結果が残っていても答えられる
+/// 辿り着けない組の距離は i64::MAX
+pub struct LazyGraphDistance {
+    adjacency: Vec<Vec<u32>>,
+    max_cached_sources: usize,
+    // 全ての始点の BFS の結果。full[s * n + t] が s から t の距離
+    // 表がある時は lock を取らずにここから返す
+    full: Option<Vec<u32>>,
+    cache: RwLock<SourceCache>,
+    // clock 法で追い出すための、前回の追い出し以降に使われたかどうか
+    // 全体で共有する時刻を持たないので、並列に問い合わせても 1 つの atomic に書き込みが集中しない
+    referenced: Vec<AtomicBool>,
+}
+
+impl LazyGraphDistance {
+    // adjacency[i] は i に隣接する点のリスト。j が adjacency[i] にあれば i も adjacency[j] にある前提
+    pub fn new(adjacency: Vec<Vec<u32>>, max_cached_sources: usize) -> LazyGraphDistance {
+        let n = adjacency.len();
+        let mut distance = LazyGraphDistance {
+            adjacency,
+            max_cached_sources: max_cached_sources.max(1),
+            full: None,
+            cache: RwLock::new(SourceCache {
+                rows: vec![None; n],
+                cached: 0,
+                hand: 0,
+            }),
+            referenced: (0..n).map(|_| AtomicBool::new(false)).collect(),
+        };
+        if distance.max_cached_sources >= n {
+            let mut full = Vec::with_capacity(n * n);
+            for source in 0..n {
+                full.extend(distance.bfs(source as u32));
+            }
+            distance.full = Some(full);
+        }
+        distance
+    }
+
+    // 今覚えている BFS の結果の数
+    pub fn cached_sources(&self) -> usize {
+        if self.full.is_some() {
+            return self.adjacency.len();
+        }
+        self.cache.read().unwrap().cached
+    }
+
+    fn touch(&self, source: u32) {
+        // 既に立っていれば書き込まない (cache line を取り合わないように)
+        let referenced = &self.referenced[source as usize];
+        if !referenced.load(Ordering::Relaxed) {
+            referenced.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn bfs(&self, source: u32) -> Vec<u32> {
+        let mut row = vec![UNREACHABLE; self.adjacency.len()];
+        let mut queue = VecDeque::new();
+        row[source as usize] = 0;
+        queue.push_back(source);
+
+        while let Some(id) = queue.pop_front() {
+            let next_distance = row[id as usize] + 1;
+            for &next in self.adjacency[id as usize].iter() {
+                if row[next as usize] == UNREACHABLE {
+                    row[next as usize] = next_distance;
+                    queue.push_back(next);
+                }
+            }
+        }
+        row
+    }
+
+    // hand から順に覚えている始点を見て、前回から使われていないものを 1 つ捨てる
+    // 使われていたものは印を消して次に回すので、2 周以内に必ず見つかる
+    fn evict(&self, cache: &mut SourceCache) {
+        let n = cache.rows.len();
+        for step in 0..2 * n {
+            let s = (cache.hand + step) % n;
+            if cache.rows[s].is_none() || self.referenced[s].swap(false, Ordering::Relaxed) {
+                continue;
+            }
+            cache.rows[s] = None;
+            cache.cached -= 1;
+            cache.hand = (s + 1) % n;
+            return;
+        }
+    }
+}
+
+fn to_distance(d: u32) -> i64 {
+    if d == UNREACHABLE {
+        i64::MAX
+    } else {
+        d as i64
+    }
+}
+
+impl DistanceFunction for LazyGraphDistance {
+    fn distance(&self, id1: u32, id2: u32) -> i64 {
+        if let Some(full) = &self.full {
+            return to_distance(full[id1 as usize * self.adjacency.len() + id2 as usize]);
+        }
+        if id1 == id2 {
+            return 0;
+        }
+        {
+            let cache = self.cache.read().unwrap();
+            for (source, target) in [(id1, id2), (id2, id1)] {
+                if let Some(row) = &cache.rows[source as usize] {
+                    self.touch(source);
+                    return to_distance(row[target as usize]);
+                }
+            }
+        }
+
+        // BFS は lock の外で行う。並列に同じ始点の BFS が走っても、結果は同じなので先に入れた方を残す
+        let row = Arc::new(self.bfs(id1));
+        let d = row[id2 as usize];
+        {
+            let mut cache = self.cache.write().unwrap();
+            if cache.rows[id1 as usize].is_none() {
+                if cache.cached >= self.max_cached_sources {
+                    self.evict(&mut cache);
+                }
+                cache.rows[id1 as usize] = Some(row);
+                cache.cached += 1;
+            }
+        }
+        self.touch(id1);
+        to_distance(d)
+    }
+
+    fn dimension(&self) -> u32 {
+        self.adjacency.len() as u32
+    }
+
+    fn name(&self) -> String {
+        "lazy_graph".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    // 壁をランダムに置いたグリッドの、壁でないセルを点とするグラフ
+    fn random_grid_graph(height: usize, width: usize, seed: u64) -> Vec<Vec<u32>> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let open = (0..height)
+            .map(|_| (0..width).map(|_| rng.gen_bool(0.7)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        let mut id_table = vec![vec![None; width]; height];
+        let mut id = 0;
+        for y in 0..height {
+            for x in 0..width {
+                if open[y][x] {
+                    id_table[y][x] = Some(id);
+                    id += 1;
+                }
+            }
+        }
+
+        let mut adjacency = vec![vec![]; id as usize];
+        for y in 0..height {
+            for x in 0..width {
+                let Some(from) = id_table[y][x] else {
+                    continue;
+                };
+                for (ny, nx) in [(y + 1, x), (y, x + 1)] {
+                    if let Some(Some(to)) = id_table.get(ny).and_then(|row| row.get(nx)) {
+                        adjacency[from as usize].push(*to);
+                        adjacency[*to as usize].push(from);
+                    }
+                }
+            }
+        }
+        adjacency
+    }
+
+    // 全ての始点から BFS した表
+    fn eager_distance(adjacency: &[Vec<u32>]) -> Vec<Vec<i64>> {
+        let n = adjacency.len();
+        (0..n)
+            .map(|source| {
+                let mut row = vec![i64::MAX; n];
+                let mut queue = VecDeque::new();
+                row[source] = 0;
+                queue.push_back(source);
+                while let Some(id) = queue.pop_front() {
+                    for &next in adjacency[id].iter() {
+                        if row[next as usize] == i64::MAX {
+                            row[next as usize] = row[id] + 1;
+                            queue.push_back(next as usize);
+                        }
+                    }
+                }
+                row
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_same_as_eager() {
+        let adjacency = random_grid_graph(30, 40, 0);
+        let expected = eager_distance(&adjacency);
+        let n = adjacency.len() as u32;
+
+        let max_cached_sources = 8;
+        let lazy = LazyGraphDistance::new(adjacency, max_cached_sources);
+        assert_eq!(lazy.dimension(), n);
+
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..20000 {
+            let id1 = rng.gen_range(0..n);
+            let id2 = rng.gen_range(0..n);
+            assert_eq!(
+                lazy.distance(id1, id2),
+                expected[id1 as usize][id2 as usize]
+            );
+            assert!(lazy.cached_sources() <= max_cached_sources);
+        }
+        // 壁で分断された組も含まれている
+        assert!(expected.iter().flatten().any(|&d| d == i64::MAX));
+    }
+
+    #[test]
+    fn test_reuses_cached_source() {
+        let adjacency = random_grid_graph(10, 10, 2);
+        let n = adjacency.len() as u32;
+        let lazy = LazyGraphDistance::new(adjacency, 1);
+
+        for id2 in 0..n {
+            lazy.distance(0, id2);
+        }
+        // 逆向きの問い合わせも 0 からの結果で答える
+        for id1 in 0..n {
+            lazy.distance(id1, 0);
+        }
+        assert_eq!(lazy.cached_sources(), 1);
+        assert!(lazy.cache.read().unwrap().rows[0].is_some());
+    }
+
+    #[test]
+    fn test_full_table_when_fits() {
+        let adjacency = random_grid_graph(20, 20, 3);
+        let expected = eager_distance(&adjacency);
+        let n = adjacency.len() as u32;
+        let lazy = LazyGraphDistance::new(adjacency, n as usize);
+
+        // 問い合わせる前に全ての始点の結果ができていて、cache は使わない
+        assert_eq!(lazy.cached_sources(), n as usize);
+        for id1 in 0..n {
+            for id2 in 0..n {
+                assert_eq!(
+                    lazy.distance(id1, id2),
+                    expected[id1 as usize][id2 as usize]
+                );
+            }
+        }
+        assert_eq!(lazy.cache.read().unwrap().cached, 0);
+    }
+
+    #[test]
+    fn test_keeps_recently_used_source() {
+        let adjacency = random_grid_graph(10, 10, 4);
+        let target = adjacency.len() as u32 - 1;
+        let lazy = LazyGraphDistance::new(adjacency, 2);
+
+        lazy.distance(0, target);
+        lazy.distance(1, target);
+        // 溢れたので 1 つ捨てる。どちらも使われていたので印を消して一回りし、0 を捨てる
+        lazy.distance(2, target);
+        assert!(lazy.cache.read().unwrap().rows[0].is_none());
+
+        // 前回の追い出し以降に使われた 2 は残り、使われていない 1 が捨てられる
+        lazy.distance(2, target);
+        lazy.distance(3, target);
+        let cache = lazy.cache.read().unwrap();
+        assert_eq!(cache.cached, 2);
+        assert!(cache.rows[1].is_none());
+        assert!(cache.rows[2].is_some());
+        assert!(cache.rows[3].is_some());
+    }
+}
@@ -1,12 +1,67 @@
-use std::{path::PathBuf, time::Instant};
+use std::{
+    collections::VecDeque,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
-use rand::{rngs::ThreadRng, Rng};
+use rand::{rngs::StdRng, Rng};
 
 use crate::tsp::{
-    array_solution::ArraySolution, bitset::BitSet, distance::DistanceFunction, evaluate::evaluate,
-    intset::IntSet, neighbor_table::NeighborTable, segment_tree::SegmentTree, solution::Solution,
+    array_solution::ArraySolution,
+    bitset::BitSet,
+    distance::DistanceFunction,
+    evaluate::{asymmetric_two_opt_delta, evaluate, two_opt_delta},
+    intset::IntSet,
+    neighbor_table::NeighborTable,
+    new_rng,
+    segment_tree::SegmentTree,
+    solution::Solution,
+    two_level_tree_solution::TwoLeveltreeSolution,
 };
 
+/// n がこれを超えたら LKH の解を two-level doubly-linked list で持つ
+pub const DEFAULT_TWO_LEVEL_TREE_THRESHOLD: usize = 10_000;
+
+// LKH の本体で使う解の表現
+// 配列は swap が O(n) なので、n が大きい時は two-level doubly-linked list を使う
+trait LKHSolution: Solution + Clone {
+    fn from_array_solution(solution: &ArraySolution) -> Self;
+
+    fn copy_from(&mut self, other: &Self);
+
+    fn to_array_solution(&self) -> ArraySolution;
+}
+
+impl LKHSolution for ArraySolution {
+    fn from_array_solution(solution: &ArraySolution) -> Self {
+        solution.clone()
+    }
+
+    fn copy_from(&mut self, other: &Self) {
+        ArraySolution::copy_from(self, other);
+    }
+
+    fn to_array_solution(&self) -> ArraySolution {
+        self.clone()
+    }
+}
+
+impl<const N: usize> LKHSolution for TwoLeveltreeSolution<N> {
+    fn from_array_solution(solution: &ArraySolution) -> Self {
+        TwoLeveltreeSolution::new(solution)
+    }
+
+    fn copy_from(&mut self, other: &Self) {
+        self.clone_from(other);
+    }
+
+    fn to_array_solution(&self) -> ArraySolution {
+        TwoLeveltreeSolution::to_array_solution(self)
+    }
+}
+
 fn solve_inner<'a, T: Solution>(
     depth: usize,
     max_depth: usize,
@@ -18,7 +73,7 @@ fn solve_inner<'a, T: Solution>(
     gain: i64,
     best_gain: &mut i64,
     selected: &mut BitSet,
-    rng: &mut ThreadRng,
+    rng: &mut StdRng,
 ) {
     if depth == max_depth {
         // 評価して最も良いゲインのものを保存
@@ -44,7 +99,7 @@ fn solve_inner<'a, T: Solution>(
         t1: u32,
         f2: u32,
         t2: u32,
-        rng: &mut ThreadRng,
+        rng: &mut StdRng,
     ) {
         if selected.test(f2) || selected.test(t2) {
             return;
@@ -131,6 +186,46 @@ fn solve_inner<'a, T: Solution>(
     }
 }
 
+// kick で繋ぎ変えるエッジ数 (kick step) の増やし方
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KickSchedule {
+    // 停滞する度に kick_step_diff ずつ増やす
+    Linear,
+    // 停滞する度に factor 倍する
+    Geometric { factor: f64 },
+    // 停滞したら kick_step_diff 増やし、改善したら kick_step_diff 減らす
+    Adaptive,
+}
+
+impl KickSchedule {
+    // fail_count_threashold 回連続で改善しなかった時の次の kick step
+    fn on_plateau(&self, step: usize, config: &LKHConfig) -> usize {
+        let next = match *self {
+            KickSchedule::Linear | KickSchedule::Adaptive => step + config.kick_step_diff,
+            KickSchedule::Geometric { factor } => {
+                ((step as f64 * factor).ceil() as usize).max(step + 1)
+            }
+        };
+        next.min(config.end_kick_step).max(config.start_kick_step)
+    }
+
+    // 最良解が更新された時の次の kick step
+    fn on_improve(&self, step: usize, config: &LKHConfig) -> usize {
+        match *self {
+            KickSchedule::Linear | KickSchedule::Geometric { .. } => step,
+            KickSchedule::Adaptive => step
+                .saturating_sub(config.kick_step_diff)
+                .max(config.start_kick_step),
+        }
+    }
+}
+
+/// global best が改善する度に (評価値, 解) で呼ばれる
+/// num_threads > 1 の時は改善を見つけた thread から呼ばれるが、同時に呼ばれることは無く、評価値は狭義単調減少になる
+/// 設定を thread ごとに複製できるように Arc で持つ
+pub type ImprovementCallback = Arc<Mutex<dyn FnMut(i64, &ArraySolution) + Send>>;
+
+#[derive(Clone)]
 pub struct LKHConfig {
     pub use_neighbor_cache: bool,
     pub cache_filepath: PathBuf,
@@ -141,29 +236,408 @@ pub struct LKHConfig {
     pub end_kick_step: usize,
     pub fail_count_threashold: u32,
     pub max_depth: usize,
+    pub kick_schedule: KickSchedule,
+    // None なら OS の乱数を使う
+    pub seed: Option<u64>,
+    // Some の場合は time_ms を無視して、この回数だけ kick したら終了する
+    // 時間で打ち切ると実行速度で結果が変わるので、seed と合わせて使うと結果が再現できる
+    pub kick_limit: Option<usize>,
+    // 各点で改善の候補にする近傍の数
+    // cache を使う場合、既に cache があればその近傍数が使われる
+    pub neighbor_list_size: usize,
+    // 途中経過を保存したり表示したりするために、global best が改善する度に呼ぶ
+    pub on_improvement: Option<ImprovementCallback>,
+    // Some なら global best が改善する度にこのファイルへ保存する
+    // 起動時に次元の一致する解が保存されていれば、渡された初期解の代わりにそこから再開する
+    pub checkpoint_filepath: Option<PathBuf>,
+    // n がこれを超えたら、解を配列ではなく two-level doubly-linked list で持つ
+    // どちらでも探索の結果は同じで、速さだけが変わる
+    pub two_level_tree_threshold: usize,
+    // LKH の局所最適に達する度に、連続する 1 - 3 点を別の場所へ移す Or-opt も試す
+    pub use_or_opt: bool,
+    // 最良値がこの値以下になったら打ち切る (下界が分かっている時に使う)
+    pub target_eval: Option<i64>,
+    // 直近 fail_count_threashold 回の kick での最良値の改善率がこの値未満なら打ち切る
+    pub gap_stop: Option<f64>,
+    // 1 より大きければ、seed をずらした thread で並列に kick し、局所最適に達する度に最良解を共有する
+    // 0 番目以外の thread は他の thread の解に乗り換えるので、seed を指定しても結果は再現しない
+    // 非対称な距離では使わない
+    pub num_threads: usize,
+}
+
+impl LKHConfig {
+    // i 番目の thread 用の設定。callback と checkpoint は SharedBest が扱うので持たせない
+    fn worker(&self, i: usize) -> LKHConfig {
+        LKHConfig {
+            use_neighbor_cache: false,
+            debug: false,
+            seed: self.seed.map(|seed| seed.wrapping_add(i as u64)),
+            on_improvement: None,
+            checkpoint_filepath: None,
+            num_threads: 1,
+            ..self.clone()
+        }
+    }
+}
+
+// 並列に探索する時の、各 thread と最良解の関わり方
+#[derive(Clone, Copy)]
+struct Sharing<'a> {
+    best: &'a SharedBest,
+    // 他の thread の方が良い解を見つけていたら乗り換えるか
+    adopt: bool,
+}
+
+// 並列に探索する thread 間で共有する最良解
+// どの thread が見つけた改善でも、checkpoint の保存と callback の呼び出しはここで行う
+struct SharedBest {
+    best: Mutex<(i64, ArraySolution)>,
+    checkpoint_filepath: Option<PathBuf>,
+    on_improvement: Option<ImprovementCallback>,
+}
+
+impl SharedBest {
+    // config の callback と checkpoint を引き取る
+    fn new(eval: i64, solution: ArraySolution, config: &mut LKHConfig) -> SharedBest {
+        SharedBest {
+            best: Mutex::new((eval, solution)),
+            checkpoint_filepath: config.checkpoint_filepath.take(),
+            on_improvement: config.on_improvement.take(),
+        }
+    }
+
+    // 共有している解より良ければ置き換えて、checkpoint の保存と callback の呼び出しを行う
+    // lock を持ったまま呼ぶので、呼ばれる順に評価値が小さくなる
+    fn offer(&self, eval: i64, solution: &impl LKHSolution) {
+        let mut best = self.best.lock().unwrap();
+        if eval < best.0 {
+            *best = (eval, solution.to_array_solution());
+            notify(
+                self.checkpoint_filepath.as_ref(),
+                self.on_improvement.as_ref(),
+                eval,
+                &best.1,
+            );
+        }
+    }
+
+    // 共有している解の方が良ければ、その評価値と解を返す
+    // 0 番目の thread は乗り換えずに 1 thread の時と同じ探索をするので、kick の回数を揃えれば悪くならない
+    fn take_if_better(&self, eval: i64) -> Option<(i64, ArraySolution)> {
+        let best = self.best.lock().unwrap();
+        if best.0 < eval {
+            Some((best.0, best.1.clone()))
+        } else {
+            None
+        }
+    }
+}
+
+// 前回の checkpoint が使えるならそれを返す
+// 読めない場合や 0..n の順列でない場合は、checkpoint が無いものとして扱う
+fn load_checkpoint(filepath: &PathBuf, n: usize) -> Option<ArraySolution> {
+    if !filepath.exists() {
+        return None;
+    }
+    match ArraySolution::load(filepath) {
+        Ok(solution) if solution.len() == n => Some(solution),
+        Ok(solution) => {
+            eprintln!(
+                "ignore checkpoint {:?}: dimension {} != {}",
+                filepath,
+                solution.len(),
+                n
+            );
+            None
+        }
+        Err(e) => {
+            eprintln!("ignore checkpoint {:?}: {}", filepath, e);
+            None
+        }
+    }
+}
+
+// global best が改善した時に、checkpoint の保存と callback の呼び出しを行う
+// どちらも無ければ配列への変換もしない
+// num_threads > 1 の時は config から SharedBest に移しているので、ここでは何もしない
+fn notify_improvement(config: &LKHConfig, eval: i64, solution: &impl LKHSolution) {
+    if config.checkpoint_filepath.is_none() && config.on_improvement.is_none() {
+        return;
+    }
+    notify(
+        config.checkpoint_filepath.as_ref(),
+        config.on_improvement.as_ref(),
+        eval,
+        &solution.to_array_solution(),
+    );
+}
+
+fn notify(
+    checkpoint_filepath: Option<&PathBuf>,
+    on_improvement: Option<&ImprovementCallback>,
+    eval: i64,
+    solution: &ArraySolution,
+) {
+    if let Some(filepath) = checkpoint_filepath {
+        save_checkpoint(filepath, solution);
+    }
+    if let Some(on_improvement) = on_improvement {
+        (on_improvement.lock().unwrap())(eval, solution);
+    }
+}
+
+// 探索を打ち切るかの判定
+struct StopCondition {
+    start: Instant,
+    kick_count: usize,
+    // gap_stop のために、直近の kick の後の最良値を覚えておく
+    history: VecDeque<i64>,
+}
+
+impl StopCondition {
+    fn new(start: Instant) -> StopCondition {
+        StopCondition {
+            start,
+            kick_count: 0,
+            history: VecDeque::new(),
+        }
+    }
+
+    // kick を 1 回終える度に呼び、打ち切るなら true を返す
+    // 最良値が target_eval に達したか、改善率が gap_stop を下回ったか、
+    // kick_limit があれば kick の回数、無ければ経過時間が上限に達したら打ち切る
+    fn on_kick(&mut self, config: &LKHConfig, global_best_eval: i64) -> bool {
+        self.kick_count += 1;
+
+        if config
+            .target_eval
+            .is_some_and(|target| global_best_eval <= target)
+        {
+            return true;
+        }
+
+        if let Some(gap) = config.gap_stop {
+            self.history.push_back(global_best_eval);
+            let window = config.fail_count_threashold as usize;
+            if self.history.len() > window {
+                let old = self.history.pop_front().unwrap();
+                let improvement = (old - global_best_eval) as f64 / old.max(1) as f64;
+                if improvement < gap {
+                    return true;
+                }
+            }
+        }
+
+        match config.kick_limit {
+            Some(kick_limit) => self.kick_count >= kick_limit,
+            None => self.start.elapsed().as_millis() > config.time_ms,
+        }
+    }
+}
+
+// 書き込み中に kill されても前回の checkpoint が壊れないように、一時ファイルに書いてから置き換える
+// 保存に失敗しても探索は続ける
+fn save_checkpoint(filepath: &PathBuf, solution: &ArraySolution) {
+    let tmp_filepath = filepath.with_extension("tmp");
+    if let Err(e) = solution
+        .save(&tmp_filepath)
+        .and_then(|_| fs::rename(&tmp_filepath, filepath))
+    {
+        eprintln!("failed to save checkpoint {:?}: {}", filepath, e);
+    }
 }
 
 pub fn solve(
     distance: &(impl DistanceFunction + std::marker::Sync),
     mut solution: ArraySolution,
-    config: LKHConfig,
+    mut config: LKHConfig,
 ) -> ArraySolution {
     let n = distance.dimension() as usize;
     // 解く
 
     let start = Instant::now();
 
-    let neighbor_table = if config.use_neighbor_cache && config.cache_filepath.exists() {
-        NeighborTable::load(&config.cache_filepath)
+    if let Some(checkpoint) = config
+        .checkpoint_filepath
+        .as_ref()
+        .and_then(|filepath| load_checkpoint(filepath, n))
+    {
+        solution = checkpoint;
+    }
+
+    // 非対称な距離だと gain の計算が間違ったまま探索が進んでしまうので、別の探索で解く
+    if !distance.is_symmetric() {
+        return solve_asymmetric(distance, solution, config, start);
+    }
+
+    let neighbor_table = NeighborTable::load_or_new(
+        distance,
+        config.neighbor_list_size,
+        config.use_neighbor_cache,
+        &config.cache_filepath,
+    );
+
+    if config.num_threads <= 1 {
+        return solve_with_backend(distance, solution, config, start, &neighbor_table, None);
+    }
+
+    let shared = SharedBest::new(evaluate(distance, &solution), solution.clone(), &mut config);
+    let worker_config_list = (1..config.num_threads)
+        .map(|i| config.worker(i))
+        .collect::<Vec<_>>();
+    std::thread::scope(|scope| {
+        for worker_config in worker_config_list {
+            let (solution, shared, neighbor_table) = (solution.clone(), &shared, &neighbor_table);
+            let sharing = Sharing {
+                best: shared,
+                adopt: true,
+            };
+            scope.spawn(move || {
+                solve_with_backend(
+                    distance,
+                    solution,
+                    worker_config,
+                    start,
+                    neighbor_table,
+                    Some(sharing),
+                )
+            });
+        }
+        // 0 番目の thread は呼び出し元で動かす
+        let sharing = Sharing {
+            best: &shared,
+            adopt: false,
+        };
+        solve_with_backend(
+            distance,
+            solution,
+            config,
+            start,
+            &neighbor_table,
+            Some(sharing),
+        );
+    });
+    // 各 thread は改善する度に共有しているので、共有している解が全体の最良解になる
+    shared.best.into_inner().unwrap().1
+}
+
+// n に応じて解の表現を選んで解く
+fn solve_with_backend(
+    distance: &(impl DistanceFunction + std::marker::Sync),
+    solution: ArraySolution,
+    config: LKHConfig,
+    start: Instant,
+    neighbor_table: &NeighborTable,
+    sharing: Option<Sharing>,
+) -> ArraySolution {
+    if solution.len() > config.two_level_tree_threshold {
+        let solution = TwoLeveltreeSolution::<1000>::new(&solution);
+        solve_symmetric(distance, solution, config, start, neighbor_table, sharing)
+            .to_array_solution()
+    } else {
+        solve_symmetric(distance, solution, config, start, neighbor_table, sharing)
+    }
+}
+
+// Or-opt で移す区間の最大の長さ
+const OR_OPT_MAX_LENGTH: usize = 3;
+
+// s1 から始まる length 点の区間を、辺 (x, next(x)) の間に移した時の (gain, 区間を反転するか)
+// 区間の前後を p, n2 として、(p, s1), (s2, n2), (x, y) を切って (p, n2) と x - 区間 - y をつなぐ
+fn or_opt_gain(
+    distance: &impl DistanceFunction,
+    (p, s1, s2, n2): (u32, u32, u32, u32),
+    (x, y): (u32, u32),
+) -> (i64, bool) {
+    let removed = distance.distance(p, s1) + distance.distance(s2, n2) + distance.distance(x, y);
+    let base = removed - distance.distance(p, n2);
+    let forward = base - distance.distance(x, s1) - distance.distance(s2, y);
+    let reversed = base - distance.distance(x, s2) - distance.distance(s1, y);
+    if reversed > forward {
+        (reversed, true)
     } else {
-        let table = NeighborTable::new(distance, 5);
-        if config.use_neighbor_cache {
-            table.save(&config.cache_filepath);
+        (forward, false)
+    }
+}
+
+// 区間 [s1, s2] を x の直後に移す
+// p [s1..s2] n2 .. x y を、s1 から x までの反転と n2 から x までの反転で p n2 .. x [s2..s1] y にする
+// 向きを保つ場合は、最後に区間をもう一度反転する
+fn apply_or_opt(solution: &mut impl Solution, s1: u32, s2: u32, n2: u32, x: u32, reversed: bool) {
+    solution.swap(s1, x);
+    if x != n2 {
+        solution.swap(x, n2);
+    }
+    if !reversed && s1 != s2 {
+        solution.swap(s2, s1);
+    }
+}
+
+// 改善する Or-opt が無くなるまで、見つけた順に適用する。減った長さの合計を返す
+// 挿入先は区間の端点の近傍 c について、辺 (c, next(c)) と (prev(c), c) を調べる
+fn or_opt(
+    distance: &impl DistanceFunction,
+    neighbor_table: &NeighborTable,
+    solution: &mut impl Solution,
+) -> i64 {
+    let n = solution.len();
+    if n < OR_OPT_MAX_LENGTH + 3 {
+        return 0;
+    }
+
+    let mut total_gain = 0;
+    let mut improved = true;
+    while improved {
+        improved = false;
+        for s1 in 0..n as u32 {
+            for length in 1..=OR_OPT_MAX_LENGTH {
+                let mut segment = vec![s1];
+                for _ in 1..length {
+                    segment.push(solution.next(*segment.last().unwrap()));
+                }
+                let s2 = *segment.last().unwrap();
+                let p = solution.prev(s1);
+                let n2 = solution.next(s2);
+
+                let mut best: Option<(i64, u32, bool)> = None;
+                for &end in [s1, s2].iter() {
+                    for &c in neighbor_table.neighbor_list(end) {
+                        for x in [c, solution.prev(c)] {
+                            if x == p || segment.contains(&x) {
+                                continue;
+                            }
+                            let y = solution.next(x);
+                            let (gain, reversed) = or_opt_gain(distance, (p, s1, s2, n2), (x, y));
+                            if gain > best.map_or(0, |(best_gain, _, _)| best_gain) {
+                                best = Some((gain, x, reversed));
+                            }
+                        }
+                    }
+                }
+
+                if let Some((gain, x, reversed)) = best {
+                    apply_or_opt(solution, s1, s2, n2, x, reversed);
+                    total_gain += gain;
+                    improved = true;
+                    break;
+                }
+            }
         }
-        table
-    };
+    }
+    total_gain
+}
 
-    let mut rng = rand::thread_rng();
+fn solve_symmetric<S: LKHSolution>(
+    distance: &(impl DistanceFunction + std::marker::Sync),
+    mut solution: S,
+    config: LKHConfig,
+    start: Instant,
+    neighbor_table: &NeighborTable,
+    sharing: Option<Sharing>,
+) -> S {
+    let n = solution.len();
+
+    let mut rng = new_rng(config.seed);
 
     let mut dlb = IntSet::new(n);
     dlb.set_all();
@@ -176,6 +650,7 @@ pub fn solve(
 
     let mut no_random_step = config.start_kick_step;
     let mut no_continuous_fail_count = 0;
+    let mut stop_condition = StopCondition::new(start);
 
     for iter in 0.. {
         let a = dlb.random_select(&mut rng);
@@ -204,7 +679,7 @@ pub fn solve(
                         1,
                         max_depth,
                         distance,
-                        &neighbor_table,
+                        neighbor_table,
                         &mut current_tree,
                         &mut best_tree,
                         &mut edge_stack,
@@ -242,6 +717,11 @@ pub fn solve(
         }
 
         if dlb.is_empty() {
+            if config.use_or_opt {
+                eval -= or_opt(distance, neighbor_table, &mut solution);
+                debug_assert_eq!(eval, evaluate(distance, &solution));
+            }
+
             if config.debug {
                 eprintln!("-----");
                 eprintln!(
@@ -256,14 +736,32 @@ pub fn solve(
             if global_best_eval > eval {
                 global_best_eval = eval;
                 global_best_solution.copy_from(&solution);
+                notify_improvement(&config, global_best_eval, &global_best_solution);
+                if let Some(sharing) = sharing {
+                    sharing.best.offer(global_best_eval, &global_best_solution);
+                }
                 no_continuous_fail_count = 0;
+                no_random_step = config.kick_schedule.on_improve(no_random_step, &config);
             } else {
                 solution.copy_from(&global_best_solution);
+                eval = global_best_eval;
                 no_continuous_fail_count += 1;
             }
 
+            // 他の thread がより良い解を見つけていたら、そこから kick する
+            if let Some((shared_eval, shared_solution)) = sharing
+                .filter(|sharing| sharing.adopt)
+                .and_then(|sharing| sharing.best.take_if_better(global_best_eval))
+            {
+                global_best_eval = shared_eval;
+                global_best_solution = S::from_array_solution(&shared_solution);
+                solution.copy_from(&global_best_solution);
+                eval = global_best_eval;
+                notify_improvement(&config, global_best_eval, &global_best_solution);
+            }
+
             if no_continuous_fail_count == config.fail_count_threashold {
-                no_random_step = (config.end_kick_step).min(no_random_step + config.kick_step_diff);
+                no_random_step = config.kick_schedule.on_plateau(no_random_step, &config);
                 no_continuous_fail_count = 0;
             }
 
@@ -320,6 +818,10 @@ pub fn solve(
                     selected.set(c);
                     selected.set(d);
 
+                    // a は近傍を辿って選び直されることがあるので、b の直前とは限らない
+                    // swap(b, c) で実際に切れる辺 (prev(b), b), (c, next(c)) で差分を取る
+                    let (b_prev, c_next) = (solution.prev(b), solution.next(c));
+                    eval += two_opt_delta(distance, b_prev, b, c, c_next);
                     solution.swap(b, c);
                     for id in [a, b, c, d] {
                         dlb.push(id);
@@ -329,10 +831,10 @@ pub fn solve(
                     break;
                 }
             }
-            eval = evaluate(distance, &solution);
+            // kick の差分だけで eval を更新しているので、debug ビルドでは全体の再計算と比べる
+            debug_assert_eq!(eval, evaluate(distance, &solution));
 
-            let end = Instant::now();
-            if (end - start).as_millis() > config.time_ms {
+            if stop_condition.on_kick(&config, global_best_eval) {
                 break;
             }
         }
@@ -344,3 +846,647 @@ pub fn solve(
     }
     global_best_solution
 }
+
+// 非対称な距離用の探索
+// LKH の gain は対称性を前提にしているので使わず、反転する区間の向きの変化も含めて差分を計算する 2-opt と、
+// ランダムな 2-opt の kick を繰り返す。差分の計算に区間の長さ分の時間がかかるので、小さい問題向け
+fn solve_asymmetric(
+    distance: &(impl DistanceFunction + std::marker::Sync),
+    mut solution: ArraySolution,
+    config: LKHConfig,
+    start: Instant,
+) -> ArraySolution {
+    let n = solution.len();
+    if n < 4 {
+        return solution;
+    }
+
+    let neighbor_table = NeighborTable::load_or_new(
+        distance,
+        config.neighbor_list_size.min(n - 1),
+        config.use_neighbor_cache,
+        &config.cache_filepath,
+    );
+    let mut rng = new_rng(config.seed);
+
+    let mut eval = evaluate(distance, &solution);
+    let mut global_best_eval = eval;
+    let mut global_best_solution = solution.clone();
+
+    let mut stop_condition = StopCondition::new(start);
+    loop {
+        // 改善する 2-opt が無くなるまで、見つけた順に適用する
+        let mut improved = true;
+        while improved {
+            improved = false;
+            for a in 0..n as u32 {
+                let b = solution.next(a);
+                for &c in neighbor_table.neighbor_list(a) {
+                    let d = solution.next(c);
+                    if c == b || d == a {
+                        continue;
+                    }
+                    let delta = asymmetric_two_opt_delta(distance, &solution, a, b, c, d);
+                    if delta < 0 {
+                        solution.swap(b, c);
+                        eval += delta;
+                        improved = true;
+                        break;
+                    }
+                }
+            }
+        }
+        debug_assert_eq!(eval, evaluate(distance, &solution));
+
+        if global_best_eval > eval {
+            global_best_eval = eval;
+            global_best_solution.copy_from(&solution);
+            notify_improvement(&config, global_best_eval, &global_best_solution);
+        } else {
+            solution.copy_from(&global_best_solution);
+            eval = global_best_eval;
+        }
+        if config.debug {
+            eprintln!(
+                "kick: {}, best eval: {}",
+                stop_condition.kick_count, global_best_eval
+            );
+        }
+
+        // random 2-opt kick
+        for _step in 0..config.start_kick_step {
+            let a = rng.gen_range(0..n as u32);
+            let c = rng.gen_range(0..n as u32);
+            let (b, d) = (solution.next(a), solution.next(c));
+            if c == a || c == b || d == a {
+                continue;
+            }
+            eval += asymmetric_two_opt_delta(distance, &solution, a, b, c, d);
+            solution.swap(b, c);
+        }
+
+        if stop_condition.on_kick(&config, global_best_eval) {
+            break;
+        }
+    }
+    global_best_solution
+}
+
+// max_depth を変えながら同じ初期解から LKH を実行し、(max_depth, 評価値, 解) を返す
+// 時間などの条件を揃えるために、config は max_depth から作ってもらう
+pub fn sweep_depth(
+    distance: &(impl DistanceFunction + std::marker::Sync),
+    solution: &ArraySolution,
+    depth_list: &[usize],
+    config_of: impl Fn(usize) -> LKHConfig,
+) -> Vec<(usize, i64, ArraySolution)> {
+    depth_list
+        .iter()
+        .map(|&max_depth| {
+            let config = LKHConfig {
+                max_depth,
+                ..config_of(max_depth)
+            };
+            let result = solve(distance, solution.clone(), config);
+            let eval = evaluate(distance, &result);
+            (max_depth, eval, result)
+        })
+        .collect()
+}
+
+// sweep_depth の結果を max_depth と評価値の表にして標準エラーに出力し、最も良い解を返す
+// depth_list が空なら solution をそのまま返す
+pub fn sweep_depth_best(
+    distance: &(impl DistanceFunction + std::marker::Sync),
+    solution: &ArraySolution,
+    depth_list: &[usize],
+    config_of: impl Fn(usize) -> LKHConfig,
+) -> ArraySolution {
+    let rows = sweep_depth(distance, solution, depth_list, config_of);
+    eprintln!("max_depth | eval");
+    for (max_depth, eval, _) in rows.iter() {
+        eprintln!("{:>9} | {}", max_depth, eval);
+    }
+    rows.into_iter()
+        .min_by_key(|(_, eval, _)| *eval)
+        .map_or_else(|| solution.clone(), |(_, _, solution)| solution)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    use super::*;
+    use crate::tsp::{test_util::RandomPoints, DEFAULT_NEIGHBOR_LIST_SIZE};
+
+    fn default_config(n: usize) -> LKHConfig {
+        LKHConfig {
+            use_neighbor_cache: false,
+            cache_filepath: PathBuf::new(),
+            debug: false,
+            time_ms: 100,
+            start_kick_step: 5,
+            kick_step_diff: 10,
+            end_kick_step: n / 10,
+            fail_count_threashold: 50,
+            max_depth: 6,
+            kick_schedule: KickSchedule::Linear,
+            seed: None,
+            kick_limit: None,
+            neighbor_list_size: DEFAULT_NEIGHBOR_LIST_SIZE,
+            on_improvement: None,
+            checkpoint_filepath: None,
+            two_level_tree_threshold: DEFAULT_TWO_LEVEL_TREE_THRESHOLD,
+            use_or_opt: false,
+            target_eval: None,
+            gap_stop: None,
+            num_threads: 1,
+        }
+    }
+
+    fn assert_valid_tour(solution: &impl Solution) {
+        let mut visited = vec![false; solution.len()];
+        let mut id = 0;
+        for _iter in 0..solution.len() {
+            assert!(!visited[id as usize]);
+            visited[id as usize] = true;
+            id = solution.next(id);
+        }
+        assert_eq!(id, 0);
+        assert!(visited.iter().all(|&v| v));
+    }
+
+    #[test]
+    fn test_two_opt_delta() {
+        let n = 50;
+        let distance = RandomPoints::new(n, 1);
+        let mut solution = ArraySolution::new(n);
+        let mut rng = StdRng::seed_from_u64(1);
+
+        let mut eval = evaluate(&distance, &solution);
+        for _iter in 0..200 {
+            let a = rng.gen_range(0..n as u32);
+            let b = solution.next(a);
+            let c = rng.gen_range(0..n as u32);
+            let d = solution.next(c);
+            if [b, c, d].contains(&a) || c == b {
+                continue;
+            }
+            eval += two_opt_delta(&distance, a, b, c, d);
+            solution.swap(b, c);
+            assert_eq!(eval, evaluate(&distance, &solution));
+        }
+        assert_valid_tour(&solution);
+    }
+
+    #[test]
+    fn test_larger_neighbor_list() {
+        let n = 100;
+        // 1 つの seed だと近傍 5 の方が運良く良い解になることもあるので、複数の seed の合計で比べる
+        let (mut total5, mut total10) = (0, 0);
+        for seed in 0..5 {
+            let distance = RandomPoints::new(n, seed);
+            // kick の回数と seed を揃えて、近傍数だけを変える
+            let eval_of = |neighbor_list_size| {
+                let config = LKHConfig {
+                    seed: Some(seed),
+                    kick_limit: Some(100),
+                    neighbor_list_size,
+                    // 近傍 10 で深く探索すると debug ビルドでは遅いので浅くする
+                    max_depth: 4,
+                    ..default_config(n)
+                };
+                let result = solve(&distance, ArraySolution::new(n), config);
+                assert_valid_tour(&result);
+                evaluate(&distance, &result)
+            };
+            total5 += eval_of(5);
+            total10 += eval_of(10);
+        }
+        assert!(total10 <= total5, "{} > {}", total10, total5);
+    }
+
+    #[test]
+    fn test_same_seed_same_tour() {
+        let n = 200;
+        let distance = RandomPoints::new(n, 2);
+        let solve_with = |seed| {
+            let config = LKHConfig {
+                seed: Some(seed),
+                kick_limit: Some(30),
+                ..default_config(n)
+            };
+            let result = solve(&distance, ArraySolution::new(n), config);
+            let tour = (0..n as u32).map(|id| result.next(id)).collect::<Vec<_>>();
+            (evaluate(&distance, &result), tour)
+        };
+
+        // solve は global best を返すので、評価値と巡回路の両方が一致する
+        let first = solve_with(7);
+        let second = solve_with(7);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_on_improvement() {
+        let n = 200;
+        let distance = RandomPoints::new(n, 3);
+        let init_eval = evaluate(&distance, &ArraySolution::new(n));
+
+        // 複数 thread の時も、どの thread の改善でも呼ばれ、最後の値が返り値と一致する
+        for num_threads in [1, 4] {
+            // callback は 'static + Send なので、呼ばれた評価値は Arc 越しに記録する
+            let history = Arc::new(Mutex::new(vec![]));
+            let history_in_callback = history.clone();
+            let config = LKHConfig {
+                seed: Some(0),
+                kick_limit: Some(30),
+                on_improvement: Some(Arc::new(Mutex::new(
+                    move |eval, solution: &ArraySolution| {
+                        assert_valid_tour(solution);
+                        history_in_callback.lock().unwrap().push(eval);
+                    },
+                ))),
+                num_threads,
+                ..default_config(n)
+            };
+            let result = solve(&distance, ArraySolution::new(n), config);
+
+            let history = history.lock().unwrap();
+            assert!(!history.is_empty());
+            assert!(history[0] < init_eval);
+            // 改善した時だけ呼ばれるので狭義単調減少で、最後の値が返り値の評価値になる
+            assert!(history.windows(2).all(|w| w[1] < w[0]));
+            assert_eq!(
+                *history.last().unwrap(),
+                evaluate(&distance, &result),
+                "num_threads: {}",
+                num_threads
+            );
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_resume() {
+        let n = 200;
+        let distance = RandomPoints::new(n, 4);
+        let filepath = std::env::temp_dir().join("lkh_checkpoint_resume_test.txt");
+        let _ = fs::remove_file(&filepath);
+
+        let config_of = || LKHConfig {
+            seed: Some(0),
+            kick_limit: Some(20),
+            checkpoint_filepath: Some(filepath.clone()),
+            ..default_config(n)
+        };
+        let first = solve(&distance, ArraySolution::new(n), config_of());
+        let first_eval = evaluate(&distance, &first);
+        // 最後に保存されたのは返り値と同じ global best
+        assert_eq!(
+            evaluate(&distance, &ArraySolution::load(&filepath).unwrap()),
+            first_eval
+        );
+
+        // 複数 thread でも、保存されるのは全 thread を通した global best
+        let multi = solve(
+            &distance,
+            ArraySolution::new(n),
+            LKHConfig {
+                num_threads: 4,
+                ..config_of()
+            },
+        );
+        assert_eq!(
+            evaluate(&distance, &ArraySolution::load(&filepath).unwrap()),
+            evaluate(&distance, &multi)
+        );
+
+        // 再開すると checkpoint から始まるので、悪くならない
+        let second = solve(&distance, ArraySolution::new(n), config_of());
+        assert!(evaluate(&distance, &second) <= first_eval);
+
+        // 次元が違う checkpoint は無視する
+        let small = RandomPoints::new(50, 4);
+        let config = LKHConfig {
+            checkpoint_filepath: Some(filepath.clone()),
+            ..default_config(50)
+        };
+        assert_valid_tour(&solve(&small, ArraySolution::new(50), config));
+
+        // 順列になっていない checkpoint や、数として読めない checkpoint も無視する
+        for content in ["0\n".repeat(50), "x\n".to_string()] {
+            fs::write(&filepath, content).unwrap();
+            let config = LKHConfig {
+                checkpoint_filepath: Some(filepath.clone()),
+                kick_limit: Some(1),
+                ..default_config(50)
+            };
+            assert_valid_tour(&solve(&small, ArraySolution::new(50), config));
+        }
+        fs::remove_file(&filepath).unwrap();
+    }
+
+    #[test]
+    fn test_two_level_tree_backend() {
+        let n = 300;
+        let distance = RandomPoints::new(n, 5);
+        let solve_with = |two_level_tree_threshold| {
+            let config = LKHConfig {
+                seed: Some(1),
+                kick_limit: Some(30),
+                two_level_tree_threshold,
+                ..default_config(n)
+            };
+            let result = solve(&distance, ArraySolution::new(n), config);
+            assert_valid_tour(&result);
+            (0..n as u32).map(|id| result.next(id)).collect::<Vec<_>>()
+        };
+
+        // 表現が違っても next / prev は同じなので、同じ seed なら同じ巡回路になる
+        let array = solve_with(usize::MAX);
+        let two_level_tree = solve_with(0);
+        assert_eq!(array, two_level_tree);
+    }
+
+    #[test]
+    fn test_apply_or_opt() {
+        let n = 40;
+        let distance = RandomPoints::new(n, 6);
+        let mut rng = StdRng::seed_from_u64(6);
+        for _iter in 0..300 {
+            let mut solution = ArraySolution::new(n);
+            let s1 = rng.gen_range(0..n as u32);
+            let length = rng.gen_range(1..=OR_OPT_MAX_LENGTH);
+            let mut segment = vec![s1];
+            for _ in 1..length {
+                segment.push(solution.next(*segment.last().unwrap()));
+            }
+            let s2 = *segment.last().unwrap();
+            let (p, n2) = (solution.prev(s1), solution.next(s2));
+            let x = rng.gen_range(0..n as u32);
+            if x == p || segment.contains(&x) {
+                continue;
+            }
+            let y = solution.next(x);
+
+            let before = evaluate(&distance, &solution);
+            let (gain, reversed) = or_opt_gain(&distance, (p, s1, s2, n2), (x, y));
+            apply_or_opt(&mut solution, s1, s2, n2, x, reversed);
+            assert_valid_tour(&solution);
+            assert_eq!(before - gain, evaluate(&distance, &solution));
+
+            // 区間が x と y の間に入っている
+            let (first, last) = if reversed { (s2, s1) } else { (s1, s2) };
+            assert_eq!(solution.next(x), first);
+            assert_eq!(solution.prev(y), last);
+        }
+    }
+
+    #[test]
+    fn test_or_opt() {
+        let n = 200;
+        let mut total = [0, 0];
+        for seed in 0..5 {
+            let distance = RandomPoints::new(n, seed);
+            for (i, use_or_opt) in [false, true].into_iter().enumerate() {
+                let config = LKHConfig {
+                    seed: Some(seed),
+                    kick_limit: Some(50),
+                    max_depth: 3,
+                    use_or_opt,
+                    ..default_config(n)
+                };
+                let result = solve(&distance, ArraySolution::new(n), config);
+                assert_valid_tour(&result);
+                total[i] += evaluate(&distance, &result);
+            }
+        }
+        // 5 seed の合計で 56219 -> 55059
+        assert!(total[1] <= total[0], "{:?}", total);
+    }
+
+    #[test]
+    fn test_target_eval() {
+        // 一直線に並んだ点は、端から端へ行って戻る長さが最適
+        let n = 100;
+        let distance = RandomPoints {
+            point_list: (0..n as i64).map(|i| (0, 10 * i)).collect(),
+        };
+        let optimal = 2 * 10 * (n as i64 - 1);
+
+        let mut order = (0..n as u32).collect::<Vec<_>>();
+        order.shuffle(&mut StdRng::seed_from_u64(0));
+        // 時間では打ち切らないので、すぐに返ってくるなら target_eval で止まっている
+        let config = LKHConfig {
+            seed: Some(0),
+            time_ms: 60_000,
+            target_eval: Some(optimal),
+            ..default_config(n)
+        };
+        let start = Instant::now();
+        let result = solve(&distance, ArraySolution::from_array(order), config);
+        assert!(start.elapsed().as_millis() < 30_000);
+        assert_eq!(evaluate(&distance, &result), optimal);
+    }
+
+    #[test]
+    fn test_gap_stop() {
+        let n = 100;
+        let distance = RandomPoints::new(n, 7);
+        let config = LKHConfig {
+            seed: Some(0),
+            time_ms: 60_000,
+            fail_count_threashold: 20,
+            gap_stop: Some(0.001),
+            ..default_config(n)
+        };
+        let start = Instant::now();
+        let result = solve(&distance, ArraySolution::new(n), config);
+        assert!(start.elapsed().as_millis() < 30_000);
+        assert_valid_tour(&result);
+    }
+
+    #[test]
+    fn test_num_threads() {
+        let n = 300;
+        for seed in 0..3 {
+            let distance = RandomPoints::new(n, seed);
+            // 各 thread の kick の回数を揃える
+            let eval_of = |num_threads| {
+                let config = LKHConfig {
+                    seed: Some(seed),
+                    kick_limit: Some(30),
+                    num_threads,
+                    ..default_config(n)
+                };
+                let result = solve(&distance, ArraySolution::new(n), config);
+                assert_valid_tour(&result);
+                evaluate(&distance, &result)
+            };
+            let single = eval_of(1);
+            let multi = eval_of(4);
+            assert!(multi <= single, "seed {}: {} > {}", seed, multi, single);
+        }
+    }
+
+    #[test]
+    fn test_sweep_depth() {
+        let n = 100;
+        let distance = RandomPoints::new(n, 0);
+        let solution = ArraySolution::new(n);
+        let init_eval = evaluate(&distance, &solution);
+
+        let depth_list = [2, 3, 4];
+        let rows = sweep_depth(&distance, &solution, &depth_list, |_| default_config(n));
+
+        assert_eq!(rows.len(), depth_list.len());
+        for (&depth, (max_depth, eval, result)) in depth_list.iter().zip(rows.iter()) {
+            assert_eq!(depth, *max_depth);
+            assert_valid_tour(result);
+            assert_eq!(*eval, evaluate(&distance, result));
+            assert!(*eval <= init_eval);
+        }
+
+        // seed と kick 回数を固定すれば sweep_depth と同じ解が得られるので、その中で最も良いものを返す
+        let seeded_config = |_| LKHConfig {
+            seed: Some(0),
+            kick_limit: Some(20),
+            ..default_config(n)
+        };
+        let rows = sweep_depth(&distance, &solution, &depth_list, seeded_config);
+        let best = sweep_depth_best(&distance, &solution, &depth_list, seeded_config);
+        let best_eval = rows.iter().map(|(_, eval, _)| *eval).min().unwrap();
+        assert_eq!(evaluate(&distance, &best), best_eval);
+
+        let unchanged = sweep_depth_best(&distance, &solution, &[], |_| default_config(n));
+        assert_eq!(evaluate(&distance, &unchanged), init_eval);
+    }
+
+    #[test]
+    fn test_kick_schedule() {
+        let n = 200;
+        let schedule_list = [
+            KickSchedule::Linear,
+            KickSchedule::Geometric { factor: 1.5 },
+            KickSchedule::Adaptive,
+        ];
+        for kick_schedule in schedule_list {
+            let config = LKHConfig {
+                kick_schedule,
+                ..default_config(n)
+            };
+            let range = config.start_kick_step..=config.end_kick_step;
+
+            let mut step = config.start_kick_step;
+            for iter in 0..100 {
+                step = if iter % 3 == 0 {
+                    kick_schedule.on_improve(step, &config)
+                } else {
+                    kick_schedule.on_plateau(step, &config)
+                };
+                assert!(range.contains(&step), "{:?}: {}", kick_schedule, step);
+            }
+
+            let distance = RandomPoints::new(n, 1);
+            let result = solve(&distance, ArraySolution::new(n), config);
+            assert_valid_tour(&result);
+        }
+    }
+
+    struct OneWayPoints {
+        inner: RandomPoints,
+    }
+
+    impl DistanceFunction for OneWayPoints {
+        // 番号の大きい方へ進む時だけ 2 倍かかる
+        fn distance(&self, id1: u32, id2: u32) -> i64 {
+            let d = self.inner.distance(id1, id2);
+            if id1 < id2 {
+                d * 2
+            } else {
+                d
+            }
+        }
+
+        fn dimension(&self) -> u32 {
+            self.inner.dimension()
+        }
+
+        fn name(&self) -> String {
+            "one-way".to_string()
+        }
+
+        fn is_symmetric(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_asymmetric_two_opt_delta() {
+        let n = 30;
+        let distance = OneWayPoints {
+            inner: RandomPoints::new(n, 0),
+        };
+        let mut solution = ArraySolution::new(n);
+        let mut rng = StdRng::seed_from_u64(2);
+
+        let mut eval = evaluate(&distance, &solution);
+        for _iter in 0..200 {
+            let a = rng.gen_range(0..n as u32);
+            let b = solution.next(a);
+            let c = rng.gen_range(0..n as u32);
+            let d = solution.next(c);
+            if c == a || c == b || d == a {
+                continue;
+            }
+            eval += asymmetric_two_opt_delta(&distance, &solution, a, b, c, d);
+            solution.swap(b, c);
+            assert_eq!(eval, evaluate(&distance, &solution));
+        }
+    }
+
+    #[test]
+    fn test_asymmetric_distance() {
+        let n = 8;
+        let distance = OneWayPoints {
+            inner: RandomPoints::new(n, 0),
+        };
+
+        // 0 を始点に固定して、全ての巡回路を調べた最適値
+        fn permutations(rest: &mut Vec<u32>, order: &mut Vec<u32>, out: &mut Vec<Vec<u32>>) {
+            if rest.is_empty() {
+                out.push(order.clone());
+                return;
+            }
+            for i in 0..rest.len() {
+                let id = rest.remove(i);
+                order.push(id);
+                permutations(rest, order, out);
+                order.pop();
+                rest.insert(i, id);
+            }
+        }
+        let mut tours = vec![];
+        permutations(&mut (1..n as u32).collect(), &mut vec![0], &mut tours);
+        let optimal = tours
+            .into_iter()
+            .map(|tour| evaluate(&distance, &ArraySolution::from_array(tour)))
+            .min()
+            .unwrap();
+
+        let init_solution = ArraySolution::new(n);
+        let init_eval = evaluate(&distance, &init_solution);
+        let config = LKHConfig {
+            seed: Some(0),
+            kick_limit: Some(200),
+            ..default_config(n)
+        };
+        let result = solve(&distance, init_solution, config);
+        assert_valid_tour(&result);
+        let eval = evaluate(&distance, &result);
+        assert!(eval <= init_eval);
+        assert_eq!(eval, optimal);
+    }
+}
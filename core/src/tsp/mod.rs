@@ -1,13 +1,30 @@
 pub mod array_solution;
 mod bitset;
+pub mod cached_distance;
 pub mod distance;
 pub mod euclid_distance;
 pub mod evaluate;
 mod intset;
+pub mod lazy_graph_distance;
 pub mod lkh;
 mod neighbor_table;
 pub mod opt2;
 pub mod opt3;
 mod segment_tree;
 pub mod solution;
+#[cfg(test)]
+pub(crate) mod test_util;
 pub mod two_level_tree_solution;
+
+use rand::{rngs::StdRng, SeedableRng};
+
+/// opt2 / opt3 / LKH で各点の候補にする近傍の数の既定値
+pub const DEFAULT_NEIGHBOR_LIST_SIZE: usize = 5;
+
+// seed が指定されていればそれで、無ければ OS の乱数で初期化する
+pub(crate) fn new_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
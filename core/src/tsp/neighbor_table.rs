@@ -60,6 +60,45 @@ impl NeighborTable {
         NeighborTable { table }
     }
 
+    // use_cache の場合、cache_filepath があれば読み込み、無ければ作って保存する
+    // opt2 / opt3 / LKH で同じファイルを共有できる
+    // 読み込んだ table の点数や近傍数が今の問題と合わない (別の問題の cache) 場合は、作り直して上書きする
+    pub fn load_or_new(
+        distance: &(impl DistanceFunction + std::marker::Sync),
+        neighbor_size: usize,
+        use_cache: bool,
+        cache_filepath: &PathBuf,
+    ) -> NeighborTable {
+        if use_cache && cache_filepath.exists() {
+            let table = NeighborTable::load(cache_filepath);
+            if table.is_compatible(distance.dimension(), neighbor_size) {
+                return table;
+            }
+            eprintln!(
+                "neighbor cache {} does not match the problem (dimension = {}), rebuilding",
+                cache_filepath.display(),
+                distance.dimension()
+            );
+        }
+        let table = NeighborTable::new(distance, neighbor_size);
+        if use_cache {
+            table.save(cache_filepath);
+        }
+        table
+    }
+
+    // NeighborTable::new(distance, neighbor_size) で作った table と同じ形か
+    // 保存したファイルの先頭行の点数と近傍数で判定する
+    fn is_compatible(&self, dimension: u32, neighbor_size: usize) -> bool {
+        let dimension = dimension as usize;
+        let expected_neighbor_size = neighbor_size.min(dimension.saturating_sub(1));
+        self.table.len() == dimension
+            && self
+                .table
+                .iter()
+                .all(|row| row.len() == expected_neighbor_size)
+    }
+
     pub fn neighbor_list(&self, id: u32) -> &Vec<u32> {
         &self.table[id as usize]
     }
@@ -69,12 +108,12 @@ impl NeighborTable {
         let mut writer = BufWriter::new(f);
 
         writer
-            .write(format!("{} {}\n", self.table.len(), self.table[0].len()).as_bytes())
+            .write_all(format!("{} {}\n", self.table.len(), self.table[0].len()).as_bytes())
             .unwrap();
         for row in self.table.iter() {
             let line = row.iter().map(|n| n.to_string()).collect::<Vec<_>>();
-            writer.write(line.join(" ").as_bytes()).unwrap();
-            writer.write("\n".as_bytes()).unwrap();
+            writer.write_all(line.join(" ").as_bytes()).unwrap();
+            writer.write_all("\n".as_bytes()).unwrap();
         }
     }
 
@@ -89,3 +128,34 @@ impl NeighborTable {
         NeighborTable { table }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tsp::test_util::LineDistance;
+
+    #[test]
+    fn test_load_or_new_rebuilds_stale_cache() {
+        let cache_filepath = std::env::temp_dir().join("neighbor_table_stale_cache_test");
+        let _ = std::fs::remove_file(&cache_filepath);
+
+        let small = LineDistance::range(10);
+        let table = NeighborTable::load_or_new(&small, 5, true, &cache_filepath);
+        assert_eq!(table.table.len(), 10);
+
+        // 点数の違う問題で同じ cache を使うと作り直す
+        let large = LineDistance::range(20);
+        let table = NeighborTable::load_or_new(&large, 5, true, &cache_filepath);
+        assert_eq!(table.table, NeighborTable::new(&large, 5).table);
+        assert!(table.neighbor_list(19).iter().all(|&j| j >= 14));
+
+        // 作り直した table で上書きされている
+        assert_eq!(NeighborTable::load(&cache_filepath).table, table.table);
+
+        // 近傍数が違う場合も作り直す
+        let table = NeighborTable::load_or_new(&large, 3, true, &cache_filepath);
+        assert!(table.table.iter().all(|row| row.len() == 3));
+
+        std::fs::remove_file(&cache_filepath).unwrap();
+    }
+}
@@ -2,7 +2,7 @@ use std::path::PathBuf;
 
 use crate::tsp::{
     array_solution::ArraySolution, distance::DistanceFunction, evaluate::evaluate, intset::IntSet,
-    neighbor_table::NeighborTable, solution::Solution,
+    neighbor_table::NeighborTable, new_rng, solution::Solution,
     two_level_tree_solution::TwoLeveltreeSolution,
 };
 
@@ -10,6 +10,10 @@ pub struct Opt2Config {
     pub use_neighbor_cache: bool,
     pub cache_filepath: PathBuf,
     pub debug: bool,
+    // None なら OS の乱数を使う
+    pub seed: Option<u64>,
+    // 2-opt の候補にする各点の近傍の数
+    pub neighbor_list_size: usize,
 }
 
 pub fn solve(
@@ -21,17 +25,14 @@ pub fn solve(
 
     let mut tlt = TwoLeveltreeSolution::<1000>::new(&solution);
 
-    let neighbor_table = if config.use_neighbor_cache && config.cache_filepath.exists() {
-        NeighborTable::load(&config.cache_filepath)
-    } else {
-        let table = NeighborTable::new(distance, 5);
-        if config.use_neighbor_cache {
-            table.save(&config.cache_filepath);
-        }
-        table
-    };
+    let neighbor_table = NeighborTable::load_or_new(
+        distance,
+        config.neighbor_list_size,
+        config.use_neighbor_cache,
+        &config.cache_filepath,
+    );
 
-    let mut rng = rand::thread_rng();
+    let mut rng = new_rng(config.seed);
 
     let mut dlb = IntSet::new(n);
     dlb.set_all();
@@ -83,3 +84,40 @@ pub fn solve(
     }
     tlt.to_array_solution()
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    use super::*;
+    use crate::tsp::{test_util::RandomPoints, DEFAULT_NEIGHBOR_LIST_SIZE};
+
+    #[test]
+    fn test_never_increases_length() {
+        let n = 500;
+        for seed in 0..3 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let point_list = (0..n)
+                .map(|_| (rng.gen_range(0..1000), rng.gen_range(0..1000)))
+                .collect();
+            let distance = RandomPoints { point_list };
+
+            // 初期解もランダムな順序にする
+            let mut order = (0..n as u32).collect::<Vec<_>>();
+            order.shuffle(&mut rng);
+            let init_solution = ArraySolution::from_array(order);
+            let init_eval = evaluate(&distance, &init_solution);
+
+            let config = Opt2Config {
+                use_neighbor_cache: false,
+                cache_filepath: PathBuf::new(),
+                debug: false,
+                seed: Some(seed),
+                neighbor_list_size: DEFAULT_NEIGHBOR_LIST_SIZE,
+            };
+            let solution = solve(&distance, init_solution, config);
+            assert_eq!(solution.len(), n);
+            assert!(evaluate(&distance, &solution) <= init_eval);
+        }
+    }
+}
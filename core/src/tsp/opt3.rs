@@ -1,8 +1,8 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Instant};
 
 use crate::tsp::{
     array_solution::ArraySolution, bitset::BitSet, distance::DistanceFunction, evaluate::evaluate,
-    intset::IntSet, neighbor_table::NeighborTable, solution::Solution,
+    intset::IntSet, neighbor_table::NeighborTable, new_rng, solution::Solution,
     two_level_tree_solution::TwoLeveltreeSolution,
 };
 
@@ -14,10 +14,56 @@ enum NeighborPattern {
     Pat3((u32, u32), (u32, u32), (u32, u32)),
 }
 
+// 3-opt で辺を繋ぎ替える相手の候補
+enum Candidates {
+    // 各点の近傍だけ
+    Neighbors(NeighborTable),
+    // 全ての点。n * n の表は大きすぎるので作らずに 0..n を順に見る
+    All(u32),
+}
+
+impl Candidates {
+    fn of(&self, id: u32) -> CandidateIter<'_> {
+        match self {
+            Candidates::Neighbors(neighbor_table) => {
+                CandidateIter::Neighbors(neighbor_table.neighbor_list(id).iter())
+            }
+            // 自分自身も含まれるが、既に選んだ辺の端点なので selected で弾かれる
+            Candidates::All(n) => CandidateIter::All(0..*n),
+        }
+    }
+}
+
+enum CandidateIter<'a> {
+    Neighbors(std::slice::Iter<'a, u32>),
+    All(std::ops::Range<u32>),
+}
+
+impl Iterator for CandidateIter<'_> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        match self {
+            CandidateIter::Neighbors(iter) => iter.next().copied(),
+            CandidateIter::All(range) => range.next(),
+        }
+    }
+}
+
 pub struct Opt3Config {
     pub use_neighbor_cache: bool,
     pub cache_filepath: PathBuf,
     pub debug: bool,
+    // None なら OS の乱数を使う
+    pub seed: Option<u64>,
+    // 3-opt の候補を各点の近傍 neighbor_list_size 点に限定する
+    pub neighbor_limited: bool,
+    // neighbor_limited の時に候補にする近傍の数
+    pub neighbor_list_size: usize,
+    // この時間を超えたら、局所最適に達していなくてもその時点の解を返す
+    pub max_time_ms: Option<u128>,
+    // n 回の改善の試行を 1 round として、この round 数で打ち切る
+    pub max_rounds: Option<usize>,
 }
 
 // https://en.wikipedia.org/wiki/3-opt
@@ -27,20 +73,26 @@ pub fn solve(
     config: Opt3Config,
 ) -> ArraySolution {
     let n = solution.len();
+    // 繋ぎ替える辺が無い
+    if n < 2 {
+        return solution;
+    }
 
     let mut tlt = TwoLeveltreeSolution::<1000>::new(&solution);
 
-    let neighbor_table = if config.use_neighbor_cache && config.cache_filepath.exists() {
-        NeighborTable::load(&config.cache_filepath)
+    // neighbor_limited でない場合は全ての点を候補にする
+    let candidates = if config.neighbor_limited {
+        Candidates::Neighbors(NeighborTable::load_or_new(
+            distance,
+            config.neighbor_list_size,
+            config.use_neighbor_cache,
+            &config.cache_filepath,
+        ))
     } else {
-        let table = NeighborTable::new(distance, 5);
-        if config.use_neighbor_cache {
-            table.save(&config.cache_filepath);
-        }
-        table
+        Candidates::All(n as u32)
     };
 
-    let mut rng = rand::thread_rng();
+    let mut rng = new_rng(config.seed);
 
     let mut dlb = IntSet::new(n);
     dlb.set_all();
@@ -48,7 +100,20 @@ pub fn solve(
     let mut eval = evaluate(distance, &tlt);
     let mut selected = BitSet::new(n);
 
+    let start = Instant::now();
+    let round_len = n.max(1);
+
     for iter in 0.. {
+        if config
+            .max_time_ms
+            .is_some_and(|max_time_ms| start.elapsed().as_millis() >= max_time_ms)
+        {
+            if config.debug {
+                eprintln!("time limit: iter = {}, eval = {}", iter, eval);
+            }
+            break;
+        }
+
         let a = dlb.random_select(&mut rng);
 
         selected.clear_all();
@@ -64,22 +129,22 @@ pub fn solve(
             selected.set(b);
 
             // a-c をくっつけるとよさそう
-            for c in neighbor_table.neighbor_list(a) {
-                let c_next = tlt.next(*c);
-                let c_prev = tlt.prev(*c);
+            for c in candidates.of(a) {
+                let c_next = tlt.next(c);
+                let c_prev = tlt.prev(c);
 
-                for (c, d) in [(c_prev, *c), (*c, c_next)] {
+                for (c, d) in [(c_prev, c), (c, c_next)] {
                     if selected.test(c) || selected.test(d) {
                         continue;
                     }
                     selected.set(c);
                     selected.set(d);
 
-                    for e in neighbor_table.neighbor_list(c) {
-                        let e_next = tlt.next(*e);
-                        let e_prev = tlt.prev(*e);
+                    for e in candidates.of(c) {
+                        let e_next = tlt.next(e);
+                        let e_prev = tlt.prev(e);
 
-                        for (e, f) in [(e_prev, *e), (*e, e_next)] {
+                        for (e, f) in [(e_prev, e), (e, e_next)] {
                             if selected.test(e) || selected.test(f) {
                                 continue;
                             }
@@ -157,22 +222,22 @@ pub fn solve(
             }
 
             // a-e をくっつけるとよさそう
-            for e in neighbor_table.neighbor_list(a) {
-                let e_next = tlt.next(*e);
-                let e_prev = tlt.prev(*e);
+            for e in candidates.of(a) {
+                let e_next = tlt.next(e);
+                let e_prev = tlt.prev(e);
 
-                for (e, f) in [(e_prev, *e), (*e, e_next)] {
+                for (e, f) in [(e_prev, e), (e, e_next)] {
                     if selected.test(e) || selected.test(f) {
                         continue;
                     }
                     selected.set(e);
                     selected.set(f);
 
-                    for c in neighbor_table.neighbor_list(f) {
-                        let c_next = tlt.next(*c);
-                        let c_prev = tlt.prev(*c);
+                    for c in candidates.of(f) {
+                        let c_next = tlt.next(c);
+                        let c_prev = tlt.prev(c);
 
-                        for (c, d) in [(c_prev, *c), (*c, c_next)] {
+                        for (c, d) in [(c_prev, c), (c, c_next)] {
                             if selected.test(c) || selected.test(d) {
                                 continue;
                             }
@@ -250,13 +315,133 @@ pub fn solve(
             }
         }
 
-        if config.debug && (iter % (n / 10) == 0 || dlb.is_empty()) {
-            eprintln!("iter = {}, eval = {}", iter, eval);
+        let round_end = (iter + 1) % round_len == 0;
+        if config.debug && (round_end || dlb.is_empty()) {
+            eprintln!("round = {}, eval = {}", iter / round_len, eval);
             eprintln!("dlb size = {}", dlb.len());
         }
         if dlb.is_empty() {
             break;
         }
+        if round_end
+            && config
+                .max_rounds
+                .is_some_and(|max_rounds| (iter + 1) / round_len >= max_rounds)
+        {
+            break;
+        }
     }
     tlt.to_array_solution()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::tsp::{
+        test_util::{CountingDistance, RandomPoints},
+        DEFAULT_NEIGHBOR_LIST_SIZE,
+    };
+
+    fn config(neighbor_limited: bool) -> Opt3Config {
+        Opt3Config {
+            use_neighbor_cache: false,
+            cache_filepath: PathBuf::new(),
+            debug: false,
+            seed: None,
+            neighbor_limited,
+            neighbor_list_size: DEFAULT_NEIGHBOR_LIST_SIZE,
+            max_time_ms: None,
+            max_rounds: None,
+        }
+    }
+
+    #[test]
+    fn test_neighbor_limited_large() {
+        let n = 2000;
+        let distance = RandomPoints::new(n, 0);
+        let init_solution = ArraySolution::new(n);
+        let init_eval = evaluate(&distance, &init_solution);
+
+        let solution = solve(&distance, init_solution, config(true));
+        assert!(evaluate(&distance, &solution) <= init_eval);
+    }
+
+    #[test]
+    fn test_neighbor_limited_is_faster() {
+        // 時間ではなく、距離を評価した回数で比べる
+        let n = 100;
+        let limited_distance = CountingDistance::new(RandomPoints::new(n, 1));
+        let limited = solve(&limited_distance, ArraySolution::new(n), config(true));
+
+        let full_distance = CountingDistance::new(RandomPoints::new(n, 1));
+        let full = solve(&full_distance, ArraySolution::new(n), config(false));
+
+        let init_eval = evaluate(&limited_distance, &ArraySolution::new(n));
+        assert!(evaluate(&limited_distance, &limited) <= init_eval);
+        assert!(evaluate(&full_distance, &full) <= init_eval);
+        assert!(
+            limited_distance.count() * 10 < full_distance.count(),
+            "limited: {}, full: {}",
+            limited_distance.count(),
+            full_distance.count()
+        );
+    }
+
+    #[test]
+    fn test_full_candidates_small() {
+        // 全点を候補にする時も、点が 0 個や 1 個の場合に落ちない
+        for n in 0..=5 {
+            let distance = RandomPoints::new(n, 4);
+            let init_eval = evaluate(&distance, &ArraySolution::new(n));
+            let solution = solve(&distance, ArraySolution::new(n), config(false));
+            assert_eq!(solution.len(), n);
+            assert!(evaluate(&distance, &solution) <= init_eval);
+        }
+    }
+
+    #[test]
+    fn test_max_time_ms() {
+        let n = 2000;
+        let distance = RandomPoints::new(n, 2);
+        let init_eval = evaluate(&distance, &ArraySolution::new(n));
+        let timed_solve = |max_time_ms| {
+            let config = Opt3Config {
+                max_time_ms: Some(max_time_ms),
+                ..config(true)
+            };
+            let start = Instant::now();
+            let solution = solve(&distance, ArraySolution::new(n), config);
+            (solution, start.elapsed().as_millis())
+        };
+
+        // 近傍の構築など、3-opt 以外にかかる時間
+        let (_, overhead) = timed_solve(0);
+
+        let time_ms = 100;
+        let (solution, elapsed) = timed_solve(time_ms);
+        assert!(
+            elapsed <= overhead + time_ms + 500,
+            "elapsed: {} ms, overhead: {} ms",
+            elapsed,
+            overhead
+        );
+        assert!(evaluate(&distance, &solution) <= init_eval);
+    }
+
+    #[test]
+    fn test_max_rounds() {
+        let n = 200;
+        let distance = RandomPoints::new(n, 3);
+        let init_eval = evaluate(&distance, &ArraySolution::new(n));
+
+        let config = Opt3Config {
+            max_rounds: Some(1),
+            ..config(true)
+        };
+        let solution = solve(&distance, ArraySolution::new(n), config);
+        assert_eq!(solution.len(), n);
+        assert!(evaluate(&distance, &solution) <= init_eval);
+    }
+}
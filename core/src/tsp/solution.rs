@@ -11,12 +11,20 @@ pub trait Solution {
 
     fn len(&self) -> usize;
 
+    // start から next を辿って一周する順に、全ての id を返す
+    fn iter_order(&self, start: u32) -> impl Iterator<Item = u32> + '_ {
+        let mut id = start;
+        (0..self.len()).map(move |_| {
+            let current = id;
+            id = self.next(id);
+            current
+        })
+    }
+
     fn print(&self) {
-        let mut id = 0;
         eprint!("[");
-        for _iter in 0..self.len() {
+        for id in self.iter_order(0) {
             eprint!("{}, ", id);
-            id = self.next(id);
         }
         eprintln!("]");
     }
@@ -0,0 +1,103 @@
+// テストで使う DistanceFunction
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::tsp::distance::DistanceFunction;
+
+// 平面上の点のユークリッド距離 (四捨五入)
+pub(crate) struct RandomPoints {
+    pub(crate) point_list: Vec<(i64, i64)>,
+}
+
+impl RandomPoints {
+    // [0, 1000) x [0, 1000) にランダムに置いた n 点
+    pub(crate) fn new(n: usize, seed: u64) -> RandomPoints {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let point_list = (0..n)
+            .map(|_| (rng.gen_range(0..1000), rng.gen_range(0..1000)))
+            .collect();
+        RandomPoints { point_list }
+    }
+}
+
+impl DistanceFunction for RandomPoints {
+    fn distance(&self, id1: u32, id2: u32) -> i64 {
+        let (y1, x1) = self.point_list[id1 as usize];
+        let (y2, x2) = self.point_list[id2 as usize];
+        (((y1 - y2).pow(2) + (x1 - x2).pow(2)) as f64)
+            .sqrt()
+            .round() as i64
+    }
+
+    fn dimension(&self) -> u32 {
+        self.point_list.len() as u32
+    }
+
+    fn name(&self) -> String {
+        "random".to_string()
+    }
+}
+
+// 数直線上の点
+pub(crate) struct LineDistance {
+    pub(crate) point_list: Vec<i64>,
+}
+
+impl LineDistance {
+    // 0, 1, ..., n - 1 に置いた n 点
+    pub(crate) fn range(n: usize) -> LineDistance {
+        LineDistance {
+            point_list: (0..n as i64).collect(),
+        }
+    }
+}
+
+impl DistanceFunction for LineDistance {
+    fn distance(&self, id1: u32, id2: u32) -> i64 {
+        (self.point_list[id1 as usize] - self.point_list[id2 as usize]).abs()
+    }
+
+    fn dimension(&self) -> u32 {
+        self.point_list.len() as u32
+    }
+
+    fn name(&self) -> String {
+        "line".to_string()
+    }
+}
+
+// inner の distance が呼ばれた回数を数える
+pub(crate) struct CountingDistance<D: DistanceFunction> {
+    pub(crate) inner: D,
+    pub(crate) count: AtomicUsize,
+}
+
+impl<D: DistanceFunction> CountingDistance<D> {
+    pub(crate) fn new(inner: D) -> CountingDistance<D> {
+        CountingDistance {
+            inner,
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+impl<D: DistanceFunction> DistanceFunction for CountingDistance<D> {
+    fn distance(&self, id1: u32, id2: u32) -> i64 {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.inner.distance(id1, id2)
+    }
+
+    fn dimension(&self) -> u32 {
+        self.inner.dimension()
+    }
+
+    fn name(&self) -> String {
+        "counting".to_string()
+    }
+}
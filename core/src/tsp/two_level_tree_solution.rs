@@ -617,9 +617,15 @@ impl<const N: usize> Solution for TwoLeveltreeSolution<N> {
             }
         }
         let segment_id = self.segment_list.content[left];
-        let inner_index = index - self.buffer[segment_id as usize].start_1d_index;
-        assert!(inner_index < self.buffer[segment_id as usize].len());
-        self.buffer[segment_id as usize].array[inner_index]
+        let segment = &self.buffer[segment_id as usize];
+        let inner_index = index - segment.start_1d_index;
+        assert!(inner_index < segment.len());
+        // index_of と同じく、反転している segment は後ろから数える
+        if segment.reversed {
+            segment.array[segment.len() - 1 - inner_index]
+        } else {
+            segment.array[inner_index]
+        }
     }
 }
 
@@ -949,4 +955,30 @@ mod tests {
             assert_eq!(solution.id_of(index), two_level_tree.id_of(index));
         }
     }
+
+    #[test]
+    fn test_id_of_after_swap() {
+        // 反転した segment があっても id_of と index_of が逆関数になり、index + 1 が next になる
+        const SIZE: usize = 300;
+        let solution = ArraySolution::new(SIZE);
+        let mut two_level_tree = TwoLeveltreeSolution::<1000>::new(&solution);
+        let mut rng = rand::thread_rng();
+
+        for _iter in 0..200 {
+            let from = rng.gen_range(0..SIZE as u32);
+            let to = rng.gen_range(0..SIZE as u32);
+            if from == to {
+                continue;
+            }
+            two_level_tree.swap(from, to);
+            for id in 0..SIZE as u32 {
+                let index = two_level_tree.index_of(id);
+                assert_eq!(two_level_tree.id_of(index), id);
+                assert_eq!(
+                    two_level_tree.id_of((index + 1) % SIZE),
+                    two_level_tree.next(id)
+                );
+            }
+        }
+    }
 }
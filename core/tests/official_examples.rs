@@ -0,0 +1,114 @@
+// 公式の icfp.html に載っている例を、演算子ごとにまとめて評価する
+use core::parser::{
+    ast::{evaluate_to_value, ParseConfig, Value},
+    icfpstring::ICFPString,
+};
+use num_bigint::BigInt;
+
+fn int(v: i64) -> Value {
+    Value::Integer(BigInt::from(v))
+}
+
+fn string(s: &str) -> Value {
+    Value::String(ICFPString::from_encoded_str(s).unwrap())
+}
+
+fn assert_cases(cases: &[(&str, Value)]) {
+    for (program, expected) in cases {
+        let result = evaluate_to_value(program.to_string(), ParseConfig::default());
+        assert_eq!(result.as_ref(), Ok(expected), "program: {}", program);
+    }
+}
+
+#[test]
+fn test_boolean() {
+    assert_cases(&[("T", Value::Boolean(true)), ("F", Value::Boolean(false))]);
+}
+
+#[test]
+fn test_integer() {
+    assert_cases(&[("I/6", int(1337)), ("I!", int(0)), ("I\"", int(1))]);
+}
+
+#[test]
+fn test_string() {
+    assert_cases(&[
+        ("SB%,,/}Q/2,$_", string("Hello World!")),
+        ("S4%34", string("test")),
+    ]);
+}
+
+#[test]
+fn test_unary() {
+    assert_cases(&[
+        ("U- I$", int(-3)),
+        ("U! T", Value::Boolean(false)),
+        ("U! F", Value::Boolean(true)),
+        ("U# S4%34", int(15818151)),
+        ("U$ I4%34", string("test")),
+    ]);
+}
+
+#[test]
+fn test_binary_integer() {
+    assert_cases(&[
+        ("B+ I# I$", int(5)),
+        ("B- I$ I#", int(1)),
+        ("B* I$ I#", int(6)),
+        // 0 方向への切り捨て
+        ("B/ U- I( I#", int(-3)),
+        ("B% U- I( I#", int(-1)),
+        ("B< I$ I#", Value::Boolean(false)),
+        ("B> I$ I#", Value::Boolean(true)),
+        ("B= I$ I#", Value::Boolean(false)),
+        ("B= I$ I$", Value::Boolean(true)),
+    ]);
+}
+
+#[test]
+fn test_binary_boolean() {
+    assert_cases(&[
+        ("B| T F", Value::Boolean(true)),
+        ("B| F F", Value::Boolean(false)),
+        ("B& T F", Value::Boolean(false)),
+        ("B& T T", Value::Boolean(true)),
+    ]);
+}
+
+#[test]
+fn test_binary_string() {
+    assert_cases(&[
+        ("B. S4% S34", string("test")),
+        ("BT I$ S4%34", string("tes")),
+        ("BD I$ S4%34", string("t")),
+        ("B= S4%34 S4%34", Value::Boolean(true)),
+    ]);
+}
+
+#[test]
+fn test_if() {
+    assert_cases(&[
+        ("? B> I# I$ S9%3 S./", string("no")),
+        ("? T I\" I#", int(1)),
+    ]);
+}
+
+#[test]
+fn test_lambda() {
+    assert_cases(&[
+        (
+            "B$ B$ L# L$ v# B. SB%,,/ S}Q/2,$_ IK",
+            string("Hello World!"),
+        ),
+        ("B$ L# B$ L\" B+ v\" v\" B* I$ I# v8", int(12)),
+    ]);
+}
+
+#[test]
+fn test_recursion() {
+    // Y コンビネータで f(n) = f(n - 1) + f(n - 1), f(0) = 1 を n = 4 で計算する例 (2^4 = 16)
+    assert_cases(&[(
+        "B$ B$ L\" B$ L# B$ v\" B$ v# v# L# B$ v\" B$ v# v# L\" L# ? B= v# I! I\" B$ L$ B+ B$ v\" v$ B$ v\" v$ B- v# I\" I%",
+        int(16),
+    )]);
+}
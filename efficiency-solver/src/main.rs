@@ -1,5 +1,7 @@
 use clap::Parser;
-use core::parser::ast::parse;
+use core::parser::ast::{
+    construct_program, parse_with_config_and_callback, EvalStrategy, ParseConfig, Value,
+};
 use std::fs;
 use std::path::PathBuf;
 
@@ -10,6 +12,94 @@ use std::path::PathBuf;
 struct Args {
     #[arg(short, long)]
     filepath: PathBuf,
+
+    /// iteration 毎に node_size を出力する
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// 最後に node_size の統計情報を出力する
+    #[arg(short, long)]
+    stats: bool,
+
+    /// 統計情報と一緒に node_size のヒストグラムを出力する
+    #[arg(long)]
+    histogram: bool,
+
+    /// 縮約前のプログラムを Graphviz の DOT 形式で書き出す
+    #[arg(long)]
+    dot: Option<PathBuf>,
+}
+
+/// 縮約中の node_size の推移を記録する
+#[derive(Debug, Default)]
+struct NodeSizeStats {
+    history: Vec<usize>,
+}
+
+impl NodeSizeStats {
+    fn record(&mut self, node_size: usize) {
+        self.history.push(node_size);
+    }
+
+    fn min(&self) -> Option<usize> {
+        self.history.iter().copied().min()
+    }
+
+    fn max(&self) -> Option<usize> {
+        self.history.iter().copied().max()
+    }
+
+    fn final_size(&self) -> Option<usize> {
+        self.history.last().copied()
+    }
+
+    // 1 iteration あたりの node_size の平均増加量
+    // 正なら発散気味、0 付近なら収束している
+    fn growth_rate(&self) -> f64 {
+        if self.history.len() < 2 {
+            return 0.0;
+        }
+        let first = self.history[0] as f64;
+        let last = *self.history.last().unwrap() as f64;
+        (last - first) / (self.history.len() - 1) as f64
+    }
+
+    // [min, max] を bin_count 個に分割して、各区間に入った iteration 数を # で表示する
+    fn histogram(&self, bin_count: usize, bar_width: usize) -> String {
+        let (Some(min), Some(max)) = (self.min(), self.max()) else {
+            return String::new();
+        };
+        let bin_size = ((max - min) / bin_count + 1).max(1);
+        let mut bins = vec![0usize; bin_count];
+        for &size in self.history.iter() {
+            let index = ((size - min) / bin_size).min(bin_count - 1);
+            bins[index] += 1;
+        }
+
+        let max_count = *bins.iter().max().unwrap();
+        let mut ret = String::new();
+        for (i, &count) in bins.iter().enumerate() {
+            let lower = min + i * bin_size;
+            let upper = lower + bin_size - 1;
+            let bar_len = (count * bar_width).div_ceil(max_count);
+            ret.push_str(&format!(
+                "{:>10} - {:>10} | {} {}\n",
+                lower,
+                upper,
+                "#".repeat(bar_len),
+                count
+            ));
+        }
+        ret
+    }
+
+    fn print_summary(&self) {
+        eprintln!("iterations : {}", self.history.len());
+        eprintln!("min        : {}", self.min().unwrap_or(0));
+        eprintln!("max        : {}", self.max().unwrap_or(0));
+        eprintln!("final      : {}", self.final_size().unwrap_or(0));
+        eprintln!("growth/iter: {:.3}", self.growth_rate());
+    }
 }
 
 fn read_content(path: &PathBuf) -> Result<String, anyhow::Error> {
@@ -20,9 +110,67 @@ fn main() -> Result<(), anyhow::Error> {
     let args = Args::parse();
 
     let contents = read_content(&args.filepath)?;
-    let node = parse(contents)?;
+    if let Some(dot_path) = &args.dot {
+        let parser_state = construct_program(&contents, EvalStrategy::default())?;
+        let root = parser_state.root();
+        eprintln!("initial nodes: {}", root.len(parser_state.node_factory()));
+        fs::write(dot_path, root.to_dot_string(parser_state.node_factory()))?;
+    }
+
+    let mut stats = NodeSizeStats::default();
+    let output =
+        parse_with_config_and_callback(contents, ParseConfig::default(), |iter, node_size| {
+            if args.verbose {
+                eprintln!("iter: {}, node_size: {}", iter, node_size);
+            }
+            stats.record(node_size);
+        })?;
+    let value = Value::from_node_type(output.node.node_type)?;
+
+    println!("{}", value);
 
-    println!("{:?}", node);
+    if args.stats || args.histogram {
+        stats.print_summary();
+    }
+    if args.histogram {
+        eprint!("{}", stats.histogram(10, 50));
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_final_node_size() {
+        let mut stats = NodeSizeStats::default();
+        let output = parse_with_config_and_callback(
+            "B+ I# B* I$ I%".to_string(),
+            ParseConfig::default(),
+            |_iter, node_size| stats.record(node_size),
+        )
+        .unwrap();
+        let value = Value::from_node_type(output.node.node_type).unwrap();
+        assert_eq!(value.to_string(), "14");
+
+        // 縮約して回収されたノードの分だけ、最後は最初より小さくなる
+        assert!(stats.history.len() >= 2);
+        assert!(stats.final_size() < stats.max(), "{:?}", stats.history);
+    }
+
+    #[test]
+    fn test_stats_histogram() {
+        let mut stats = NodeSizeStats::default();
+        for size in [10, 10, 12, 20, 30] {
+            stats.record(size);
+        }
+        assert_eq!(stats.min(), Some(10));
+        assert_eq!(stats.max(), Some(30));
+        assert_eq!(stats.growth_rate(), 5.0);
+
+        let histogram = stats.histogram(4, 10);
+        assert_eq!(histogram.lines().count(), 4);
+    }
+}
@@ -1,23 +1,91 @@
+use clap::{Parser, ValueEnum};
+use core::grid::{count_remaining_pellets, simulate_lambdaman, LambdamanSimulator};
+use core::parser::compress::{compress_string, repeat_program};
+use core::parser::icfpstring::{strip_trailing_newline, ICFPString};
+use core::solver::{split_records, Comparison, SolveError};
 use core::tsp::{
     array_solution::ArraySolution,
     distance::DistanceFunction,
-    lkh::{self, LKHConfig},
+    lazy_graph_distance::LazyGraphDistance,
+    lkh::{self, KickSchedule, LKHConfig},
     opt3,
     solution::Solution,
+    DEFAULT_NEIGHBOR_LIST_SIZE,
 };
 use std::{
     collections::VecDeque,
-    io::{self, BufRead},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
     path::PathBuf,
     str::FromStr,
 };
 
-fn read_input() -> Result<Vec<Vec<char>>, anyhow::Error> {
-    let stdin = io::stdin();
+#[derive(Parser, Debug)]
+struct Args {
+    /// 問題ファイルのパス。省略した場合は標準入力から読む
+    #[arg(short, long)]
+    input: Option<PathBuf>,
+
+    /// 入力を空行で区切った複数の問題として順に解き、解を 1 行ずつ空行で区切って出力する
+    #[arg(long, conflicts_with = "compare_baseline")]
+    multi: bool,
+
+    /// 出力した経路で全てのペレットを回収できるか、元のグリッドで確認する
+    #[arg(long)]
+    validate: bool,
+
+    /// 訪問する順に、各セルの座標 (y x) を標準エラーに出力する
+    #[arg(long)]
+    waypoints: bool,
+
+    /// 指定した max_depth (例: 2,3,4,5) それぞれで LKH を実行して評価値を比較する
+    #[arg(long, value_delimiter = ',')]
+    sweep_depth: Vec<usize>,
+
+    /// 乱数の seed。指定すると LKH を時間ではなく --kick-limit 回の kick で打ち切るので、
+    /// 同じ seed と入力なら同じ出力になる
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// --seed を指定した時に LKH で行う kick の回数
+    #[arg(long, default_value_t = 1000)]
+    kick_limit: usize,
+
+    /// 保存済みの解と新しく求めた解の手数を比べる。新しい解の方が長ければエラー終了する
+    #[arg(long)]
+    compare_baseline: Option<PathBuf>,
+
+    /// 出力の形式
+    #[arg(long, value_enum, default_value_t = OutputMode::Raw)]
+    output_mode: OutputMode,
+
+    /// LKH の前に行う 3-opt を打ち切る時間 (ms)。大きなグリッドで 3-opt に時間を取られすぎないようにする
+    #[arg(long)]
+    opt3_time_limit_ms: Option<u128>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum OutputMode {
+    /// 移動の文字列をそのまま出力する
+    Raw,
+    /// 同じ塊が続く部分を繰り返しにした、評価すると移動の文字列になる ICFP のプログラムを出力する
+    Rle,
+    /// S... と rle の短い方を出力する
+    Auto,
+}
+
+fn open_input(path: &Option<PathBuf>) -> Result<Box<dyn BufRead>, anyhow::Error> {
+    match path {
+        Some(path) => Ok(Box::new(BufReader::new(File::open(path)?))),
+        None => Ok(Box::new(BufReader::new(io::stdin()))),
+    }
+}
+
+fn read_input(reader: impl BufRead) -> Result<Vec<Vec<char>>, SolveError> {
     let mut grid: Vec<Vec<char>> = Vec::new();
 
-    for line in stdin.lock().lines() {
-        let line = line?;
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| SolveError::input_io(index + 1, e))?;
         if line.is_empty() {
             break;
         }
@@ -26,64 +94,40 @@ fn read_input() -> Result<Vec<Vec<char>>, anyhow::Error> {
     Ok(grid)
 }
 
+// 周囲を壁で囲む
+// 行の長さが揃っていない場合は、最も長い行に合わせて短い行の右側も壁で埋める
 fn create_wall(grid: Vec<Vec<char>>) -> Vec<Vec<char>> {
-    let mut new_grid = vec![vec!['#'; grid[0].len() + 2]; grid.len() + 2];
-    for i in 0..grid.len() {
-        for j in 0..grid[0].len() {
-            new_grid[i + 1][j + 1] = grid[i][j];
+    let width = grid.iter().map(|row| row.len()).max().unwrap_or(0);
+    let mut new_grid = vec![vec!['#'; width + 2]; grid.len() + 2];
+    for (i, row) in grid.iter().enumerate() {
+        for (j, &c) in row.iter().enumerate() {
+            new_grid[i + 1][j + 1] = c;
         }
     }
     new_grid
 }
 
 struct Problem {
-    grid: Vec<Vec<char>>,
     id_table: Vec<Vec<usize>>,
     width: usize,
     height: usize,
     coords: Vec<(usize, usize)>,
-    distance_table: Vec<Vec<i64>>,
+    distance: LazyGraphDistance,
     start: usize,
 }
 
+// 距離の cache に使うメモリの上限 (byte)
+// この範囲に収まる (空きマスが 3000 程度までの) グリッドなら、最初に全ての始点から BFS して表を作る
+// 表を作る BFS は点数の 2 乗かかるので、それより大きいグリッドでは問い合わせがあった始点の分だけにする
+const DISTANCE_CACHE_BYTES: usize = 32 << 20;
+
 const DY: [i64; 4] = [0, 1, 0, -1];
 const DX: [i64; 4] = [1, 0, -1, 0];
 const DIRS: [char; 4] = ['R', 'D', 'L', 'U'];
 
 impl Problem {
-    fn bfs(&mut self, start: usize) {
-        let mut queue = VecDeque::new();
-        queue.push_back((start, 0));
-        self.distance_table[start][start] = 0;
-
-        while let Some((id, distance)) = queue.pop_front() {
-            for i in 0..4 {
-                let (y, x) = self.coords[id];
-                let ny = y as i64 + DY[i];
-                let nx = x as i64 + DX[i];
-                if nx < 0
-                    || ny < 0
-                    || ny >= self.height as i64
-                    || nx >= self.width as i64
-                    || self.grid[ny as usize][nx as usize] == '#'
-                {
-                    continue;
-                }
-                let next_id = self.id_table[ny as usize][nx as usize];
-                if next_id == std::usize::MAX {
-                    continue;
-                }
-                if self.distance_table[start][next_id] != std::i64::MAX {
-                    continue;
-                }
-                self.distance_table[start][next_id] = distance + 1;
-
-                queue.push_back((next_id, distance + 1));
-            }
-        }
-    }
-
-    fn new(grid: Vec<Vec<char>>) -> Self {
+    // L が無い (全て壁の場合を含む) グリッドはエラーにする
+    fn new(grid: Vec<Vec<char>>) -> Result<Self, anyhow::Error> {
         let width = grid[0].len();
         let height: usize = grid.len();
         let mut id_table = vec![vec![std::usize::MAX; width]; height];
@@ -103,30 +147,47 @@ impl Problem {
                 }
             }
         }
-        // ハミルトン路を計算するために、距離0の頂点を挿入する
-        let distance_table = vec![vec![std::i64::MAX; id]; id];
+        if id == 0 {
+            anyhow::bail!("no open cell found: the grid is all walls");
+        }
+        if start == usize::MAX {
+            anyhow::bail!("no Lambdaman start cell found");
+        }
+
+        let adjacency = coords
+            .iter()
+            .map(|&(y, x)| {
+                (0..4)
+                    .filter_map(|dir| {
+                        let ny = y as i64 + DY[dir];
+                        let nx = x as i64 + DX[dir];
+                        if nx < 0 || ny < 0 || ny >= height as i64 || nx >= width as i64 {
+                            return None;
+                        }
+                        let next_id = id_table[ny as usize][nx as usize];
+                        (next_id != usize::MAX).then_some(next_id as u32)
+                    })
+                    .collect()
+            })
+            .collect();
+        // n * n の表が収まれば最初に全て作り、点数が多い場合は最近使った始点の分だけ覚えておく
+        let max_cached_sources = DISTANCE_CACHE_BYTES / (id * std::mem::size_of::<u32>());
+        let distance = LazyGraphDistance::new(adjacency, max_cached_sources);
 
-        let mut problem = Problem {
-            grid,
+        Ok(Problem {
             id_table,
             width,
             height,
             coords,
-            distance_table,
+            distance,
             start,
-        };
-
-        for i in 0..id {
-            problem.bfs(i);
-        }
-
-        problem
+        })
     }
 }
 
 impl DistanceFunction for Problem {
     fn distance(&self, id1: u32, id2: u32) -> i64 {
-        self.distance_table[id1 as usize][id2 as usize]
+        self.distance.distance(id1, id2)
     }
 
     fn dimension(&self) -> u32 {
@@ -138,119 +199,751 @@ impl DistanceFunction for Problem {
     }
 }
 
-fn bfs(problem: &Problem, start: usize, goal: usize) -> String {
-    let mut queue = VecDeque::new();
-    queue.push_back((start, 0));
-
-    let mut recur_table = vec![std::usize::MAX; problem.dimension() as usize];
-
-    while let Some((id, distance)) = queue.pop_front() {
-        if id == goal {
-            let mut command_buffer = vec![];
-            let c = problem.coords[goal];
-            let mut c = (c.0 as i64, c.1 as i64);
-            let target = problem.coords[start];
-            let target = (target.0 as i64, target.1 as i64);
-
-            while target != c {
-                let id = problem.id_table[c.0 as usize][c.1 as usize];
-                let dir = recur_table[id];
-                command_buffer.push(DIRS[(dir + 2) % 4]);
-                c.0 += DY[dir];
-                c.1 += DX[dir];
-            }
-            command_buffer.reverse();
-            return String::from_iter(command_buffer);
+// 区間ごとに start から BFS して、goal に辿り着いたところで止める
+// tour の隣り合う点は近いことが多いので、グリッド全体を BFS するより調べるセルがずっと少ない
+// tour では同じ始点は 2 度出てこないので、BFS の結果は覚えておかない
+// 作業用の配列は使い回し、世代番号で今回の BFS で訪れたかを区別するので、区間ごとに O(n) の初期化はしない
+struct PathFinder<'a> {
+    problem: &'a Problem,
+    // prev[id] は今回の BFS で id に最初に辿り着いた時の 1 つ前のセル (visited[id] == generation の時だけ有効)
+    prev: Vec<usize>,
+    visited: Vec<u32>,
+    generation: u32,
+    queue: VecDeque<usize>,
+}
+
+impl<'a> PathFinder<'a> {
+    fn new(problem: &'a Problem) -> PathFinder<'a> {
+        let n = problem.coords.len();
+        PathFinder {
+            problem,
+            prev: vec![usize::MAX; n],
+            visited: vec![0; n],
+            generation: 0,
+            queue: VecDeque::new(),
         }
+    }
 
-        for dir in 0..4 {
-            let (y, x) = problem.coords[id];
-            let ny = y as i64 + DY[dir];
-            let nx = x as i64 + DX[dir];
-            if nx < 0
-                || ny < 0
-                || ny >= problem.height as i64
-                || nx >= problem.width as i64
-                || problem.grid[ny as usize][nx as usize] == '#'
-            {
-                continue;
-            }
-            let next_id = problem.id_table[ny as usize][nx as usize];
-            if next_id == std::usize::MAX {
-                continue;
+    // start から BFS して goal に辿り着いたら止める
+    fn search(&mut self, start: usize, goal: usize) {
+        let problem = self.problem;
+        self.generation += 1;
+        self.queue.clear();
+        self.visited[start] = self.generation;
+        self.prev[start] = start;
+        self.queue.push_back(start);
+
+        while let Some(id) = self.queue.pop_front() {
+            if id == goal {
+                return;
             }
-            if recur_table[next_id] != std::usize::MAX {
-                continue;
+            let (y, x) = problem.coords[id];
+            for dir in 0..4 {
+                let ny = y as i64 + DY[dir];
+                let nx = x as i64 + DX[dir];
+                if nx < 0 || ny < 0 || ny >= problem.height as i64 || nx >= problem.width as i64 {
+                    continue;
+                }
+                let next_id = problem.id_table[ny as usize][nx as usize];
+                if next_id == usize::MAX || self.visited[next_id] == self.generation {
+                    continue;
+                }
+                self.visited[next_id] = self.generation;
+                self.prev[next_id] = id;
+                self.queue.push_back(next_id);
             }
-            recur_table[next_id] = (dir + 2) % 4;
-            queue.push_back((next_id, distance + 1));
         }
+        unreachable!("cannot find target id");
     }
-    unreachable!("cannot find target id");
-}
 
-fn reconstruct_path(problem: &Problem, solution: &ArraySolution) -> String {
-    // L から始めて、最短経路を通っては復元するのを繰り返す
-    let mut buffer = String::new();
-    let mut start = problem.start;
+    // start から goal までの最短経路を移動コマンドの列で返す
+    fn path(&mut self, start: usize, goal: usize) -> String {
+        self.search(start, goal);
+        moves_from_predecessors(self.problem, &self.prev, start, goal)
+    }
+}
 
-    for _iter in 0..problem.dimension() - 1 {
-        let next = solution.next(start as u32) as usize;
-        let path = bfs(problem, start, next);
-        buffer.push_str(path.as_str());
-        start = next;
+// goal から 1 つ前のセルを start まで辿って、start から goal への移動の列にする
+fn moves_from_predecessors(problem: &Problem, prev: &[usize], start: usize, goal: usize) -> String {
+    let mut moves = vec![];
+    let mut id = goal;
+    while id != start {
+        let (y1, x1) = problem.coords[prev[id]];
+        let (y2, x2) = problem.coords[id];
+        let dir = (0..4)
+            .find(|&dir| y1 as i64 + DY[dir] == y2 as i64 && x1 as i64 + DX[dir] == x2 as i64)
+            .unwrap();
+        moves.push(DIRS[dir]);
+        id = prev[id];
     }
-    buffer
+    moves.iter().rev().collect()
 }
 
-fn main() -> Result<(), anyhow::Error> {
-    let table = read_input()?;
-    let table = create_wall(table);
+// L から始めて、tour を一周する順に頂点 id を並べる
+fn tour_order(problem: &Problem, solution: &ArraySolution) -> Vec<usize> {
+    solution
+        .iter_order(problem.start as u32)
+        .map(|id| id as usize)
+        .collect()
+}
 
-    let problem = Problem::new(table);
-    if false {
-        for y in 0..problem.dimension() {
-            for x in 0..problem.dimension() {
-                eprint!("{:5}, ", problem.distance(y, x));
-            }
-            eprintln!();
-        }
+// 訪問順に並べた元のグリッド上の (y, x)
+// problem は create_wall で周囲に壁を足しているので、1 ずらして戻す
+fn waypoints(problem: &Problem, order: &[usize]) -> Vec<(usize, usize)> {
+    order
+        .iter()
+        .map(|&id| {
+            let (y, x) = problem.coords[id];
+            (y - 1, x - 1)
+        })
+        .collect()
+}
+
+// L から始めて、最短経路を通っては復元するのを繰り返す
+// 経路全体を String に溜めずに、区間ごとに out に書き出す。書いた手数を返す
+fn reconstruct_path(
+    problem: &Problem,
+    order: &[usize],
+    out: &mut impl Write,
+) -> Result<usize, io::Error> {
+    let mut finder = PathFinder::new(problem);
+    let mut moves = 0;
+    for w in order.windows(2) {
+        let segment = finder.path(w[0], w[1]);
+        out.write_all(segment.as_bytes())?;
+        moves += segment.len();
     }
+    Ok(moves)
+}
+
+fn reconstruct_path_to_string(problem: &Problem, order: &[usize]) -> String {
+    let mut buffer = vec![];
+    reconstruct_path(problem, order, &mut buffer).unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+// 両方の解が正しいことを確認してから手数を比べ、結果を標準エラーに出す
+fn compare_baseline(
+    grid: &[Vec<char>],
+    path: &str,
+    baseline: &str,
+) -> Result<Comparison, anyhow::Error> {
+    simulate_lambdaman(grid, baseline)
+        .map_err(|e| anyhow::anyhow!("baseline is invalid: {}", e))?;
+    simulate_lambdaman(grid, path)?;
+
+    let comparison = Comparison::of(path.len(), baseline.len());
+    eprintln!(
+        "{} than baseline (new: {}, baseline: {})",
+        comparison,
+        path.len(),
+        baseline.len()
+    );
+    Ok(comparison)
+}
 
+// opt3 で初期解を作ってから LKH で改善する
+fn solve_lkh(args: &Args, problem: &Problem) -> ArraySolution {
     let solution = ArraySolution::new(problem.dimension() as usize);
     let path = "lambdaman.txt";
 
-    eprintln!("dimension: {}", problem.dimension());
-
     let init_solution = opt3::solve(
-        &problem,
+        problem,
         solution,
         opt3::Opt3Config {
             use_neighbor_cache: false,
             debug: false,
             cache_filepath: PathBuf::from_str(path).unwrap(),
+            seed: args.seed,
+            neighbor_limited: true,
+            neighbor_list_size: DEFAULT_NEIGHBOR_LIST_SIZE,
+            max_time_ms: args.opt3_time_limit_ms,
+            max_rounds: None,
         },
     );
 
-    let final_solution = lkh::solve(
-        &problem,
-        init_solution,
-        LKHConfig {
-            use_neighbor_cache: false,
-            cache_filepath: PathBuf::from_str(path).unwrap(),
-            debug: false,
-            time_ms: 600_000,
-            start_kick_step: 5,
-            kick_step_diff: 10,
-            end_kick_step: problem.dimension() as usize / 10,
-            fail_count_threashold: 50,
-            max_depth: 6,
-        },
-    );
+    let lkh_config = |max_depth| LKHConfig {
+        use_neighbor_cache: false,
+        cache_filepath: PathBuf::from_str(path).unwrap(),
+        debug: false,
+        time_ms: 600_000,
+        start_kick_step: 5,
+        kick_step_diff: 10,
+        end_kick_step: problem.dimension() as usize / 10,
+        fail_count_threashold: 50,
+        max_depth,
+        kick_schedule: KickSchedule::Linear,
+        seed: args.seed,
+        kick_limit: args.seed.map(|_| args.kick_limit),
+        neighbor_list_size: DEFAULT_NEIGHBOR_LIST_SIZE,
+        on_improvement: None,
+        checkpoint_filepath: None,
+        two_level_tree_threshold: lkh::DEFAULT_TWO_LEVEL_TREE_THRESHOLD,
+        use_or_opt: false,
+        target_eval: None,
+        gap_stop: None,
+        num_threads: 1,
+    };
+
+    if args.sweep_depth.is_empty() {
+        lkh::solve(problem, init_solution, lkh_config(6))
+    } else {
+        lkh::sweep_depth_best(problem, &init_solution, &args.sweep_depth, lkh_config)
+    }
+}
+
+/// この数以下の頂点数なら solve_exact で厳密に解く
+const EXACT_DP_MAX_NODES: usize = 18;
+
+/// start から全ての頂点を 1 回ずつ訪れる最短のハミルトン路を bit DP (Held-Karp) で求め、訪問順に頂点 id を返す
+/// 頂点数が EXACT_DP_MAX_NODES を超える場合は None
+fn solve_exact(problem: &Problem) -> Option<Vec<usize>> {
+    let n = problem.dimension() as usize;
+    if n > EXACT_DP_MAX_NODES {
+        return None;
+    }
+
+    // dp[mask][v]: start から mask の頂点を全て訪れて v にいる時の最短距離
+    let full = (1usize << n) - 1;
+    let mut dp = vec![vec![i64::MAX; n]; 1 << n];
+    let mut prev = vec![vec![usize::MAX; n]; 1 << n];
+    dp[1 << problem.start][problem.start] = 0;
+
+    for mask in 1..=full {
+        for v in 0..n {
+            if dp[mask][v] == i64::MAX {
+                continue;
+            }
+            for next in 0..n {
+                if mask & (1 << next) != 0 {
+                    continue;
+                }
+                let next_mask = mask | (1 << next);
+                let distance = dp[mask][v].saturating_add(problem.distance(v as u32, next as u32));
+                if distance < dp[next_mask][next] {
+                    dp[next_mask][next] = distance;
+                    prev[next_mask][next] = v;
+                }
+            }
+        }
+    }
+
+    let mut v = (0..n).min_by_key(|&v| dp[full][v])?;
+    let mut mask = full;
+    let mut order = vec![v];
+    while v != problem.start {
+        let p = prev[mask][v];
+        mask ^= 1 << v;
+        v = p;
+        order.push(v);
+    }
+    order.reverse();
+    Some(order)
+}
+
+struct Solved {
+    original_table: Vec<Vec<char>>,
+    problem: Problem,
+    // L から始まる訪問順
+    order: Vec<usize>,
+}
+
+// 入力を読んで、セルを訪問する順番まで求める
+fn solve(args: &Args, reader: impl BufRead) -> Result<Solved, anyhow::Error> {
+    let original_table = read_input(reader)?;
+    if original_table.is_empty() {
+        anyhow::bail!("empty grid");
+    }
+    let table = create_wall(original_table.clone());
+
+    let problem = Problem::new(table)?;
+    // L 以外に通れるセルが無ければ動く必要はない
+    if problem.dimension() == 1 {
+        let order = vec![problem.start];
+        return Ok(Solved {
+            original_table,
+            problem,
+            order,
+        });
+    }
+    if false {
+        for y in 0..problem.dimension() {
+            for x in 0..problem.dimension() {
+                eprint!("{:5}, ", problem.distance(y, x));
+            }
+            eprintln!();
+        }
+    }
+
+    eprintln!("dimension: {}", problem.dimension());
+
+    // 小さい問題は DP で厳密に解く。--sweep-depth は LKH を比べるためのものなので DP は使わない
+    let exact = if args.sweep_depth.is_empty() {
+        solve_exact(&problem)
+    } else {
+        None
+    };
+    let order = match exact {
+        Some(order) => {
+            eprintln!("solved exactly by DP");
+            order
+        }
+        None => tour_order(&problem, &solve_lkh(args, &problem)),
+    };
+
+    if args.waypoints {
+        for (y, x) in waypoints(&problem, &order) {
+            eprintln!("{} {}", y, x);
+        }
+    }
+
+    Ok(Solved {
+        original_table,
+        problem,
+        order,
+    })
+}
+
+// --validate が無くても、食べ残しがあれば気付けるように警告だけは出す
+fn warn_remaining_pellets(remaining: usize) {
+    if remaining > 0 {
+        eprintln!(
+            "warning: {} pellets remain after the output moves",
+            remaining
+        );
+    }
+}
+
+// 書き出した移動を simulator でも再生する Writer
+// 経路全体を持たずに書き出す時に、食べ残しを数えるのに使う
+struct SimulatingWriter<'a, W: Write> {
+    inner: W,
+    simulator: LambdamanSimulator<'a>,
+}
+
+impl<W: Write> Write for SimulatingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let moves =
+            std::str::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.simulator
+            .apply(moves)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.inner.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// 経路全体を持たずに標準出力に書き出せるか
+// 確認 (--validate / --compare-baseline) や圧縮には経路全体が必要
+fn can_stream(args: &Args) -> bool {
+    args.output_mode == OutputMode::Raw
+        && !args.validate
+        && args.compare_baseline.is_none()
+        && !args.multi
+}
+
+// 標準出力に出す内容 (移動の列) を返す
+fn run(args: &Args, reader: impl BufRead) -> Result<String, anyhow::Error> {
+    let Solved {
+        original_table,
+        problem,
+        order,
+    } = solve(args, reader)?;
 
     // パスの復元
-    let path_all = reconstruct_path(&problem, &final_solution);
-    print!("{}", path_all);
+    let path_all = reconstruct_path_to_string(&problem, &order);
+    if args.validate {
+        simulate_lambdaman(&original_table, &path_all)?;
+        eprintln!("validation passed");
+    } else {
+        warn_remaining_pellets(count_remaining_pellets(&original_table, &path_all)?);
+    }
+    if let Some(baseline_path) = &args.compare_baseline {
+        let baseline = fs::read_to_string(baseline_path)?;
+        let baseline = strip_trailing_newline(&baseline);
+        if compare_baseline(&original_table, &path_all, baseline)? == Comparison::Worse {
+            anyhow::bail!("regression: new solution is longer than baseline");
+        }
+    }
+
+    Ok(path_all)
+}
+
+// 移動の列を ICFPString にする
+fn moves_string(moves: &str) -> Result<ICFPString, anyhow::Error> {
+    Ok(ICFPString::from_encoded_str(moves)?)
+}
 
+// 評価すると moves になる ICFP のプログラムのうち、S... と繰り返しの loop の短い方を返す
+// moves は UDLR だけなので、必ず ICFPString にできる
+fn compress_moves(moves: &str) -> String {
+    let s = moves_string(moves).expect("moves must consist of UDLR");
+    compress_string(&s).expect("moves must consist of UDLR")
+}
+
+// 移動の列を output_mode の形式にする
+fn format_output(output_mode: OutputMode, moves: &str) -> Result<String, anyhow::Error> {
+    match output_mode {
+        OutputMode::Raw => Ok(moves.to_string()),
+        OutputMode::Rle => Ok(repeat_program(&moves_string(moves)?)?),
+        OutputMode::Auto => Ok(compress_moves(moves)),
+    }
+}
+
+// --multi 用。空行で区切られた問題を 1 つずつ解いて、問題の順に output_mode の形式の解を返す
+fn run_multi(args: &Args, reader: impl BufRead) -> Result<Vec<String>, anyhow::Error> {
+    split_records(reader)?
+        .iter()
+        .enumerate()
+        .map(|(index, record)| {
+            eprintln!("problem: {}", index + 1);
+            run(args, io::Cursor::new(record))
+                .and_then(|moves| format_output(args.output_mode, &moves))
+                .map_err(|e| anyhow::anyhow!("problem {}: {}", index + 1, e))
+        })
+        .collect()
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
+    if args.multi {
+        let outputs = run_multi(&args, open_input(&args.input)?)?;
+        println!("{}", outputs.join("\n\n"));
+        return Ok(());
+    }
+    if can_stream(&args) {
+        // 経路が長くてもメモリを使わないように、復元しながら書き出す
+        // 食べ残しは書き出しながら数える
+        let solved = solve(&args, open_input(&args.input)?)?;
+        let mut out = SimulatingWriter {
+            inner: BufWriter::new(io::stdout().lock()),
+            simulator: LambdamanSimulator::new(&solved.original_table)?,
+        };
+        reconstruct_path(&solved.problem, &solved.order, &mut out)?;
+        out.flush()?;
+        warn_remaining_pellets(out.simulator.remaining());
+        return Ok(());
+    }
+
+    let moves = run(&args, open_input(&args.input)?)?;
+    let output = format_output(args.output_mode, &moves)?;
+    match args.output_mode {
+        OutputMode::Raw => print!("{}", output),
+        OutputMode::Rle | OutputMode::Auto => println!("{}", output),
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::parser::ast::{parse, NodeType};
+    use core::parser::compress::string_token;
+
+    #[test]
+    fn test_read_input_from_file_and_stdin() {
+        let input = "###.#...\n...L..##\n.#######\n";
+
+        let filepath = std::env::temp_dir().join("lambdaman_read_input_test.txt");
+        std::fs::write(&filepath, input).unwrap();
+
+        let from_file = read_input(open_input(&Some(filepath.clone())).unwrap()).unwrap();
+        let from_reader = read_input(io::Cursor::new(input)).unwrap();
+        std::fs::remove_file(&filepath).unwrap();
+
+        assert_eq!(from_file, from_reader);
+        assert_eq!(from_file.len(), 3);
+    }
+
+    #[test]
+    fn test_run_multi() {
+        let first = "###.#...\n...L..##\n.#######\n";
+        let second = "L...#\n.#.#.\n.....\n";
+        let input = format!("{}\n{}", first, second);
+        let args = Args::parse_from(["lambdaman-solver", "--seed", "1", "--kick-limit", "20"]);
+
+        let outputs = run_multi(&args, io::Cursor::new(input)).unwrap();
+        assert_eq!(outputs.len(), 2);
+        for (problem, output) in [first, second].iter().zip(outputs.iter()) {
+            let grid = read_input(io::Cursor::new(problem)).unwrap();
+            simulate_lambdaman(&grid, output).unwrap();
+        }
+        assert_eq!(outputs[0], run(&args, io::Cursor::new(first)).unwrap());
+    }
+
+    #[test]
+    fn test_same_seed_same_output() {
+        let input = "###.#...\n...L..##\n.#######\n";
+        let args = Args::parse_from(["lambdaman-solver", "--seed", "42", "--kick-limit", "20"]);
+
+        let first = run(&args, io::Cursor::new(input)).unwrap();
+        let second = run(&args, io::Cursor::new(input)).unwrap();
+        assert_eq!(first.as_bytes(), second.as_bytes());
+        assert!(simulate_lambdaman(&read_input(io::Cursor::new(input)).unwrap(), &first).is_ok());
+    }
+
+    #[test]
+    fn test_compress_moves() {
+        let evaluate = |program: String| match parse(program).unwrap().node_type {
+            NodeType::String(s) => format!("{}", s),
+            node_type => panic!("not a string: {:?}", node_type),
+        };
+
+        // 短い入力はそのまま
+        let program = compress_moves("LLLDURRRUDRRURR");
+        assert!(program.starts_with('S'));
+        assert_eq!(evaluate(program), "LLLDURRRUDRRURR");
+
+        // 長い run は繰り返しにした方が短い
+        let moves = format!(
+            "{}{}LURD{}",
+            "R".repeat(200),
+            "D".repeat(150),
+            "L".repeat(199)
+        );
+        let program = compress_moves(&moves);
+        assert!(program.len() < moves.len() / 2, "{}", program);
+        assert_eq!(evaluate(program), moves);
+    }
+
+    #[test]
+    fn test_rle_on_straight_corridor() {
+        let evaluate = |program: String| match parse(program).unwrap().node_type {
+            NodeType::String(s) => format!("{}", s),
+            node_type => panic!("not a string: {:?}", node_type),
+        };
+
+        let input = format!("L{}\n", ".".repeat(300));
+        let args = Args::parse_from(["lambdaman-solver", "--seed", "42", "--kick-limit", "5"]);
+        let moves = run(&args, io::Cursor::new(input)).unwrap();
+        assert_eq!(moves, "R".repeat(300));
+
+        let raw = string_token(&moves_string(&moves).unwrap()).unwrap();
+        let rle = format_output(OutputMode::Rle, &moves).unwrap();
+        assert!(rle.len() < raw.len() / 2, "{}", rle);
+        assert_eq!(format_output(OutputMode::Auto, &moves).unwrap(), rle);
+        assert_eq!(evaluate(rle), moves);
+
+        // 短い経路では rle を強制すると S... より長くなるので、auto は S... を選ぶ
+        let moves = "RRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRRR";
+        let rle = format_output(OutputMode::Rle, moves).unwrap();
+        assert!(rle.len() > moves.len() + 1);
+        assert_eq!(
+            format_output(OutputMode::Auto, moves).unwrap(),
+            string_token(&moves_string(moves).unwrap()).unwrap()
+        );
+        assert_eq!(evaluate(rle), moves);
+    }
+
+    #[test]
+    fn test_jagged_grid() {
+        let input = "L...\n.#\n......\n.\n";
+        let grid = read_input(io::Cursor::new(input)).unwrap();
+
+        let table = create_wall(grid.clone());
+        assert!(table.iter().all(|row| row.len() == 8));
+        assert_eq!(table[2], "#.######".chars().collect::<Vec<_>>());
+
+        let args = Args::parse_from(["lambdaman-solver", "--seed", "42", "--kick-limit", "20"]);
+        let moves = run(&args, io::Cursor::new(input)).unwrap();
+        assert!(simulate_lambdaman(&grid, &moves).is_ok());
+    }
+
+    #[test]
+    fn test_solve_exact_matches_brute_force() {
+        let grid = read_input(io::Cursor::new("#...#\n.L#..\n..#..\n")).unwrap();
+        let problem = Problem::new(create_wall(grid)).unwrap();
+        let n = problem.dimension() as usize;
+        let length = |order: &[usize]| -> i64 {
+            order
+                .windows(2)
+                .map(|w| problem.distance(w[0] as u32, w[1] as u32))
+                .sum()
+        };
+
+        // start 以外の頂点の全ての順列を試す
+        fn permutations(rest: &mut Vec<usize>, k: usize, f: &mut impl FnMut(&[usize])) {
+            if k == rest.len() {
+                f(rest);
+                return;
+            }
+            for i in k..rest.len() {
+                rest.swap(k, i);
+                permutations(rest, k + 1, f);
+                rest.swap(k, i);
+            }
+        }
+        let mut rest = (0..n).filter(|&v| v != problem.start).collect::<Vec<_>>();
+        let mut best = i64::MAX;
+        permutations(&mut rest, 0, &mut |perm| {
+            let mut order = vec![problem.start];
+            order.extend_from_slice(perm);
+            best = best.min(length(&order));
+        });
+
+        let order = solve_exact(&problem).unwrap();
+        assert_eq!(order.len(), n);
+        assert_eq!(order[0], problem.start);
+        assert_eq!(length(&order), best);
+        assert_eq!(
+            reconstruct_path_to_string(&problem, &order).len() as i64,
+            best
+        );
+
+        let big = Problem::new(create_wall(vec![format!(
+            "L{}",
+            ".".repeat(EXACT_DP_MAX_NODES)
+        )
+        .chars()
+        .collect()]))
+        .unwrap();
+        assert!(solve_exact(&big).is_none());
+    }
+
+    #[test]
+    fn test_grid_without_start() {
+        let args = Args::parse_from(["lambdaman-solver", "--seed", "42"]);
+
+        let err = run(&args, io::Cursor::new("###.#...\n......##\n.#######\n")).unwrap_err();
+        assert_eq!(err.to_string(), "no Lambdaman start cell found");
+
+        let err = run(&args, io::Cursor::new("###\n###\n")).unwrap_err();
+        assert_eq!(err.to_string(), "no open cell found: the grid is all walls");
+
+        assert!(run(&args, io::Cursor::new("")).is_err());
+
+        // L しか通れない場合は動かない
+        assert_eq!(run(&args, io::Cursor::new("#L#\n")).unwrap(), "");
+    }
+
+    #[test]
+    fn test_compare_baseline() {
+        let grid = read_input(io::Cursor::new("###.#...\n...L..##\n.#######\n")).unwrap();
+        let short = "LLLDURRRUDRRURR";
+        // 全て回収した後に 1 手余計に動く
+        let long = "LLLDURRRUDRRURRL";
+
+        assert_eq!(
+            compare_baseline(&grid, short, long).unwrap(),
+            Comparison::Better
+        );
+        assert_eq!(
+            compare_baseline(&grid, short, short).unwrap(),
+            Comparison::Equal
+        );
+        assert_eq!(
+            compare_baseline(&grid, long, short).unwrap(),
+            Comparison::Worse
+        );
+        // 正しくない baseline とは比べない
+        assert!(compare_baseline(&grid, short, "LLL").is_err());
+    }
+
+    #[test]
+    fn test_waypoints() {
+        let grid = read_input(io::Cursor::new("###.#...\n...L..##\n.#######\n")).unwrap();
+        let problem = Problem::new(create_wall(grid)).unwrap();
+        let solution = ArraySolution::new(problem.dimension() as usize);
+
+        let waypoints = waypoints(&problem, &tour_order(&problem, &solution));
+        assert_eq!(waypoints.len(), problem.dimension() as usize);
+        assert_eq!(waypoints[0], (1, 3));
+    }
+
+    #[test]
+    fn test_reconstruct_path_streaming() {
+        // 書き込みごとに区切って覚えておく Writer
+        struct SegmentWriter(Vec<String>);
+
+        impl Write for SegmentWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.push(String::from_utf8(buf.to_vec()).unwrap());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let grid = read_input(io::Cursor::new("###.#...\n...L..##\n.#######\n")).unwrap();
+        let problem = Problem::new(create_wall(grid.clone())).unwrap();
+        let order = tour_order(&problem, &ArraySolution::new(problem.dimension() as usize));
+
+        let mut writer = SegmentWriter(vec![]);
+        let moves = reconstruct_path(&problem, &order, &mut writer).unwrap();
+        let path = reconstruct_path_to_string(&problem, &order);
+
+        // 区間ごとに書き出している
+        assert_eq!(writer.0.len(), order.len() - 1);
+        assert_eq!(writer.0.concat(), path);
+        assert_eq!(moves, path.len());
+        simulate_lambdaman(&grid, &path).unwrap();
+
+        // 書き出しながら再生しても、食べ残しの数は経路全体で数えた時と同じ
+        let mut writer = SimulatingWriter {
+            inner: vec![],
+            simulator: LambdamanSimulator::new(&grid).unwrap(),
+        };
+        reconstruct_path(&problem, &order[..2], &mut writer).unwrap();
+        let partial = String::from_utf8(writer.inner).unwrap();
+        assert!(writer.simulator.remaining() > 0);
+        assert_eq!(
+            writer.simulator.remaining(),
+            count_remaining_pellets(&grid, &partial).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_path_finder_shortest() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let grid = read_input(io::Cursor::new(
+            "L...#.....\n.##.#.###.\n.#.....#..\n...##.#...\n.#....#.#.\n",
+        ))
+        .unwrap();
+        let problem = Problem::new(create_wall(grid)).unwrap();
+        let n = problem.dimension() as usize;
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut finder = PathFinder::new(&problem);
+        for _ in 0..200 {
+            let start = rng.gen_range(0..n);
+            let goal = rng.gen_range(0..n);
+            let path = finder.path(start, goal);
+            assert_eq!(
+                path.len() as i64,
+                problem.distance(start as u32, goal as u32)
+            );
+        }
+    }
+
+    #[test]
+    fn test_path_finder_stops_at_goal() {
+        // 広いグリッドでも、隣のセルへの経路では周りの数セルしか調べない
+        let input = format!(
+            "L{}\n{}",
+            ".".repeat(199),
+            format!("{}\n", ".".repeat(200)).repeat(199)
+        );
+        let grid = read_input(io::Cursor::new(input)).unwrap();
+        let problem = Problem::new(create_wall(grid)).unwrap();
+        let order = tour_order(&problem, &ArraySolution::new(problem.dimension() as usize));
+
+        let mut finder = PathFinder::new(&problem);
+        for w in order.windows(2).take(100) {
+            let path = finder.path(w[0], w[1]);
+            assert_eq!(path.len(), 1);
+            let explored = finder
+                .visited
+                .iter()
+                .filter(|&&generation| generation == finder.generation)
+                .count();
+            assert!(explored <= 10, "explored: {}", explored);
+        }
+    }
+}
@@ -2,19 +2,44 @@ extern crate proc_macro;
 
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::{parse_macro_input, LitStr};
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input, Ident, LitStr, Token,
+};
+
+// str_to_char_array!("...") または str_to_char_array!(NAME, "...")
+struct CharArrayInput {
+    name: Ident,
+    value: LitStr,
+}
+
+impl Parse for CharArrayInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.peek(LitStr) {
+            // 名前を省略した場合は ARRAY
+            let value = input.parse()?;
+            return Ok(CharArrayInput {
+                name: Ident::new("ARRAY", proc_macro2::Span::call_site()),
+                value,
+            });
+        }
+        let name = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let value = input.parse()?;
+        Ok(CharArrayInput { name, value })
+    }
+}
 
 #[proc_macro]
 pub fn str_to_char_array(input: TokenStream) -> TokenStream {
-    let input = parse_macro_input!(input as LitStr);
-    let value = input.value();
+    let CharArrayInput { name, value } = parse_macro_input!(input as CharArrayInput);
 
-    let chars: Vec<char> = value.chars().collect();
+    let chars: Vec<char> = value.value().chars().collect();
     let len = chars.len();
-    let char_literals = chars.iter().map(|&c| c);
+    let char_literals = chars.iter();
 
     let expanded = quote! {
-        const ARRAY: [char; #len] = [#(#char_literals),*];
+        const #name: [char; #len] = [#(#char_literals),*];
     };
 
     TokenStream::from(expanded)
@@ -0,0 +1,23 @@
+use macro_util::str_to_char_array;
+
+mod default_name {
+    use super::*;
+
+    str_to_char_array!("ab\n");
+
+    #[test]
+    fn test_default_name() {
+        assert_eq!(ARRAY, ['a', 'b', '\n']);
+    }
+}
+
+// 同じ module で複数の表を定義できる
+str_to_char_array!(DIGITS, "0123");
+str_to_char_array!(OPERATORS, "<>^v+-*/");
+
+#[test]
+fn test_custom_name() {
+    assert_eq!(DIGITS, ['0', '1', '2', '3']);
+    assert_eq!(OPERATORS.len(), 8);
+    assert_eq!(OPERATORS[2], '^');
+}
@@ -1,6 +1,12 @@
 use clap::{Parser, Subcommand};
-use core::parser::ast::{parse, NodeType};
-use core::{client::ICFPCClient, parser::icfpstring::ICFPString};
+use core::parser::ast::{evaluate_to_value, parse_with_config_and_callback, ParseConfig, Value};
+use core::parser::ParseError;
+use core::{
+    client::{ICFPCClient, Track, DEFAULT_URL},
+    grid::{parse_grid, simulate_lambdaman},
+    parser::icfpstring::{strip_trailing_newline, ICFPString},
+    spaceship::{parse_points, simulate_spaceship},
+};
 use std::fs;
 use std::path::PathBuf;
 
@@ -11,6 +17,20 @@ use std::path::PathBuf;
 struct Args {
     #[command(subcommand)]
     command: Commands,
+
+    /// サーバーの auth token。環境変数 ICFP_TOKEN が設定されていればそちらを優先する
+    #[arg(long, global = true)]
+    token: Option<String>,
+
+    /// 送信先の URL。環境変数 ICFP_URL が設定されていればそちらを優先する
+    /// どちらも無ければ本番のサーバーに送る
+    #[arg(long, global = true)]
+    url: Option<String>,
+
+    /// 応答を標準出力ではなくこのファイルに書き出す。末尾に改行は付けない
+    /// 親ディレクトリが無ければ作る
+    #[arg(long, global = true)]
+    out: Option<PathBuf>,
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -50,6 +70,15 @@ enum Commands {
         #[arg(short, long)]
         problem_id: String,
     },
+    /// efficiency の問題を取得して、手元で評価した整数を出力する
+    EfficiencyEval {
+        #[arg(short, long)]
+        problem_id: String,
+
+        /// この回数縮約しても終わらなければ、その時点のノード数を出して諦める
+        #[arg(long, default_value_t = ParseConfig::default().max_iterations)]
+        max_iterations: usize,
+    },
     EfficiencySubmit {
         #[arg(short, long)]
         problem_id: String,
@@ -80,12 +109,38 @@ enum Commands {
         #[arg(short, long)]
         filepath: PathBuf,
     },
+    /// 問題一覧のページを取得して、問題の id を 1 行に 1 つずつ出力する
+    Index {
+        #[arg(short, long)]
+        track: Track,
+    },
+    /// ICFP の式が書かれたファイルを、エンコードせずにそのまま送る
+    RawPost {
+        #[arg(short, long)]
+        filepath: PathBuf,
+    },
+    /// 保存した解が問題を解けているか、送信せずに手元で確認する (lambdaman / spaceship)
+    Verify {
+        #[arg(short, long)]
+        problem: PathBuf,
+
+        #[arg(short, long)]
+        solution: PathBuf,
+
+        #[arg(short, long)]
+        track: Track,
+    },
 }
 
 fn read_content(path: &PathBuf) -> Result<String, anyhow::Error> {
     fs::read_to_string(path).map_err(|e| e.into())
 }
 
+// 平文のコマンドに埋め込むファイルは、末尾の改行を取り除いて読む
+fn read_message(path: &PathBuf) -> Result<String, anyhow::Error> {
+    Ok(strip_trailing_newline(&read_content(path)?).to_string())
+}
+
 fn encode(contents: String) -> Result<String, anyhow::Error> {
     let s = ICFPString::from_encoded_str(&contents.as_str())?;
     let encoded = s.to_string()?.into_iter().collect::<String>();
@@ -93,80 +148,409 @@ fn encode(contents: String) -> Result<String, anyhow::Error> {
 }
 
 fn decode(contents: String) -> Result<String, anyhow::Error> {
-    let decoded_message = parse(contents)?;
-    match decoded_message.node_type {
-        NodeType::String(s) => Ok(s.iter().collect::<String>()),
+    match evaluate_to_value(contents, ParseConfig::default())? {
+        Value::String(s) => Ok(s.iter().collect::<String>()),
         _ => Err(anyhow::anyhow!("Invalid message")),
     }
 }
 
 fn select_content(command: Commands) -> Result<String, anyhow::Error> {
     match command {
-        Commands::Spaceship => Ok("get spaceship".to_string()),
-        Commands::SpaceshipGet { problem_id } => Ok(format!("get spaceship{}", problem_id)),
+        Commands::Spaceship => Ok(Track::Spaceship.get_command("")),
+        Commands::SpaceshipGet { problem_id } => Ok(Track::Spaceship.get_command(&problem_id)),
         Commands::SpaceshipSubmit {
             problem_id,
             filepath,
         } => {
-            let contents = read_content(&filepath)?;
-            Ok(format!("solve spaceship{} {}", problem_id, contents))
+            let contents = read_message(&filepath)?;
+            Ok(Track::Spaceship.solve_command(&problem_id, &contents))
         }
         Commands::Echo { message } => Ok(format!("get echo {}", message)),
         Commands::Scoreboard => Ok("get scoreboard".to_string()),
         Commands::LanguageTest => Ok("get language_test".to_string()),
-        Commands::Efficiency => Ok("get efficiency".to_string()),
-        Commands::EfficiencyGet { problem_id } => Ok(format!("get efficiency{}", problem_id)),
+        Commands::Efficiency => Ok(Track::Efficiency.get_command("")),
+        Commands::EfficiencyGet { problem_id } | Commands::EfficiencyEval { problem_id, .. } => {
+            Ok(Track::Efficiency.get_command(&problem_id))
+        }
         Commands::EfficiencySubmit {
             problem_id,
             filepath,
         } => {
-            let contents = read_content(&filepath)?;
-            Ok(format!("solve efficiency{} {}", problem_id, contents))
+            let contents = read_message(&filepath)?;
+            Ok(Track::Efficiency.solve_command(&problem_id, &contents))
         }
-        Commands::D3 => Ok("get 3d".to_string()),
-        Commands::D3Example => Ok("get 3d-example".to_string()),
-        Commands::D3Get { problem_id } => Ok(format!("get 3d{}", problem_id)),
+        Commands::D3 => Ok(Track::ThreeD.get_command("")),
+        Commands::D3Example => Ok(Track::ThreeD.get_command("-example")),
+        Commands::D3Get { problem_id } => Ok(Track::ThreeD.get_command(&problem_id)),
         Commands::D3Test { filepath, a, b } => {
-            let contents = read_content(&filepath)?;
-            Ok(format!("test 3d {} {}\n {}", a, b, contents))
+            let contents = read_message(&filepath)?;
+            Ok(format!(
+                "test {} {} {}\n {}",
+                Track::ThreeD.prefix(),
+                a,
+                b,
+                contents
+            ))
         }
         Commands::D3Submit {
             problem_id,
             filepath,
         } => {
-            let contents = read_content(&filepath)?;
-            Ok(format!("solve 3d{}\n {}", problem_id, contents))
+            let contents = read_message(&filepath)?;
+            Ok(Track::ThreeD.solve_command(&problem_id, &contents))
         }
-        Commands::Lambdaman => Ok("get lambdaman".to_string()),
-        Commands::LambdamanGet { problem_id } => Ok(format!("get lambdaman{}", problem_id)),
+        Commands::Lambdaman => Ok(Track::Lambdaman.get_command("")),
+        Commands::LambdamanGet { problem_id } => Ok(Track::Lambdaman.get_command(&problem_id)),
         Commands::LambdamanSubmit {
             problem_id,
             filepath,
         } => {
-            let contents = read_content(&filepath)?;
-            Ok(format!("solve lambdaman{} {}", problem_id, contents))
+            let contents = read_message(&filepath)?;
+            Ok(Track::Lambdaman.solve_command(&problem_id, &contents))
         }
+        Commands::Index { track } => Ok(track.get_command("")),
+        Commands::RawPost { .. } => Err(anyhow::anyhow!("RawPost is not a plaintext command")),
+        Commands::Verify { .. } => Err(anyhow::anyhow!("Verify does not send a message")),
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), anyhow::Error> {
-    let args = Args::parse();
+// 送信する ICFP の式を作る
+// RawPost 以外は平文のコマンドを文字列としてエンコードする
+fn build_message(command: Commands) -> Result<String, anyhow::Error> {
+    match command {
+        Commands::RawPost { filepath } => read_content(&filepath),
+        _ => encode(select_content(command)?),
+    }
+}
 
-    let auth_token = "5b4a264f-5e00-433c-ac1b-1f9a8b30f161".to_string();
-    let client = ICFPCClient::new(auth_token);
+// efficiency の問題 (評価すると整数になる式) を縮約して、結果の整数を文字列で返す
+fn evaluate_efficiency(program: String, max_iterations: usize) -> Result<String, anyhow::Error> {
+    let config = ParseConfig {
+        debug: false,
+        max_iterations,
+        ..ParseConfig::default()
+    };
+    let mut node_count = 0;
+    let value =
+        parse_with_config_and_callback(program, config, |_, node_len| node_count = node_len)
+            .and_then(|output| Value::from_node_type(output.node.node_type));
+    match value {
+        Ok(Value::Integer(v)) => Ok(v.to_string()),
+        Ok(value) => Err(anyhow::anyhow!("result is not an integer: {:?}", value)),
+        Err(ParseError::EvaluationLimitExceeded(limit)) => Err(anyhow::anyhow!(
+            "evaluation did not finish within {} iterations (node count: {})",
+            limit,
+            node_count
+        )),
+        Err(e) => Err(e.into()),
+    }
+}
 
-    let message = select_content(args.command.clone())?;
-    let encoded_message = encode(message)?;
+async fn send_command(client: &ICFPCClient, command: Commands) -> Result<String, anyhow::Error> {
+    let message = build_message(command.clone())?;
 
-    let response_message = client.post_message(encoded_message).await?;
-    let decoded_message = match args.command {
+    let response_message = client.post_message(message).await?;
+    let decoded_message = match command {
         // 巨大な文字列を解釈するための問題なので、decode しちゃダメ
         Commands::EfficiencyGet { .. } => response_message,
+        Commands::Index { track } => track.parse_index(&decode(response_message)?).join("\n"),
+        Commands::EfficiencyEval { max_iterations, .. } => {
+            evaluate_efficiency(response_message, max_iterations)?
+        }
         Commands::D3Test { .. } => response_message,
         _ => decode(response_message)?,
     };
-    println!("{}", decoded_message);
+    Ok(decoded_message)
+}
+
+// solution が problem を解けていれば Ok、そうでなければ最初に見つかった違反を返す
+fn verify(track: Track, problem: &str, solution: &str) -> Result<(), anyhow::Error> {
+    let solution = strip_trailing_newline(solution);
+    match track {
+        Track::Lambdaman => simulate_lambdaman(&parse_grid(problem), solution)?,
+        Track::Spaceship => simulate_spaceship(&parse_points(problem)?, solution)?,
+        _ => anyhow::bail!("verification is not supported for {}", track.prefix()),
+    }
+    Ok(())
+}
+
+// message をそのまま path に書き出して、書いたバイト数を返す
+fn write_output(path: &PathBuf, message: &str) -> Result<usize, anyhow::Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, message)?;
+    Ok(message.len())
+}
+
+// (auth token, URL) を決める。環境変数 → コマンドライン引数の順に探す
+// env は環境変数を読む関数 (テストで差し替えられるように引数にしている)
+fn client_config(
+    args: &Args,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<(String, String), anyhow::Error> {
+    // 空の環境変数は設定されていないものとして扱う
+    let lookup = |key: &str, cli: &Option<String>| {
+        env(key)
+            .filter(|value| !value.is_empty())
+            .or_else(|| cli.clone())
+    };
+    let auth_token = lookup("ICFP_TOKEN", &args.token).ok_or_else(|| {
+        anyhow::anyhow!("no auth token: set the ICFP_TOKEN environment variable or pass --token")
+    })?;
+    let url = lookup("ICFP_URL", &args.url).unwrap_or_else(|| DEFAULT_URL.to_string());
+    Ok((auth_token, url))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
+
+    if let Commands::Verify {
+        problem,
+        solution,
+        track,
+    } = &args.command
+    {
+        match verify(*track, &read_content(problem)?, &read_content(solution)?) {
+            Ok(()) => println!("PASS"),
+            Err(e) => {
+                println!("FAIL: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    let (auth_token, url) = client_config(&args, |key| std::env::var(key).ok())?;
+    let client = ICFPCClient::new(auth_token, url);
+
+    let decoded_message = send_command(&client, args.command).await?;
+    match &args.out {
+        Some(path) => {
+            let written = write_output(path, &decoded_message)?;
+            eprintln!("wrote {} bytes to {}", written, path.display());
+        }
+        None => println!("{}", decoded_message),
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // 1 回だけリクエストを受けて response_body を返すサーバーを立てる
+    // 受け取ったリクエストの body を返す
+    async fn mock_server(response_body: String) -> (String, tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/communicate", listener.local_addr().unwrap());
+
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![];
+            let mut chunk = [0u8; 4096];
+            let body_start = loop {
+                let n = stream.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+                if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break pos + 4;
+                }
+            };
+            let header = String::from_utf8_lossy(&buf[..body_start]).to_lowercase();
+            let content_length = header
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .map(|v| v.trim().parse::<usize>().unwrap())
+                .unwrap_or(0);
+            while buf.len() < body_start + content_length {
+                let n = stream.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+            }
+
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                response_body.len(),
+                response_body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            buf[body_start..body_start + content_length].to_vec()
+        });
+        (url, handle)
+    }
+
+    // crate 名の core が std の core と衝突して #[tokio::test] が使えないので、runtime を直接作る
+    #[test]
+    fn test_raw_post_sends_file_verbatim() {
+        let program = "B. S%#(/} B$ L# v# S3/,6%},!-\"$!-!.[}";
+        let filepath = std::env::temp_dir().join("message_sender_raw_post_test.txt");
+        std::fs::write(&filepath, program).unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (request_body, response) = runtime.block_on(async {
+            let (url, handle) = mock_server(encode("Correct".to_string()).unwrap()).await;
+            let client = ICFPCClient::new("token".to_string(), url);
+            let response = send_command(
+                &client,
+                Commands::RawPost {
+                    filepath: filepath.clone(),
+                },
+            )
+            .await
+            .unwrap();
+            (handle.await.unwrap(), response)
+        });
+        std::fs::remove_file(&filepath).unwrap();
+
+        assert_eq!(request_body, program.as_bytes());
+        assert_eq!(response, "Correct");
+    }
+
+    #[test]
+    fn test_write_output() {
+        let dir = std::env::temp_dir().join("message_sender_write_output_test");
+        let path = dir.join("problems").join("spaceship3.txt");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let message = "1 -1\n1 -3\n";
+        assert_eq!(write_output(&path, message).unwrap(), message.len());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), message);
+
+        // 既にあるファイルは上書きする
+        assert_eq!(write_output(&path, "x").unwrap(), 1);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "x");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_index() {
+        let page = "* [lambdaman1] Best score: 33.\n* [lambdaman2] Best score: 44.\n";
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (request_body, response) = runtime.block_on(async {
+            let (url, handle) = mock_server(encode(page.to_string()).unwrap()).await;
+            let client = ICFPCClient::new("token".to_string(), url);
+            let response = send_command(
+                &client,
+                Commands::Index {
+                    track: Track::Lambdaman,
+                },
+            )
+            .await
+            .unwrap();
+            (handle.await.unwrap(), response)
+        });
+        assert_eq!(
+            request_body,
+            encode("get lambdaman".to_string()).unwrap().as_bytes()
+        );
+        assert_eq!(response, "1\n2");
+    }
+
+    #[test]
+    fn test_efficiency_eval() {
+        // 2 + 3 * 4
+        let program = "B+ I# B* I$ I%";
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (request_body, response) = runtime.block_on(async {
+            let (url, handle) = mock_server(program.to_string()).await;
+            let client = ICFPCClient::new("token".to_string(), url);
+            let response = send_command(
+                &client,
+                Commands::EfficiencyEval {
+                    problem_id: "1".to_string(),
+                    max_iterations: 100,
+                },
+            )
+            .await
+            .unwrap();
+            (handle.await.unwrap(), response)
+        });
+        assert_eq!(
+            request_body,
+            encode("get efficiency1".to_string()).unwrap().as_bytes()
+        );
+        assert_eq!(response, "14");
+
+        let err = evaluate_efficiency(program.to_string(), 1).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("within 1 iterations (node count: "),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_client_config_precedence() {
+        fn env(
+            token: Option<&'static str>,
+            url: Option<&'static str>,
+        ) -> impl Fn(&str) -> Option<String> {
+            move |key: &str| match key {
+                "ICFP_TOKEN" => token.map(|s| s.to_string()),
+                "ICFP_URL" => url.map(|s| s.to_string()),
+                _ => None,
+            }
+        }
+        let with_cli = Args::parse_from([
+            "message-sender",
+            "scoreboard",
+            "--token",
+            "cli-token",
+            "--url",
+            "http://cli",
+        ]);
+        let without_cli = Args::parse_from(["message-sender", "scoreboard"]);
+
+        // 環境変数が優先
+        assert_eq!(
+            client_config(&with_cli, env(Some("env-token"), Some("http://env"))).unwrap(),
+            ("env-token".to_string(), "http://env".to_string())
+        );
+        // 環境変数が無い (または空の) 時はコマンドライン引数
+        assert_eq!(
+            client_config(&with_cli, env(Some(""), None)).unwrap(),
+            ("cli-token".to_string(), "http://cli".to_string())
+        );
+        // URL はどちらも無ければ本番
+        assert_eq!(
+            client_config(&without_cli, env(Some("env-token"), None)).unwrap(),
+            ("env-token".to_string(), DEFAULT_URL.to_string())
+        );
+        // token はどちらも無ければエラー
+        let err = client_config(&without_cli, env(None, None)).unwrap_err();
+        assert!(err.to_string().contains("ICFP_TOKEN"));
+    }
+
+    #[test]
+    fn test_verify_lambdaman() {
+        let problem = "###.#...\n...L..##\n.#######\n";
+        assert!(verify(Track::Lambdaman, problem, "LLLDURRRUDRRURR\n").is_ok());
+
+        let err = verify(Track::Lambdaman, problem, "LLLDURRRUDRR").unwrap_err();
+        assert!(err.to_string().contains("(y: 0, x: 5)"));
+    }
+
+    #[test]
+    fn test_verify_spaceship() {
+        let problem = "0 -1\n1 -3\n3 -5\n6 -7\n9 -9\n13 -10\n";
+        assert!(verify(Track::Spaceship, problem, "236659\n").is_ok());
+
+        let err = verify(Track::Spaceship, problem, "2366").unwrap_err();
+        assert!(err.to_string().contains("(9, -9)"));
+        assert!(verify(Track::Efficiency, problem, "1").is_err());
+    }
+
+    #[test]
+    fn test_submit_is_encoded() {
+        let command = Commands::Echo {
+            message: "hi".to_string(),
+        };
+        let message = build_message(command).unwrap();
+        assert_eq!(message, encode("get echo hi".to_string()).unwrap());
+        assert!(message.starts_with('S'));
+    }
+}
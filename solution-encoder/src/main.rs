@@ -1,7 +1,9 @@
-use clap::Parser;
-use num_bigint::BigInt;
+use clap::{Parser, ValueEnum};
+use num_bigint::{BigInt, Sign};
 
-use core::parser::icfpstring::ICFPString;
+use core::parser::ast::{parse, NodeType};
+use core::parser::compress::{compress_string, string_token};
+use core::parser::icfpstring::{strip_trailing_newline, ICFPString};
 use std::fs;
 use std::path::PathBuf;
 
@@ -13,6 +15,59 @@ struct Args {
     /// ファイルパス
     #[arg(short, long)]
     filepath: PathBuf,
+
+    /// 各段階で即値と平方分解のどちらを選んだかを木の形で標準エラーに出力する (--mode int の時のみ)
+    #[arg(long)]
+    explain: bool,
+
+    /// 内容を整数として圧縮して int2str で戻すか、文字列の繰り返しを使って圧縮するか
+    #[arg(long, value_enum, default_value_t = Mode::Int)]
+    mode: Mode,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// 整数にして compress する
+    Int,
+    /// compress_string で同じ部分文字列の繰り返しをまとめる
+    String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Strategy {
+    // I... をそのまま使う
+    Raw,
+    // q * q + r に分解する
+    Square,
+    // 94 進数の桁の文字列を先頭から読んで acc * 94 + d を繰り返す loop で書く
+    DigitExpansion,
+    // 94 進数の桁が同じ塊の繰り返しになっている時、塊を繰り返す loop で書く
+    RepeatedChunk,
+}
+
+// compress がどの値をどう表現したかの記録
+#[derive(Debug)]
+struct Explanation {
+    value: BigInt,
+    strategy: Strategy,
+    length: usize,
+    // Square の時は [r, q] の順
+    children: Vec<Explanation>,
+}
+
+impl Explanation {
+    fn write_tree(&self, depth: usize, out: &mut String) {
+        out.push_str(&format!(
+            "{}{} {:?} len={}\n",
+            "  ".repeat(depth),
+            self.value,
+            self.strategy,
+            self.length
+        ));
+        for child in self.children.iter() {
+            child.write_tree(depth + 1, out);
+        }
+    }
 }
 
 fn get_content(path: &PathBuf) -> Result<String, anyhow::Error> {
@@ -22,33 +77,208 @@ fn get_content(path: &PathBuf) -> Result<String, anyhow::Error> {
 // 生コマンドが入った文字列を返す
 // この文字列を評価すると、Integer(v) が得られる
 fn compress(v: BigInt) -> Result<String, anyhow::Error> {
-    // I"..."
-    let raw_string = ICFPString::from_int(v.clone())
+    compress_with_explanation(v).map(|(s, _)| s)
+}
+
+// 整数 v を表す式の作り方
+trait CompressionStrategy {
+    fn strategy(&self) -> Strategy;
+
+    // v を表す式と、式の中で使った部分の値の Explanation を返す
+    // この方法では v を表せない場合は None
+    fn encode(&self, v: &BigInt) -> Result<Option<(String, Vec<Explanation>)>, anyhow::Error>;
+}
+
+// I... をそのまま書く
+struct RawStrategy;
+
+impl CompressionStrategy for RawStrategy {
+    fn strategy(&self) -> Strategy {
+        Strategy::Raw
+    }
+
+    fn encode(&self, v: &BigInt) -> Result<Option<(String, Vec<Explanation>)>, anyhow::Error> {
+        Ok(Some((format!("I{}", digits(v)?), vec![])))
+    }
+}
+
+// q = floor(sqrt(v)), r = v - q * q として、q と r をそれぞれ再帰的に圧縮する
+struct SquareStrategy;
+
+impl CompressionStrategy for SquareStrategy {
+    fn strategy(&self) -> Strategy {
+        Strategy::Square
+    }
+
+    fn encode(&self, v: &BigInt) -> Result<Option<(String, Vec<Explanation>)>, anyhow::Error> {
+        let q = v.sqrt();
+        let r = v - &q * &q;
+
+        // q * q := "B$ L# B* v# v# I(q)"
+        // q * q + r := "B+ I(r) B$ L# B* v# v# I(q)"
+        let (f_q, e_q) = compress_with_explanation(q)?;
+        let (f_r, e_r) = compress_with_explanation(r)?;
+        Ok(Some((
+            format!("B+ {} B$ L# B* v# v# {}", f_r, f_q),
+            vec![e_r, e_q],
+        )))
+    }
+}
+
+// v = sum(d_i * 94^i) として、桁 d_i を並べた文字列を先頭から畳み込む loop で計算する
+// 各桁は文字列の 1 文字なので、U# で 0..94 の値に戻す
+struct DigitExpansionStrategy;
+
+// expand acc s = if s == "" then acc else expand (acc * 94 + U# (take 1 s)) (drop 1 s) を Y コンビネータで書いたもの
+// 後ろに acc の初期値, 桁の文字列の順に引数を並べる
+const DIGIT_EXPANSION_FUNCTION: &str = concat!(
+    r#"B$ L" B$ L# B$ v" B$ v# v# L# B$ v" B$ v# v# "#,
+    r#"L$ L% L& ? B= v& S v% B$ B$ v$ B+ B* v% I"! U# BT I" v& BD I" v&"#
+);
+
+impl CompressionStrategy for DigitExpansionStrategy {
+    fn strategy(&self) -> Strategy {
+        Strategy::DigitExpansion
+    }
+
+    fn encode(&self, v: &BigInt) -> Result<Option<(String, Vec<Explanation>)>, anyhow::Error> {
+        // 負の数は桁に分けられない
+        if v.sign() == Sign::Minus {
+            return Ok(None);
+        }
+        Ok(Some((
+            format!("B$ B$ {} I! S{}", DIGIT_EXPANSION_FUNCTION, digits(v)?),
+            vec![],
+        )))
+    }
+}
+
+// v を 94 進数で書いた桁が、m 桁の塊 c を k 回繰り返したものになっている時、
+// v = sum(c * (94^m)^i) (i = 0..k) を loop で計算する
+// 桁がちょうど周期的な場合にしか使えない。一般の展開は DigitExpansionStrategy
+struct RepeatedChunkStrategy;
+
+// rep n = if n == 0 then 0 else rep (n - 1) * base + chunk を Y コンビネータで書いたもの
+// 後ろに base, chunk, n の順に引数を並べる
+const REPEATED_CHUNK_FUNCTION: &str = concat!(
+    r#"B$ L" B$ L# B$ v" B$ v# v# L# B$ v" B$ v# v# "#,
+    r#"L$ L% L& L' ? B= v' I! I! B+ B* B$ B$ B$ v$ v% v& B- v' I" v% v&"#
+);
+
+impl CompressionStrategy for RepeatedChunkStrategy {
+    fn strategy(&self) -> Strategy {
+        Strategy::RepeatedChunk
+    }
+
+    fn encode(&self, v: &BigInt) -> Result<Option<(String, Vec<Explanation>)>, anyhow::Error> {
+        let digits = digits(v)?.chars().collect::<Vec<_>>();
+        let Some(m) = shortest_period(&digits) else {
+            return Ok(None);
+        };
+
+        let base = BigInt::from(94).pow(m as u32);
+        let chunk = ICFPString::from_str(digits[..m].to_vec())?.to_int();
+        let count = BigInt::from(digits.len() / m);
+
+        let (f_base, e_base) = compress_with_explanation(base)?;
+        let (f_chunk, e_chunk) = compress_with_explanation(chunk)?;
+        let (f_count, e_count) = compress_with_explanation(count)?;
+        Ok(Some((
+            format!(
+                "B$ B$ B$ {} {} {} {}",
+                REPEATED_CHUNK_FUNCTION, f_base, f_chunk, f_count
+            ),
+            vec![e_base, e_chunk, e_count],
+        )))
+    }
+}
+
+// digits が長さ m の塊の 2 回以上の繰り返しになる最小の m
+fn shortest_period(digits: &[char]) -> Option<usize> {
+    let n = digits.len();
+    (1..=n / 2)
+        .filter(|&m| n.is_multiple_of(m))
+        .find(|&m| digits.chunks(m).all(|chunk| chunk == &digits[..m]))
+}
+
+// v を 94 進数で書いた時の桁 ("I" の後ろに続く部分)
+fn digits(v: &BigInt) -> Result<String, anyhow::Error> {
+    Ok(ICFPString::from_int(v.clone())
         .to_string()?
         .into_iter()
-        .collect::<String>();
-    let bypass_str = format!("I{}", raw_string);
+        .collect::<String>())
+}
+
+// 長さが同じなら先に並んでいるものを選ぶ
+const STRATEGIES: [&dyn CompressionStrategy; 4] = [
+    &SquareStrategy,
+    &RawStrategy,
+    &DigitExpansionStrategy,
+    &RepeatedChunkStrategy,
+];
 
+// compress と同じだが、各段階の選択を Explanation として一緒に返す
+fn compress_with_explanation(v: BigInt) -> Result<(String, Explanation), anyhow::Error> {
     // 94進数で1桁で書けるなら、流石にこっちの方が短そう
-    if v < BigInt::from(94) {
-        Ok(bypass_str)
+    // (Square は 0 と 1 で自分自身に戻ってくるので、ここで再帰を止める)
+    let strategies: &[&dyn CompressionStrategy] = if v < BigInt::from(94) {
+        &[&RawStrategy]
     } else {
-        let q = v.sqrt();
-        let r = v - q.clone() * q.clone();
+        &STRATEGIES
+    };
 
-        // q * q := "B$ L# B* v# v# I(q)"
-        // q * q + r := "B+ I(r) B$ L# B* v# v# I(q)"
-        // int2str(q * q + r) := "U$ B+ I(r) B$ L# B* v# v# I(q)"
-        // cost := len(q) + len(r) +
+    let mut best: Option<(String, Explanation)> = None;
+    for strategy in strategies {
+        let Some((encoded, children)) = strategy.encode(&v)? else {
+            continue;
+        };
+        if best
+            .as_ref()
+            .is_some_and(|(best_encoded, _)| best_encoded.len() <= encoded.len())
+        {
+            continue;
+        }
+        let explanation = Explanation {
+            value: v.clone(),
+            strategy: strategy.strategy(),
+            length: encoded.len(),
+            children,
+        };
+        best = Some((encoded, explanation));
+    }
+    Ok(best.unwrap())
+}
 
-        let f_q = compress(q)?;
-        let f_r = compress(r)?;
+// s を表すプログラムを作る
+// 圧縮したプログラムを評価して s に戻らなければ、警告を出して S... をそのまま使う
+fn encode_string(s: &ICFPString, explain: bool) -> Result<String, anyhow::Error> {
+    let v = s.to_int();
+    let encoded = if explain {
+        let (encoded, explanation) = compress_with_explanation(v)?;
+        let mut tree = String::new();
+        explanation.write_tree(0, &mut tree);
+        eprint!("{}", tree);
+        encoded
+    } else {
+        compress(v)?
+    };
+    verify_or_raw(s, format!("U$ {}", encoded))
+}
 
-        let compressed_string = format!("B+ {} B$ L# B* v# v# {}", f_r, f_q);
-        if bypass_str.len() < compressed_string.len() {
-            Ok(bypass_str)
-        } else {
-            Ok(compressed_string)
+// program を評価して s に戻らなければ、警告を出して S... をそのまま使う
+fn verify_or_raw(s: &ICFPString, program: String) -> Result<String, anyhow::Error> {
+    match parse(program.clone()) {
+        Ok(node) if node.node_type == NodeType::String(s.clone()) => Ok(program),
+        result => {
+            let actual = match result {
+                Ok(node) => format!("{:?}", node.node_type),
+                Err(e) => e.to_string(),
+            };
+            eprintln!(
+                "warning: compressed program does not evaluate to the input ({}), using the raw string",
+                actual
+            );
+            Ok(string_token(s)?)
         }
     }
 }
@@ -59,20 +289,158 @@ fn main() -> Result<(), anyhow::Error> {
 
     // 生文字列を読み込む
 
-    // 以下の2つの中から、短い選択肢を選ぶ
-    // 1. 即値命令(`S...`)
+    // 表現したい文字列 S を数値化して v にし、STRATEGIES の中から v を最も短く書けるものを選ぶ
+    // 1. 即値命令(`I...`)
     // 2. 以下のような再帰的な計算
-    //     1. 表現したい文字列 S を数値化して v に
-    //     2. q = floor(sqrt(v))
-    //     3. r = v - q * q
-    //     4. f(r) と f(v) を計算
-    //     5. s = int2str(f(r)) + (λ.x x*x) f(v)
-    //     6. int2str(s)
-
-    let s = ICFPString::from_encoded_str(&contents.as_str())?;
-    let v = s.to_int();
-    let encoded = compress(v)?;
-    println!("U$ {}", encoded);
+    //     1. q = floor(sqrt(v))
+    //     2. r = v - q * q
+    //     3. f(r) と f(q) を計算
+    //     4. f(r) + (λ.x x*x) f(q)
+    // 3. v の桁の文字列を畳み込んで acc * 94 + d を繰り返す loop
+    // 4. v の桁が塊の繰り返しなら、塊を繰り返す loop
+    // 最後に int2str で文字列に戻す
+    // --mode string の時は整数にせず、compress_string で文字列のまま圧縮する
+
+    let s = ICFPString::from_encoded_str(strip_trailing_newline(&contents))?;
+    let program = match args.mode {
+        Mode::Int => encode_string(&s, args.explain)?,
+        Mode::String => verify_or_raw(&s, compress_string(&s)?)?,
+    };
+    println!("{}", program);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_compress_small_values() {
+        for v in [0, 1, 93] {
+            let program = compress(BigInt::from(v)).unwrap();
+            let result = parse(program.clone()).unwrap();
+            assert_eq!(
+                result.node_type,
+                NodeType::Integer(BigInt::from(v)),
+                "{}",
+                program
+            );
+        }
+    }
+
+    fn evaluate(program: String) -> BigInt {
+        match parse(program.clone()).unwrap().node_type {
+            NodeType::Integer(v) => v,
+            node_type => panic!("{} is evaluated to {:?}", program, node_type),
+        }
+    }
+
+    #[test]
+    fn test_each_strategy_round_trip() {
+        let repeated = ICFPString::from_str("/6~".repeat(40).chars().collect())
+            .unwrap()
+            .to_int();
+        let values = [
+            BigInt::from(94),
+            BigInt::from(1337),
+            BigInt::from(94).pow(30) - 1,
+            BigInt::from(u64::MAX) * 12345,
+            repeated,
+        ];
+        for strategy in STRATEGIES {
+            let mut encoded = 0;
+            for v in values.iter() {
+                let Some((program, _)) = strategy.encode(v).unwrap() else {
+                    continue;
+                };
+                assert_eq!(evaluate(program), *v, "{:?}", strategy.strategy());
+                encoded += 1;
+            }
+            // 桁の展開はどの値でも使え、塊の繰り返しは繰り返しのある 2 つにだけ使える
+            let expected = match strategy.strategy() {
+                Strategy::RepeatedChunk => 2,
+                _ => values.len(),
+            };
+            assert_eq!(encoded, expected, "{:?}", strategy.strategy());
+        }
+    }
+
+    #[test]
+    fn test_repeated_chunk_on_repeated_digits() {
+        // 94^30 - 1 は "~" が 30 個
+        let v = BigInt::from(94).pow(30) - 1;
+        assert!(RepeatedChunkStrategy.encode(&v).unwrap().is_some());
+        assert!(RepeatedChunkStrategy
+            .encode(&BigInt::from(1337))
+            .unwrap()
+            .is_none());
+
+        // 長い繰り返しは loop で書いた方が短い
+        let v = ICFPString::from_str("/6~".repeat(200).chars().collect())
+            .unwrap()
+            .to_int();
+        let (encoded, explanation) = compress_with_explanation(v.clone()).unwrap();
+        assert_eq!(explanation.strategy, Strategy::RepeatedChunk);
+        assert!(encoded.len() < 200, "{}", encoded);
+        assert_eq!(evaluate(encoded), v);
+    }
+
+    #[test]
+    fn test_random_round_trip() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        for _ in 0..200 {
+            // 繰り返しが出やすいように、使う桁の種類も乱数で絞る
+            let len = rng.gen_range(1..80);
+            let alphabet = rng.gen_range(1..=94);
+            let bytes = (0..len)
+                .map(|_| rng.gen_range(0..alphabet))
+                .collect::<Vec<u8>>();
+
+            let v = ICFPString::new(bytes.clone()).to_int();
+            assert_eq!(evaluate(compress(v.clone()).unwrap()), v);
+
+            let s = ICFPString::new(bytes);
+            let program = encode_string(&s, false).unwrap();
+            assert_eq!(
+                parse(program.clone()).unwrap().node_type,
+                NodeType::String(s),
+                "{}",
+                program
+            );
+        }
+    }
+
+    #[test]
+    fn test_fallback_to_raw_string() {
+        // 先頭の 'a' は 0 の桁なので、整数にすると消えてしまう
+        let s = ICFPString::from_encoded_str("aaab").unwrap();
+        let program = encode_string(&s, false).unwrap();
+        assert!(program.starts_with('S'), "{}", program);
+        assert_eq!(parse(program).unwrap().node_type, NodeType::String(s));
+    }
+
+    #[test]
+    fn test_explain_root_strategy() {
+        // 小さい値はそのまま
+        let (_, explanation) = compress_with_explanation(BigInt::from(93)).unwrap();
+        assert_eq!(explanation.strategy, Strategy::Raw);
+        assert!(explanation.children.is_empty());
+
+        // 平方数に近い大きな値は分解した方が短い
+        let q = BigInt::from(94).pow(40) + BigInt::from(1);
+        let v = q.clone() * q.clone() + BigInt::from(5);
+        let (encoded, explanation) = compress_with_explanation(v.clone()).unwrap();
+        assert_eq!(explanation.strategy, Strategy::Square);
+        assert_eq!(explanation.value, v);
+        assert_eq!(explanation.length, encoded.len());
+        assert_eq!(explanation.children[0].value, BigInt::from(5));
+        assert_eq!(explanation.children[1].value, q);
+
+        let mut tree = String::new();
+        explanation.write_tree(0, &mut tree);
+        assert!(tree.lines().next().unwrap().contains("Square"));
+        assert_eq!(encoded, compress(v).unwrap());
+    }
+}
@@ -1,17 +1,105 @@
+use clap::{Parser, ValueEnum};
+use core::parser::icfpstring::strip_trailing_newline;
+use core::solver::{split_records, Comparison, SolveError};
+use core::spaceship::simulate_spaceship;
 use core::tsp::{
     array_solution::ArraySolution,
     distance::DistanceFunction,
-    lkh::{self, LKHConfig},
+    lkh::{self, KickSchedule, LKHConfig},
     opt3,
     solution::Solution,
+    DEFAULT_NEIGHBOR_LIST_SIZE,
 };
 use std::{
     collections::HashSet,
-    io::{self, BufRead},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
     path::PathBuf,
     str::FromStr,
 };
 
+use rayon::prelude::*;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// 問題ファイルのパス。省略した場合は標準入力から読む
+    #[arg(short, long)]
+    input: Option<PathBuf>,
+
+    /// 入力を空行で区切った複数の問題として順に解き、解を空行で区切って出力する
+    #[arg(long, conflicts_with_all = ["compare_baseline", "trace_csv"])]
+    multi: bool,
+
+    /// 指定した max_depth (例: 2,3,4,5) それぞれで LKH を実行して評価値を比較する
+    #[arg(long, value_delimiter = ',')]
+    sweep_depth: Vec<usize>,
+
+    /// 解いた後に軌跡を tick,x,y,vx,vy,action の CSV として書き出す
+    #[arg(long)]
+    trace_csv: Option<PathBuf>,
+
+    /// 乱数の seed。指定すると LKH を時間ではなく --kick-limit 回の kick で打ち切るので、
+    /// 同じ seed と入力なら同じ出力になる
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// --seed を指定した時に LKH で行う kick の回数
+    #[arg(long, default_value_t = 1000)]
+    kick_limit: usize,
+
+    /// TSP の順序を変えてビームサーチを何回実行するか。最も手数が少ないものを出力する
+    #[arg(long, default_value_t = 1)]
+    restarts: usize,
+
+    /// 保存済みの解と新しく求めた解の手数を比べる。新しい解の方が長ければエラー終了する
+    #[arg(long)]
+    compare_baseline: Option<PathBuf>,
+
+    /// 次の点を通り過ぎてから止まるまでの距離がこの値を超える state をビームから捨てる
+    /// 省略した場合は速度で枝刈りしない
+    #[arg(long)]
+    speed_slack: Option<i64>,
+
+    /// ビームサーチで各 tick に残す state の数
+    #[arg(long, default_value_t = 1000)]
+    beam_width: usize,
+
+    /// ビームサーチでこの tick 数だけ次の点に進めなければ、ビーム幅を広げる
+    /// 幅を広げきっても進めなければ諦める
+    #[arg(long, default_value_t = DEFAULT_STALL_LIMIT)]
+    stall_limit: usize,
+
+    /// TSP の順序を求める LKH の制限時間 (ミリ秒)。--seed を指定した場合は kick の回数で打ち切る
+    #[arg(long, default_value_t = 10_000)]
+    time_limit_ms: u128,
+
+    /// ビームの評価値で、次の点に向かう速度成分 (速度と目標への向きの内積) に掛ける重み
+    /// 0 なら速度を考慮しない
+    #[arg(long, default_value_t = 0)]
+    velocity_weight: i64,
+
+    /// LKH の局所最適に達する度に、連続する 1 - 3 点を移す Or-opt も試す
+    #[arg(long)]
+    or_opt: bool,
+
+    /// LKH で並列に kick する thread の数
+    #[arg(long, default_value_t = 1)]
+    lkh_threads: usize,
+
+    /// TSP で訪問順を決める時の点の間の距離
+    #[arg(long, value_enum, default_value_t = DistanceKind::Euclid)]
+    distance: DistanceKind,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum DistanceKind {
+    /// ユークリッド距離
+    Euclid,
+    /// 静止状態から移動するのにかかる tick 数 (ManhattanTimeDistance)
+    Time,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 struct Point {
     x: i64,
     y: i64,
@@ -23,13 +111,19 @@ impl Point {
     }
 }
 
-fn read_input() -> Result<Vec<Point>, anyhow::Error> {
-    let stdin = io::stdin();
+fn open_input(path: &Option<PathBuf>) -> Result<Box<dyn BufRead>, anyhow::Error> {
+    match path {
+        Some(path) => Ok(Box::new(BufReader::new(File::open(path)?))),
+        None => Ok(Box::new(BufReader::new(io::stdin()))),
+    }
+}
+
+fn read_input(reader: impl BufRead) -> Result<Vec<Point>, SolveError> {
     let mut grid: Vec<Point> = Vec::new();
     grid.push(Point::new(0, 0));
 
-    for line in stdin.lock().lines() {
-        let line = line?;
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| SolveError::input_io(index + 1, e))?;
 
         if line.is_empty() {
             break;
@@ -38,13 +132,42 @@ fn read_input() -> Result<Vec<Point>, anyhow::Error> {
         let nums = line
             .split_whitespace()
             .map(|s| s.parse::<i64>())
-            .collect::<Result<Vec<_>, _>>()?;
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| SolveError::input_parse(index + 1, &line, e))?;
+        if nums.len() != 2 {
+            return Err(SolveError::input_parse(
+                index + 1,
+                &line,
+                format!("expected 2 numbers, found {}", nums.len()),
+            ));
+        }
 
         grid.push(Point::new(nums[0], nums[1]));
     }
+
+    // 先頭の原点は入力の点ではないので、重複除去の対象にしない
+    // 入力に原点と同じ点があっても残す。出発時に原点にいることは訪問にならないので、一度戻ってくる必要がある
+    let (deduped, removed) = dedup_points(grid.split_off(1));
+    if removed > 0 {
+        eprintln!("removed {} duplicate points", removed);
+    }
+    grid.extend(deduped);
     Ok(grid)
 }
 
+// 同じ座標の点は 1 回訪れれば両方訪れたことになるので、最初の 1 つだけを残す
+// 返り値は (重複を除いた点列, 除いた点の数)
+fn dedup_points(point_list: Vec<Point>) -> (Vec<Point>, usize) {
+    let original_len = point_list.len();
+    let mut seen = HashSet::new();
+    let deduped = point_list
+        .into_iter()
+        .filter(|p| seen.insert((p.x, p.y)))
+        .collect::<Vec<_>>();
+    let removed = original_len - deduped.len();
+    (deduped, removed)
+}
+
 struct Problem {
     point_list: Vec<Point>,
     name: String,
@@ -72,7 +195,58 @@ impl DistanceFunction for Problem {
     }
 }
 
-fn tsp(problem: &Problem) -> Vec<usize> {
+// 静止状態から、各軸を独立に最短で動いた時にかかる tick 数を距離とする
+// 1 軸で t tick 加速し続けると t(t+1)/2 進むので、|d| 進むのに必要な最小の t を x, y で求めて大きい方を取る
+// 到着時の速度は気にしないので、実際に必要な tick 数の下界になる
+struct ManhattanTimeDistance<'a> {
+    problem: &'a Problem,
+}
+
+impl ManhattanTimeDistance<'_> {
+    fn ticks_to_travel(d: i64) -> i64 {
+        let d = d.abs();
+        let mut t = (((8 * d + 1) as f64).sqrt() as i64 - 1) / 2;
+        // 浮動小数点の誤差を整数で補正する
+        while t * (t + 1) / 2 < d {
+            t += 1;
+        }
+        while t > 0 && (t - 1) * t / 2 >= d {
+            t -= 1;
+        }
+        t
+    }
+}
+
+impl DistanceFunction for ManhattanTimeDistance<'_> {
+    fn distance(&self, id1: u32, id2: u32) -> i64 {
+        let p1 = &self.problem.point_list[id1 as usize];
+        let p2 = &self.problem.point_list[id2 as usize];
+        Self::ticks_to_travel(p1.y - p2.y).max(Self::ticks_to_travel(p1.x - p2.x))
+    }
+
+    fn dimension(&self) -> u32 {
+        self.problem.dimension()
+    }
+
+    fn name(&self) -> String {
+        self.problem.name()
+    }
+}
+
+// seed は args.seed の代わりに使う (restart 毎に変えるため)
+fn tsp(problem: &Problem, args: &Args, seed: Option<u64>) -> Vec<usize> {
+    match args.distance {
+        DistanceKind::Euclid => tsp_order(problem, args, seed),
+        DistanceKind::Time => tsp_order(&ManhattanTimeDistance { problem }, args, seed),
+    }
+}
+
+// distance で TSP を解き、原点 (0 番) から始まる訪問順を返す
+fn tsp_order(
+    problem: &(impl DistanceFunction + Sync),
+    args: &Args,
+    seed: Option<u64>,
+) -> Vec<usize> {
     let path = "spaceship_cache";
 
     let init_solution = ArraySolution::new(problem.dimension() as usize);
@@ -83,32 +257,44 @@ fn tsp(problem: &Problem) -> Vec<usize> {
             use_neighbor_cache: false,
             debug: false,
             cache_filepath: PathBuf::from_str(path).unwrap(),
+            seed,
+            neighbor_limited: true,
+            neighbor_list_size: DEFAULT_NEIGHBOR_LIST_SIZE,
+            max_time_ms: None,
+            max_rounds: None,
         },
     );
 
-    let final_solution = lkh::solve(
-        problem,
-        init_solution,
-        LKHConfig {
-            use_neighbor_cache: false,
-            cache_filepath: PathBuf::from_str(path).unwrap(),
-            debug: false,
-            time_ms: 10_000,
-            start_kick_step: 5,
-            kick_step_diff: 10,
-            end_kick_step: problem.dimension() as usize / 10,
-            fail_count_threashold: 50,
-            max_depth: 6,
-        },
-    );
+    let lkh_config = |max_depth| LKHConfig {
+        use_neighbor_cache: false,
+        cache_filepath: PathBuf::from_str(path).unwrap(),
+        debug: false,
+        time_ms: args.time_limit_ms,
+        start_kick_step: 5,
+        kick_step_diff: 10,
+        end_kick_step: problem.dimension() as usize / 10,
+        fail_count_threashold: 50,
+        max_depth,
+        kick_schedule: KickSchedule::Linear,
+        seed,
+        kick_limit: seed.map(|_| args.kick_limit),
+        neighbor_list_size: DEFAULT_NEIGHBOR_LIST_SIZE,
+        on_improvement: None,
+        checkpoint_filepath: None,
+        two_level_tree_threshold: lkh::DEFAULT_TWO_LEVEL_TREE_THRESHOLD,
+        use_or_opt: args.or_opt,
+        target_eval: None,
+        gap_stop: None,
+        num_threads: args.lkh_threads,
+    };
 
-    let mut ret = vec![];
-    let mut start = 0;
-    for _ in 0..problem.dimension() {
-        ret.push(start as usize);
-        start = final_solution.next(start as u32) as usize;
-    }
-    ret
+    let final_solution = if args.sweep_depth.is_empty() {
+        lkh::solve(problem, init_solution, lkh_config(6))
+    } else {
+        lkh::sweep_depth_best(problem, &init_solution, &args.sweep_depth, lkh_config)
+    };
+
+    final_solution.iter_order(0).map(|id| id as usize).collect()
 }
 
 #[derive(Debug, Clone)]
@@ -122,6 +308,18 @@ struct State {
 }
 
 impl State {
+    // 原点で静止している初期状態
+    fn new() -> State {
+        State {
+            node_index: 1,
+            vy: 0,
+            vx: 0,
+            y: 0,
+            x: 0,
+            action_buffer: vec![],
+        }
+    }
+
     fn apply_action(&mut self, action: usize, problem: &Problem, coord_order: &Vec<usize>) {
         let (dy, dx) = ACTION_LIST[action];
         self.vy += dy;
@@ -146,15 +344,28 @@ impl State {
     }
 }
 
-fn evaluate(problem: &Problem, state: &State) -> (usize, i64) {
+// (残りの点の数, 次の点までの距離の 2 乗 - velocity_weight * 速度と次の点への向きの内積) の小さい方が良い
+// 同じ距離なら、次の点に向かって速く動いている state ほど評価が良くなる
+fn evaluate(
+    problem: &Problem,
+    state: &State,
+    coord_order: &[usize],
+    velocity_weight: i64,
+) -> (usize, i64) {
     if state.node_index == problem.point_list.len() {
         (0, 0)
     } else {
-        let dy = problem.point_list[state.node_index].y - state.y;
-        let dx = problem.point_list[state.node_index].x - state.x;
+        // 次に向かう点は TSP の順序で node_index 番目の点
+        let target = &problem.point_list[coord_order[state.node_index]];
+        let dy = target.y - state.y;
+        let dx = target.x - state.x;
         let dist2 = dy * dy + dx * dx;
+        let alignment = state.vy * dy + state.vx * dx;
 
-        (problem.point_list.len() + 1 - state.node_index, dist2)
+        (
+            problem.point_list.len() + 1 - state.node_index,
+            dist2 - velocity_weight * alignment,
+        )
     }
 }
 
@@ -179,31 +390,115 @@ struct StateDiff {
     score: (usize, i64),
 }
 
-fn main() -> Result<(), anyhow::Error> {
-    // ユークリッド距離で TSP を解く
-    // この順序で訪れることを強く前提に置いて、ビームサーチで手順を求める
-    let coords = read_input()?;
-    let problem = Problem::new(coords, "spaceship".to_string());
+type StateKey = (usize, i64, i64, i64, i64);
 
-    let coord_order = tsp(&problem);
+// 各 state に 9 通りの action を適用した候補を列挙する
+// parallel の場合でも、候補の順序は直列の場合と同じになる
+fn expand_candidates(
+    states: &[State],
+    problem: &Problem,
+    coord_order: &Vec<usize>,
+    config: &BeamConfig,
+) -> Vec<(StateDiff, StateKey)> {
+    let expand = |(si, s): (usize, &State)| {
+        (0..9)
+            .map(|action| {
+                let mut state = s.clone();
+                state.apply_action(action, problem, coord_order);
+                let diff = StateDiff {
+                    state_index: si,
+                    action,
+                    score: evaluate(problem, &state, coord_order, config.velocity_weight),
+                };
+                let key = (state.node_index, state.y, state.x, state.vy, state.vx);
+                (diff, key)
+            })
+            .collect::<Vec<_>>()
+    };
 
-    // beam search
-    let mut state_buffer = [
-        vec![State {
-            node_index: 1,
-            vy: 0,
-            vx: 0,
-            y: 0,
-            x: 0,
-            action_buffer: vec![],
-        }],
-        vec![],
-    ];
+    if config.parallel {
+        states
+            .par_iter()
+            .enumerate()
+            .flat_map_iter(expand)
+            .collect()
+    } else {
+        states.iter().enumerate().flat_map(expand).collect()
+    }
+}
+
+// 進めなくなってから、ビーム幅を広げるまでの tick 数の既定値
+const DEFAULT_STALL_LIMIT: usize = 1000;
+// 進めなくなった時に広げるビーム幅の上限 (元の幅の何倍まで広げるか)
+const MAX_BEAM_WIDEN_FACTOR: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+struct BeamConfig {
+    beam_width: usize,
+    parallel: bool,
+    // この tick 数だけ訪れた点が増えなければ、ビーム幅を 2 倍にする。None なら広げない
+    stall_limit: Option<usize>,
+    // ビーム幅を広げる上限。これを超えても進めなければ諦める
+    max_beam_width: usize,
+    // 速度による枝刈りの許容量。None なら枝刈りしない
+    speed_slack: Option<i64>,
+    // この手数で全ての点を訪れられなければ諦める。None なら制限しない
+    max_steps: Option<usize>,
+    // evaluate で速度の向きに掛ける重み
+    velocity_weight: i64,
+}
+
+impl BeamConfig {
+    fn new(beam_width: usize, parallel: bool) -> BeamConfig {
+        BeamConfig {
+            beam_width,
+            parallel,
+            stall_limit: Some(DEFAULT_STALL_LIMIT),
+            max_beam_width: beam_width * MAX_BEAM_WIDEN_FACTOR,
+            speed_slack: None,
+            max_steps: None,
+            velocity_weight: 0,
+        }
+    }
+}
+
+// 次に向かう点に対して速すぎる state か
+// 各軸で、今から最大限減速しても目標の座標を slack より大きく通り過ぎてしまうなら、
+// その点に辿り着くには大きく引き返す必要があるので候補から外す
+fn is_too_fast(problem: &Problem, coord_order: &[usize], key: &StateKey, slack: i64) -> bool {
+    let &(node_index, y, x, vy, vx) = key;
+    if node_index == problem.point_list.len() {
+        return false;
+    }
+    let target = &problem.point_list[coord_order[node_index]];
+    let overshoots = |pos: i64, v: i64, target: i64| {
+        // 速度 v から 1 ずつ減速して止まるまでに進む距離
+        let stop_distance = v.abs() * (v.abs() - 1) / 2;
+        let ahead = (target - pos) * v.signum();
+        stop_distance - ahead > slack
+    };
+    overshoots(y, vy, target.y) || overshoots(x, vx, target.x)
+}
+
+// 全ての点を訪れる action の列を返す
+// 枝刈りで候補が無くなるか、max_steps 手で訪れきれないか、
+// ビーム幅を max_beam_width まで広げても進めなくなった場合は None
+fn beam_search(
+    problem: &Problem,
+    coord_order: &Vec<usize>,
+    config: &BeamConfig,
+) -> Option<Vec<u8>> {
+    let mut state_buffer = [vec![State::new()], vec![]];
 
-    let beam_width = 1000;
     let mut state_diff: Vec<StateDiff> = vec![];
-    let mut state_table = HashSet::<(usize, i64, i64, i64, i64)>::new();
+    let mut state_table = HashSet::<StateKey>::new();
+    let mut beam_width = config.beam_width;
+    // 最後に訪れた点が増えた tick と、その時の node_index
+    let mut last_progress = (0, State::new().node_index);
     for iter in 0.. {
+        if config.max_steps.is_some_and(|max_steps| iter >= max_steps) {
+            return None;
+        }
         eprintln!(
             "iter: {}, node_index: {}",
             iter, state_buffer[0][0].node_index
@@ -212,44 +507,634 @@ fn main() -> Result<(), anyhow::Error> {
         state_diff.clear();
         state_table.clear();
 
-        for (si, s) in state_buffer[0].iter().enumerate() {
-            for action in 0..9 {
-                let mut state = s.clone();
-                state.apply_action(action, &problem, &coord_order);
-                let (score, dist2) = evaluate(&problem, &state);
-                let diff = StateDiff {
-                    state_index: si,
-                    action,
-                    score: (score, dist2),
-                };
-                if state_table.insert((state.node_index, state.y, state.x, state.vy, state.vx)) {
-                    state_diff.push(diff);
-                }
+        // 重複除去は列挙順に直列で行うので、並列化しても結果は変わらない
+        let candidates = expand_candidates(&state_buffer[0], problem, coord_order, config);
+        for (diff, key) in candidates {
+            if config
+                .speed_slack
+                .is_some_and(|slack| is_too_fast(problem, coord_order, &key, slack))
+            {
+                continue;
+            }
+            if state_table.insert(key) {
+                state_diff.push(diff);
             }
         }
 
-        state_diff.sort_by_key(|v| v.score);
+        state_diff.sort_by_key(|v| (v.score, v.state_index, v.action));
         state_diff.truncate(beam_width);
+        if state_diff.is_empty() {
+            return None;
+        }
 
         for diff in state_diff.iter() {
-            let state = state_buffer[0][diff.state_index].clone();
-            let mut state = state.clone();
-            state.apply_action(diff.action, &problem, &coord_order);
+            let mut state = state_buffer[0][diff.state_index].clone();
+            state.apply_action(diff.action, problem, coord_order);
             state_buffer[1].push(state);
         }
 
         state_buffer.swap(0, 1);
         state_buffer[1].clear();
 
-        if state_buffer[0][0].node_index == problem.point_list.len() {
+        // 先頭の state は残りの点が最も少ない
+        let node_index = state_buffer[0][0].node_index;
+        if node_index == problem.point_list.len() {
             break;
         }
+        if node_index > last_progress.1 {
+            last_progress = (iter, node_index);
+        } else if config
+            .stall_limit
+            .is_some_and(|stall_limit| iter - last_progress.0 >= stall_limit)
+        {
+            if beam_width >= config.max_beam_width {
+                eprintln!(
+                    "beam search stalled: visited {} / {} points in {} moves",
+                    node_index - 1,
+                    problem.point_list.len() - 1,
+                    iter + 1
+                );
+                return None;
+            }
+            beam_width = (beam_width * 2).min(config.max_beam_width);
+            eprintln!(
+                "no progress for {} moves, widening beam to {}",
+                iter - last_progress.0,
+                beam_width
+            );
+            last_progress.0 = iter;
+        }
+    }
+
+    Some(state_buffer[0][0].action_buffer.clone())
+}
+
+// action_list (1 - 9 の数字) を初期状態から再生して、各 tick の位置と速度を CSV で書き出す
+// tick 0 は初期状態なので action は空になる
+fn write_trace_csv(
+    mut writer: impl Write,
+    action_list: &[u8],
+    problem: &Problem,
+    coord_order: &Vec<usize>,
+) -> io::Result<()> {
+    let mut state = State::new();
+    writeln!(writer, "tick,x,y,vx,vy,action")?;
+    writeln!(
+        writer,
+        "0,{},{},{},{},",
+        state.x, state.y, state.vx, state.vy
+    )?;
+    for (tick, &action) in action_list.iter().enumerate() {
+        state.apply_action(action as usize - 1, problem, coord_order);
+        writeln!(
+            writer,
+            "{},{},{},{},{},{}",
+            tick + 1,
+            state.x,
+            state.y,
+            state.vx,
+            state.vy,
+            action
+        )?;
+    }
+    Ok(())
+}
+
+// restart 毎に order_of(restart) で訪問順を作ってビームサーチし、最も手数が少ない解を返す
+// 手数が同じなら先に見つかった方を使う。全ての restart で解が見つからなければ None
+fn best_of_restarts(
+    problem: &Problem,
+    restarts: usize,
+    config: &BeamConfig,
+    mut order_of: impl FnMut(usize) -> Vec<usize>,
+) -> Option<(Vec<usize>, Vec<u8>)> {
+    let mut best: Option<(Vec<usize>, Vec<u8>)> = None;
+    for restart in 0..restarts.max(1) {
+        let coord_order = order_of(restart);
+        let Some(action_list) = beam_search(problem, &coord_order, config) else {
+            eprintln!("restart: {}, beam search failed", restart);
+            continue;
+        };
+        eprintln!("restart: {}, moves: {}", restart, action_list.len());
+
+        if best
+            .as_ref()
+            .is_none_or(|(_, best_list)| action_list.len() < best_list.len())
+        {
+            best = Some((coord_order, action_list));
+        }
+    }
+    best
+}
+
+// 訪れるべき点の一覧
+// 先頭の原点は入力の点ではないので、訪問の確認には含めない
+fn target_points(problem: &Problem) -> Vec<(i64, i64)> {
+    problem.point_list[1..].iter().map(|p| (p.x, p.y)).collect()
+}
+
+// 両方の解が全ての点を訪れることを確認してから手数を比べ、結果を標準エラーに出す
+fn compare_baseline(
+    problem: &Problem,
+    action_list: &str,
+    baseline: &str,
+) -> Result<Comparison, anyhow::Error> {
+    let points = target_points(problem);
+    simulate_spaceship(&points, baseline)
+        .map_err(|e| anyhow::anyhow!("baseline is invalid: {}", e))?;
+    simulate_spaceship(&points, action_list)?;
+
+    let comparison = Comparison::of(action_list.len(), baseline.len());
+    eprintln!(
+        "{} than baseline (new: {}, baseline: {})",
+        comparison,
+        action_list.len(),
+        baseline.len()
+    );
+    Ok(comparison)
+}
+
+// 標準出力に出す内容 (action の列) を返す
+fn run(args: &Args, reader: impl BufRead) -> Result<String, anyhow::Error> {
+    // ユークリッド距離で TSP を解く
+    // この順序で訪れることを強く前提に置いて、ビームサーチで手順を求める
+    let coords = read_input(reader)?;
+    let problem = Problem::new(coords, "spaceship".to_string());
+
+    // beam search
+    // 候補の並びは (score, state_index, action) で全順序が付くので、並列化しても結果は決定的
+    // restart 毎に LKH の seed を変えて、別の訪問順を作る
+    let config = BeamConfig {
+        speed_slack: args.speed_slack,
+        velocity_weight: args.velocity_weight,
+        stall_limit: Some(args.stall_limit),
+        ..BeamConfig::new(args.beam_width, true)
+    };
+    let (coord_order, action_list) =
+        best_of_restarts(&problem, args.restarts, &config, |restart| {
+            tsp(
+                &problem,
+                args,
+                args.seed.map(|seed| seed.wrapping_add(restart as u64)),
+            )
+        })
+        .ok_or_else(|| anyhow::anyhow!("beam search could not visit all points"))?;
+
+    if let Some(path) = &args.trace_csv {
+        let writer = BufWriter::new(File::create(path)?);
+        write_trace_csv(writer, &action_list, &problem, &coord_order)?;
     }
 
-    for action in state_buffer[0][0].action_buffer.iter() {
-        print!("{}", action);
+    let mut output = action_list
+        .iter()
+        .map(|action| action.to_string())
+        .collect::<String>();
+    // ビームサーチの状態と実際の軌道がずれていたら、提出する前にここで止める
+    simulate_spaceship(&target_points(&problem), &output)
+        .map_err(|e| anyhow::anyhow!("beam search produced an invalid route: {}", e))?;
+    if let Some(baseline_path) = &args.compare_baseline {
+        let baseline = fs::read_to_string(baseline_path)?;
+        let baseline = strip_trailing_newline(&baseline);
+        if compare_baseline(&problem, &output, baseline)? == Comparison::Worse {
+            anyhow::bail!("regression: new solution is longer than baseline");
+        }
     }
-    println!("");
+    output.push('\n');
+    Ok(output)
+}
 
+// --multi 用。空行で区切られた問題を 1 つずつ run で解いて、問題の順に解を返す
+fn run_multi(args: &Args, reader: impl BufRead) -> Result<Vec<String>, anyhow::Error> {
+    split_records(reader)?
+        .iter()
+        .enumerate()
+        .map(|(index, record)| {
+            eprintln!("problem: {}", index + 1);
+            run(args, io::Cursor::new(record))
+                .map_err(|e| anyhow::anyhow!("problem {}: {}", index + 1, e))
+        })
+        .collect()
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
+    if args.multi {
+        // 各解は改行で終わるので、間に空行が 1 つ入る
+        let outputs = run_multi(&args, open_input(&args.input)?)?;
+        print!("{}", outputs.join("\n"));
+        return Ok(());
+    }
+    let output = run(&args, open_input(&args.input)?)?;
+    print!("{}", output);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_input_from_file_and_stdin() {
+        let input = "1 -1\n1 -3\n2 -5\n";
+
+        let filepath = std::env::temp_dir().join("spaceship_read_input_test.txt");
+        std::fs::write(&filepath, input).unwrap();
+
+        let from_file = read_input(open_input(&Some(filepath.clone())).unwrap()).unwrap();
+        let from_reader = read_input(io::Cursor::new(input)).unwrap();
+        std::fs::remove_file(&filepath).unwrap();
+
+        assert_eq!(from_file, from_reader);
+        // 原点が先頭に追加される
+        assert_eq!(from_file.len(), 4);
+        assert_eq!(from_file[0], Point::new(0, 0));
+    }
+
+    #[test]
+    fn test_read_input_reports_line() {
+        let input = "1 -1\n1 -3\n2 abc\n";
+        let err = read_input(io::Cursor::new(input)).unwrap_err();
+        let SolveError::InputParse { line, message } = &err;
+        assert_eq!(*line, 3);
+        assert!(message.contains("2 abc"));
+    }
+
+    #[test]
+    fn test_parallel_beam_search() {
+        let point_list = vec![
+            Point::new(0, 0),
+            Point::new(1, -1),
+            Point::new(1, -3),
+            Point::new(2, -5),
+            Point::new(2, -8),
+            Point::new(3, -10),
+        ];
+        let problem = Problem::new(point_list, "test".to_string());
+        let coord_order = (0..problem.point_list.len()).collect::<Vec<_>>();
+
+        let serial = beam_search(&problem, &coord_order, &BeamConfig::new(10, false)).unwrap();
+        let parallel = beam_search(&problem, &coord_order, &BeamConfig::new(10, true)).unwrap();
+        assert_eq!(serial.len(), parallel.len());
+        assert_eq!(serial, parallel);
+    }
+
+    #[test]
+    fn test_write_trace_csv() {
+        let point_list = vec![
+            Point::new(0, 0),
+            Point::new(1, -1),
+            Point::new(1, -3),
+            Point::new(2, -5),
+        ];
+        let problem = Problem::new(point_list, "test".to_string());
+        let coord_order = (0..problem.point_list.len()).collect::<Vec<_>>();
+        let action_list = beam_search(&problem, &coord_order, &BeamConfig::new(10, false)).unwrap();
+
+        let mut buffer = vec![];
+        write_trace_csv(&mut buffer, &action_list, &problem, &coord_order).unwrap();
+        let csv = String::from_utf8(buffer).unwrap();
+
+        let rows = csv.lines().skip(1).collect::<Vec<_>>();
+        assert_eq!(rows.len(), action_list.len() + 1);
+
+        let last = rows.last().unwrap().split(',').collect::<Vec<_>>();
+        let target = &problem.point_list[*coord_order.last().unwrap()];
+        assert_eq!(last[1].parse::<i64>().unwrap(), target.x);
+        assert_eq!(last[2].parse::<i64>().unwrap(), target.y);
+    }
+
+    #[test]
+    fn test_dedup_points() {
+        let input = "1 -1\n1 -3\n1 -1\n2 -5\n1 -3\n1 -1\n";
+        let point_list = read_input(io::Cursor::new(input)).unwrap();
+        // 原点 + 異なる 3 点
+        assert_eq!(point_list.len(), 4);
+
+        let (_, removed) = dedup_points(vec![
+            Point::new(1, 1),
+            Point::new(1, 1),
+            Point::new(2, 1),
+            Point::new(1, 1),
+        ]);
+        assert_eq!(removed, 2);
+
+        // 重複を除いた問題の解で、元の全ての点を訪れている
+        let problem = Problem::new(point_list, "test".to_string());
+        let coord_order = (0..problem.point_list.len()).collect::<Vec<_>>();
+        let action_list = beam_search(&problem, &coord_order, &BeamConfig::new(10, false)).unwrap();
+
+        let mut state = State::new();
+        let mut visited = HashSet::new();
+        for &action in action_list.iter() {
+            state.apply_action(action as usize - 1, &problem, &coord_order);
+            visited.insert((state.x, state.y));
+        }
+        for line in input.lines() {
+            let nums = line
+                .split_whitespace()
+                .map(|s| s.parse::<i64>().unwrap())
+                .collect::<Vec<_>>();
+            assert!(visited.contains(&(nums[0], nums[1])));
+        }
+    }
+
+    #[test]
+    fn test_run_with_duplicate_points() {
+        // 重複した点と、原点と同じ点を含む
+        let input = "1 -1\n0 0\n1 -3\n1 -1\n2 -5\n0 0\n1 -3\n";
+        let args = Args::parse_from(["spaceship-solver", "--seed", "1", "--kick-limit", "20"]);
+
+        let output = run(&args, io::Cursor::new(input)).unwrap();
+        // 元の入力の全ての点 (重複を含む) を訪れている
+        let points = core::spaceship::parse_points(input).unwrap();
+        assert_eq!(points.len(), 7);
+        simulate_spaceship(&points, strip_trailing_newline(&output)).unwrap();
+    }
+
+    #[test]
+    fn test_run_multi() {
+        let first = "1 -1\n1 -3\n2 -5\n";
+        let second = "-1 4\n-3 2\n";
+        let input = format!("{}\n{}", first, second);
+        let args = Args::parse_from(["spaceship-solver", "--seed", "1", "--kick-limit", "20"]);
+
+        let outputs = run_multi(&args, io::Cursor::new(input)).unwrap();
+        assert_eq!(outputs.len(), 2);
+        for (problem, output) in [first, second].iter().zip(outputs.iter()) {
+            let points = core::spaceship::parse_points(problem).unwrap();
+            simulate_spaceship(&points, strip_trailing_newline(output)).unwrap();
+        }
+        assert_eq!(outputs[0], run(&args, io::Cursor::new(first)).unwrap());
+    }
+
+    #[test]
+    fn test_same_seed_same_output() {
+        let input = "1 -1\n1 -3\n2 -5\n2 -8\n3 -10\n-1 4\n-3 2\n";
+        let args = Args::parse_from(["spaceship-solver", "--seed", "42", "--kick-limit", "20"]);
+
+        let first = run(&args, io::Cursor::new(input)).unwrap();
+        let second = run(&args, io::Cursor::new(input)).unwrap();
+        assert_eq!(first.as_bytes(), second.as_bytes());
+    }
+
+    #[test]
+    fn test_restarts_with_max_seed() {
+        // restart 毎に足した seed が u64 を溢れても止まらない
+        let input = "1 -1\n1 -3\n2 -5\n2 -8\n3 -10\n-1 4\n-3 2\n";
+        let args = Args::parse_from([
+            "spaceship-solver",
+            "--seed",
+            "18446744073709551615",
+            "--restarts",
+            "2",
+            "--kick-limit",
+            "20",
+        ]);
+        assert!(run(&args, io::Cursor::new(input)).is_ok());
+    }
+
+    #[test]
+    fn test_beam_width_arg() {
+        let args = Args::parse_from(["spaceship-solver"]);
+        assert_eq!(args.beam_width, 1000);
+        assert_eq!(args.time_limit_ms, 10_000);
+
+        let input = "1 -1\n1 -3\n2 -5\n2 -8\n3 -10\n-1 4\n-3 2\n";
+        let args = Args::parse_from([
+            "spaceship-solver",
+            "--seed",
+            "42",
+            "--kick-limit",
+            "20",
+            "--beam-width",
+            "50",
+        ]);
+        assert_eq!(args.beam_width, 50);
+        // run の中で全ての点を訪れることを確認している
+        assert!(run(&args, io::Cursor::new(input)).is_ok());
+    }
+
+    #[test]
+    fn test_manhattan_time_distance() {
+        // t tick で最大 t(t+1)/2 進める
+        let expected = [
+            (0, 0),
+            (1, 1),
+            (2, 2),
+            (3, 2),
+            (4, 3),
+            (6, 3),
+            (7, 4),
+            (10, 4),
+            (11, 5),
+        ];
+        for (d, t) in expected {
+            assert_eq!(ManhattanTimeDistance::ticks_to_travel(d), t, "d = {}", d);
+            assert_eq!(ManhattanTimeDistance::ticks_to_travel(-d), t, "d = {}", -d);
+        }
+
+        let input = "3 -10
+-1 4
+";
+        let problem = Problem::new(
+            read_input(io::Cursor::new(input)).unwrap(),
+            "test".to_string(),
+        );
+        let distance = ManhattanTimeDistance { problem: &problem };
+        // 大きい方の軸で決まる
+        assert_eq!(distance.distance(0, 1), 4);
+        assert_eq!(distance.distance(1, 2), 5);
+
+        let args = Args::parse_from([
+            "spaceship-solver",
+            "--seed",
+            "42",
+            "--kick-limit",
+            "20",
+            "--distance",
+            "time",
+        ]);
+        assert_eq!(args.distance, DistanceKind::Time);
+        assert!(run(
+            &args,
+            io::Cursor::new("1 -1\n1 -3\n2 -5\n2 -8\n3 -10\n-1 4\n-3 2\n")
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_time_distance_sample_lengths() {
+        // 同じ seed で euclid と time の TSP 順序から作った手順の長さを比べる
+        // 測った長さ (euclid -> time): 1: 5 -> 5, 8: 139 -> 90
+        // 時間がかかるのでテストでは回さないが、10: 749 -> 325, 14: 1011 -> 210 とさらに縮む問題もある
+        // 常に短くなるわけではなく、6 (145 -> 283) や 9 (280 -> 301) のように長くなる問題もある
+        for problem in [1, 8] {
+            let path = format!(
+                "{}/../dataset/problem/spaceship/{}.txt",
+                env!("CARGO_MANIFEST_DIR"),
+                problem
+            );
+            let input = fs::read_to_string(path).unwrap();
+            let length = |distance| {
+                let args = Args::parse_from([
+                    "spaceship-solver",
+                    "--seed",
+                    "42",
+                    "--kick-limit",
+                    "20",
+                    "--distance",
+                    distance,
+                ]);
+                run(&args, io::Cursor::new(input.clone()))
+                    .unwrap()
+                    .trim_end()
+                    .len()
+            };
+            let (euclid, time) = (length("euclid"), length("time"));
+            assert!(
+                time <= euclid,
+                "problem {}: euclid {}, time {}",
+                problem,
+                euclid,
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn test_velocity_alignment_score() {
+        let problem = Problem::new(
+            vec![Point::new(0, 0), Point::new(10, 0)],
+            "test".to_string(),
+        );
+        let coord_order = vec![0, 1];
+        let state = |vx| State {
+            vx,
+            x: 2,
+            ..State::new()
+        };
+        let toward = state(3);
+        let stationary = state(0);
+        let away = state(-3);
+
+        // 重みが 0 なら速度は評価に影響しない
+        assert_eq!(
+            evaluate(&problem, &toward, &coord_order, 0),
+            evaluate(&problem, &stationary, &coord_order, 0)
+        );
+
+        // 目標に向かって動いている state ほど良い
+        let score = |s: &State| evaluate(&problem, s, &coord_order, 1);
+        assert!(score(&toward) < score(&stationary));
+        assert!(score(&stationary) < score(&away));
+        assert_eq!(score(&toward), (2, 64 - 24));
+
+        let args = Args::parse_from(["spaceship-solver", "--velocity-weight", "3"]);
+        assert_eq!(args.velocity_weight, 3);
+    }
+
+    #[test]
+    fn test_compare_baseline() {
+        // "236659" で訪れる位置
+        let input = "0 -1\n1 -3\n3 -5\n6 -7\n9 -9\n13 -10\n";
+        let problem = Problem::new(read_input(io::Cursor::new(input)).unwrap(), "test".into());
+        let short = "236659";
+        // 全て訪れた後に 1 手余計に動く
+        let long = "2366595";
+
+        assert_eq!(
+            compare_baseline(&problem, short, long).unwrap(),
+            Comparison::Better
+        );
+        assert_eq!(
+            compare_baseline(&problem, long, short).unwrap(),
+            Comparison::Worse
+        );
+        // 正しくない baseline とは比べない
+        assert!(compare_baseline(&problem, short, "2366").is_err());
+    }
+
+    #[test]
+    fn test_best_of_restarts() {
+        let point_list = vec![
+            Point::new(0, 0),
+            Point::new(1, -1),
+            Point::new(1, -3),
+            Point::new(2, -5),
+            Point::new(2, -8),
+            Point::new(3, -10),
+        ];
+        let problem = Problem::new(point_list, "test".to_string());
+        let identity = (0..problem.point_list.len()).collect::<Vec<_>>();
+        // 原点以外の訪問順を入れ替えたもの
+        let order_list = [
+            identity.clone(),
+            vec![0, 2, 1, 3, 5, 4],
+            vec![0, 5, 4, 3, 2, 1],
+        ];
+
+        let config = BeamConfig::new(10, false);
+        let single = beam_search(&problem, &identity, &config).unwrap();
+        let (best_order, best) =
+            best_of_restarts(&problem, 3, &config, |restart| order_list[restart].clone()).unwrap();
+        assert!(best.len() <= single.len());
+        assert!(order_list.contains(&best_order));
+    }
+
+    #[test]
+    fn test_speed_prune() {
+        // 遠くの点の後に、原点まで引き返す
+        let point_list = vec![Point::new(0, 0), Point::new(40, 0), Point::new(0, 0)];
+        let problem = Problem::new(point_list, "test".to_string());
+        let coord_order = (0..problem.point_list.len()).collect::<Vec<_>>();
+
+        // 枝刈りしないと、加速しすぎて原点を通り過ぎるのを繰り返す
+        let config = BeamConfig {
+            max_steps: Some(60),
+            ..BeamConfig::new(3, false)
+        };
+        assert_eq!(beam_search(&problem, &coord_order, &config), None);
+
+        let config = BeamConfig {
+            speed_slack: Some(5),
+            ..config
+        };
+        let action_list = beam_search(&problem, &coord_order, &config).unwrap();
+        assert!(action_list.len() <= 60);
+
+        let mut state = State::new();
+        for &action in action_list.iter() {
+            state.apply_action(action as usize - 1, &problem, &coord_order);
+        }
+        assert_eq!(state.node_index, problem.point_list.len());
+    }
+
+    #[test]
+    fn test_stall_widens_beam() {
+        // 遠くの点まで加速した後に、原点まで引き返す
+        let point_list = vec![Point::new(0, 0), Point::new(-777, 0), Point::new(0, 0)];
+        let problem = Problem::new(point_list, "test".to_string());
+        let coord_order = (0..problem.point_list.len()).collect::<Vec<_>>();
+
+        // 幅 1 だと目標を通り過ぎるのを繰り返して、いつまでも終わらない
+        let config = BeamConfig {
+            stall_limit: Some(200),
+            max_beam_width: 1,
+            ..BeamConfig::new(1, false)
+        };
+        assert_eq!(beam_search(&problem, &coord_order, &config), None);
+
+        // 幅を広げれば辿り着ける
+        let config = BeamConfig {
+            max_beam_width: 64,
+            ..config
+        };
+        let action_list = beam_search(&problem, &coord_order, &config).unwrap();
+        let points = target_points(&problem);
+        let output = action_list
+            .iter()
+            .map(|action| action.to_string())
+            .collect::<String>();
+        simulate_spaceship(&points, &output).unwrap();
+    }
+}
@@ -1,5 +1,12 @@
 #!/bin/bash
 
+# message-sender は ICFP_TOKEN から auth token を読む
+# set -x の前に確かめて、token がログに出ないようにする
+if [ -z "${ICFP_TOKEN}" ]; then
+    echo "set ICFP_TOKEN to the auth token" >&2
+    exit 1
+fi
+
 set -eux
 
 cargo build --release
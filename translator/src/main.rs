@@ -1,51 +1,274 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 
-use core::parser::ast::{parse, NodeType};
-use core::parser::icfpstring::ICFPString;
+use core::parser::ast::{evaluate_to_value, ParseConfig, Value};
+use core::parser::icfpstring::{strip_trailing_newline, ICFPString};
 use std::fs;
-use std::path::PathBuf;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 
 /// このプログラムはコマンドライン引数からファイルパスを受け取り、その内容を出力します。
 #[derive(Parser, Debug)]
 #[command(name = "file_reader")]
 #[command(about = "A simple file reader")]
 struct Args {
-    /// ファイルパス
+    /// ファイルパス。省略した場合は標準入力から読む
     #[arg(short, long)]
-    file: PathBuf,
+    file: Option<PathBuf>,
 
     #[arg(short, long)]
     encode: bool,
+
+    /// decode の時に、どの型の値まで縮約できれば成功とするか
+    #[arg(long, value_enum, default_value_t = ReduceTo::String)]
+    reduce_to: ReduceTo,
+
+    /// ディレクトリ内の全てのファイルを処理して、foo.txt の結果を foo.decoded.txt
+    /// (--encode の時は foo.encoded.txt) に書き出す
+    #[arg(long, alias = "batch", conflicts_with = "file")]
+    dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ReduceTo {
+    String,
+    Integer,
+    Boolean,
+    /// Integer / Boolean / String のどれでも良い
+    Any,
+}
+
+// 出力先のファイル名で、元の拡張子の前に付け足す (foo.txt -> foo.decoded.txt)
+const DECODED_SUFFIX: &str = "decoded";
+const ENCODED_SUFFIX: &str = "encoded";
+
+#[derive(Debug, Default)]
+struct BatchReport {
+    written: Vec<PathBuf>,
+    failed: Vec<(PathBuf, String)>,
 }
 
 fn get_content(path: &PathBuf) -> Result<String, anyhow::Error> {
     fs::read_to_string(path).map_err(|e| e.into())
 }
 
-fn main() -> Result<(), anyhow::Error> {
-    let args = Args::parse();
-    let contents = get_content(&args.file)?;
+fn read_stdin() -> Result<String, anyhow::Error> {
+    let mut contents = String::new();
+    io::stdin().read_to_string(&mut contents)?;
+    Ok(contents)
+}
 
-    if args.encode {
-        let s = ICFPString::from_encoded_str(&contents.as_str())?;
-        let encoded = s.to_string()?.into_iter().collect::<String>();
-        println!("S{}", encoded);
-        Ok(())
+// プログラムを評価して、reduce_to の型の値になっていれば整形して返す
+fn decode_content(contents: String, reduce_to: ReduceTo) -> Result<String, anyhow::Error> {
+    let value = evaluate_to_value(contents, ParseConfig::default())?;
+    let matches = matches!(
+        (reduce_to, &value),
+        (ReduceTo::Any, _)
+            | (ReduceTo::String, Value::String(_))
+            | (ReduceTo::Integer, Value::Integer(_))
+            | (ReduceTo::Boolean, Value::Boolean(_))
+    );
+    if !matches {
+        return Err(anyhow::anyhow!(
+            "cannot reduce to {:?}: {}",
+            reduce_to,
+            value.to_string()
+        ));
+    }
+    Ok(value.to_string())
+}
+
+// 平文を S... にする
+fn encode_content(contents: &str) -> Result<String, anyhow::Error> {
+    let s = ICFPString::from_encoded_str(strip_trailing_newline(contents))?;
+    let encoded = s.to_string()?.into_iter().collect::<String>();
+    Ok(format!("S{}", encoded))
+}
+
+// foo.txt -> foo.<suffix>.txt、拡張子が無ければ foo -> foo.<suffix>
+fn output_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    path.with_file_name(name)
+}
+
+// 以前の実行で書き出したファイルかどうか
+fn is_output_path(path: &Path) -> bool {
+    let stem = Path::new(path.file_stem().unwrap_or_default());
+    [path.extension(), stem.extension()]
+        .into_iter()
+        .flatten()
+        .any(|ext| ext == DECODED_SUFFIX || ext == ENCODED_SUFFIX)
+}
+
+// dir 直下のファイルを 1 つずつ評価 (encode なら S... に変換) する
+// 失敗しても中断せずに次のファイルへ進む
+// 以前の実行で書き出した .decoded / .encoded は対象にしない
+fn process_dir(dir: &Path, encode: bool) -> Result<BatchReport, anyhow::Error> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+
+    let suffix = if encode {
+        ENCODED_SUFFIX
     } else {
-        let result_node = parse(contents)?;
-        match result_node.node_type {
-            NodeType::String(s) => {
-                for c in s.iter() {
-                    print!("{}", c);
-                }
-                println!();
-                Ok(())
+        DECODED_SUFFIX
+    };
+    let mut report = BatchReport::default();
+    for path in paths {
+        if !path.is_file() || is_output_path(&path) {
+            continue;
+        }
+        let result = get_content(&path).and_then(|contents| {
+            let output_contents = if encode {
+                encode_content(&contents)?
+            } else {
+                decode_content(contents, ReduceTo::Any)?
+            };
+            let output = output_path(&path, suffix);
+            fs::write(&output, output_contents)?;
+            Ok(output)
+        });
+        match result {
+            Ok(output) => {
+                eprintln!("ok: {} -> {}", path.display(), output.display());
+                report.written.push(output);
             }
-            _ => {
-                println!("cannot reduce to string: {:?}", result_node);
-                println!("result_node.to_dot_string()");
-                Err(anyhow::anyhow!("cannot reduce to string"))
+            Err(e) => {
+                eprintln!("failed: {}: {}", path.display(), e);
+                report.failed.push((path, e.to_string()));
             }
         }
     }
+    Ok(report)
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
+    if let Some(dir) = &args.dir {
+        let report = process_dir(dir, args.encode)?;
+        eprintln!(
+            "written: {}, failed: {}",
+            report.written.len(),
+            report.failed.len()
+        );
+        if !report.failed.is_empty() {
+            return Err(anyhow::anyhow!("{} files failed", report.failed.len()));
+        }
+        return Ok(());
+    }
+
+    let contents = match &args.file {
+        Some(path) => get_content(path)?,
+        None => read_stdin()?,
+    };
+
+    if args.encode {
+        println!("{}", encode_content(&contents)?);
+    } else {
+        println!("{}", decode_content(contents, args.reduce_to)?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_integer() {
+        // 2 * (3 + 4)
+        let program = "B* I# B+ I$ I%".to_string();
+        assert_eq!(
+            decode_content(program.clone(), ReduceTo::Integer).unwrap(),
+            "14"
+        );
+        assert_eq!(
+            decode_content(program.clone(), ReduceTo::Any).unwrap(),
+            "14"
+        );
+        assert!(decode_content(program, ReduceTo::String).is_err());
+    }
+
+    #[test]
+    fn test_decode_boolean() {
+        // 3 < 4
+        let program = "B< I$ I%".to_string();
+        assert_eq!(
+            decode_content(program.clone(), ReduceTo::Boolean).unwrap(),
+            "true"
+        );
+        assert_eq!(
+            decode_content(program.clone(), ReduceTo::Any).unwrap(),
+            "true"
+        );
+        assert!(decode_content(program, ReduceTo::Integer).is_err());
+    }
+
+    #[test]
+    fn test_decode_lambda_fails() {
+        assert!(decode_content("L# v#".to_string(), ReduceTo::Any).is_err());
+    }
+
+    #[test]
+    fn test_process_dir() {
+        let dir = std::env::temp_dir().join("translator_process_dir_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // "Hello World!"
+        fs::write(dir.join("hello.txt"), "SB%,,/}Q/2,$_").unwrap();
+        fs::write(dir.join("number"), "B+ I# I$").unwrap();
+        // 値にならない (関数のまま)
+        fs::write(dir.join("lambda.txt"), "L# v#").unwrap();
+        fs::write(dir.join("invalid.txt"), "X").unwrap();
+
+        let report = process_dir(&dir, false).unwrap();
+        assert_eq!(report.written.len(), 2);
+        assert_eq!(
+            fs::read_to_string(dir.join("hello.decoded.txt")).unwrap(),
+            "Hello World!"
+        );
+        assert_eq!(fs::read_to_string(dir.join("number.decoded")).unwrap(), "5");
+
+        let failed = report
+            .failed
+            .iter()
+            .map(|(path, _)| path.file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(failed, vec!["invalid.txt", "lambda.txt"]);
+        assert!(!dir.join("invalid.decoded.txt").exists());
+        assert!(!dir.join("lambda.decoded.txt").exists());
+
+        // 2 回目は .decoded を入力として扱わない
+        let report = process_dir(&dir, false).unwrap();
+        assert_eq!(report.written.len() + report.failed.len(), 4);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_process_dir_encode() {
+        let dir = std::env::temp_dir().join("translator_process_dir_encode_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("hello.txt"), "Hello World!\n").unwrap();
+        // ICFP の文字に無い文字は encode できない
+        fs::write(dir.join("invalid.txt"), "é").unwrap();
+
+        let report = process_dir(&dir, true).unwrap();
+        assert_eq!(report.written, vec![dir.join("hello.encoded.txt")]);
+        assert_eq!(
+            fs::read_to_string(dir.join("hello.encoded.txt")).unwrap(),
+            "SB%,,/}Q/2,$_"
+        );
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, dir.join("invalid.txt"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
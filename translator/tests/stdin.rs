@@ -0,0 +1,31 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+fn run_translator(args: &[&str], input: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_translator"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "translator {:?} failed", args);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_encode_then_decode_via_stdin() {
+    let message = "get lambdaman1\n";
+
+    let encoded = run_translator(&["--encode"], message);
+    assert!(encoded.starts_with('S'));
+
+    let decoded = run_translator(&[], &encoded);
+    assert_eq!(decoded, message);
+}